@@ -1,3 +1,4 @@
+use crate::{http_client, org_id};
 use anyhow::Result;
 use darx_core::api::NewPluginProjectReq;
 use std::path::PathBuf;
@@ -7,11 +8,11 @@ pub async fn run_deploy(plugin_name: &Option<String>, dir: &str) -> Result<()> {
   let path = PathBuf::from(dir);
   if let Some(plugin_name) = plugin_name {
     let req = NewPluginProjectReq {
-      org_id: "test_org".to_string(),
+      org_id: org_id()?,
       plugin_name: plugin_name.clone(),
     };
     let url = format!("http://127.0.0.1:3457/new_plugin_project");
-    if let Err(e) = reqwest::Client::new()
+    if let Err(e) = http_client()?
       .post(url)
       .json(&req)
       .send()
@@ -24,7 +25,7 @@ pub async fn run_deploy(plugin_name: &Option<String>, dir: &str) -> Result<()> {
 
     let req = darx_core::api::dir_to_deploy_plugin_req(path.as_path()).await?;
     let url = format!("http://127.0.0.1:3457/deploy_plugin/{}", plugin_name);
-    if let Err(e) = reqwest::Client::new()
+    if let Err(e) = http_client()?
       .post(url)
       .json(&req)
       .send()
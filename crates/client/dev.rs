@@ -2,7 +2,11 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use crate::http_client;
 use anyhow::{Context, Result};
+use darx_core::api::{
+  code_hashes, incremental_deploy_code_req, MissingBlobsReq, MissingBlobsRsp,
+};
 use notify::event::ModifyKind;
 use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
@@ -44,6 +48,20 @@ pub async fn run_dev(root_dir: &str) -> Result<()> {
         functions_path.display()
       )
     })?;
+  watcher
+    .watch(libs_path.as_path(), RecursiveMode::Recursive)
+    .with_context(|| {
+      format!("Failed to watch lib directory: {}", libs_path.display())
+    })?;
+  // the import map is in the server directory itself.
+  watcher
+    .watch(server_path.as_path(), RecursiveMode::NonRecursive)
+    .with_context(|| {
+      format!(
+        "Failed to watch server directory: {}",
+        server_path.display()
+      )
+    })?;
   for event in rx.into_iter().flatten() {
     let should_update = if let EventKind::Modify(modify) = event.kind {
       matches!(modify, ModifyKind::Name(_))
@@ -63,8 +81,33 @@ pub async fn run_dev(root_dir: &str) -> Result<()> {
 async fn handle_file_changed(server_path: &Path) -> Result<()> {
   let start_time = std::time::Instant::now();
   let req = darx_core::api::dir_to_deploy_code_req(server_path).await?;
+
+  // only upload the codes the control plane doesn't have yet.
+  let missing_req = MissingBlobsReq {
+    hashes: code_hashes(&req),
+  };
+  let missing_rsp = http_client()?
+    .post(format!(
+      "http://127.0.0.1:3457/missing_blobs/{}",
+      MVP_TEST_ENV_ID
+    ))
+    .json(&missing_req)
+    .send()
+    .await?
+    .error_for_status();
+  let req = match missing_rsp {
+    Ok(rsp) => {
+      let rsp: MissingBlobsRsp = rsp.json().await?;
+      incremental_deploy_code_req(req, rsp.missing.as_slice())
+    }
+    Err(e) => {
+      eprintln!("Failed to query missing blobs: {:?}", e);
+      req
+    }
+  };
+
   let url = format!("http://127.0.0.1:3457/deploy_code/{}", MVP_TEST_ENV_ID);
-  if let Err(e) = reqwest::Client::new()
+  if let Err(e) = http_client()?
     .post(url)
     .json(&req)
     .send()
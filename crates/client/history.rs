@@ -0,0 +1,78 @@
+use crate::http_client;
+use anyhow::Result;
+use darx_core::api::{GetDeployRsp, ListDeployRsp};
+
+pub async fn run_list_deploys(
+  env_id: &str,
+  before: &Option<i64>,
+  limit: &Option<u32>,
+) -> Result<()> {
+  let mut url = format!("http://127.0.0.1:3457/list_deploys/{}", env_id);
+  let mut params = vec![];
+  if let Some(before) = before {
+    params.push(format!("before={}", before));
+  }
+  if let Some(limit) = limit {
+    params.push(format!("limit={}", limit));
+  }
+  if !params.is_empty() {
+    url = format!("{}?{}", url, params.join("&"));
+  }
+  let rsp = http_client()?.get(url).send().await?.error_for_status();
+  let rsp = match rsp {
+    Ok(rsp) => rsp.json::<ListDeployRsp>().await?,
+    Err(e) => {
+      eprintln!("Failed to list deploys: {:?}", e);
+      return Ok(());
+    }
+  };
+
+  println!("{:<8} {:<32} {:<20} DESCRIPTION", "SEQ", "CREATED", "TAG");
+  for d in rsp.deploys.iter() {
+    println!(
+      "{:<8} {:<32} {:<20} {}",
+      d.deploy_seq,
+      d.created_at.to_string(),
+      d.tag.as_deref().unwrap_or("-"),
+      d.desc.as_deref().unwrap_or("-"),
+    );
+  }
+  if let Some(next) = rsp.next {
+    println!("more deploys: --before {}", next);
+  }
+  Ok(())
+}
+
+pub async fn run_show_deploy(env_id: &str, deploy_seq: i64) -> Result<()> {
+  let url = format!("http://127.0.0.1:3457/deploy/{}/{}", env_id, deploy_seq);
+  let rsp = http_client()?.get(url).send().await?.error_for_status();
+  let rsp = match rsp {
+    Ok(rsp) => rsp.json::<GetDeployRsp>().await?,
+    Err(e) => {
+      eprintln!("Failed to get deploy: {:?}", e);
+      return Ok(());
+    }
+  };
+
+  let d = &rsp.deploy;
+  println!("deploy_seq:  {}", d.deploy_seq);
+  println!("created_at:  {}", d.created_at);
+  println!("tag:         {}", d.tag.as_deref().unwrap_or("-"));
+  println!("description: {}", d.desc.as_deref().unwrap_or("-"));
+  println!("codes:");
+  for c in rsp.codes.iter() {
+    println!("  {} ({} bytes)", c.fs_path, c.content.len());
+  }
+  println!("routes:");
+  for r in rsp.http_routes.iter() {
+    println!(
+      "  {} {} -> {}:{}",
+      r.method, r.http_path, r.js_entry_point, r.js_export
+    );
+  }
+  println!("vars:");
+  for v in rsp.vars.iter() {
+    println!("  {}={}", v.key, v.value);
+  }
+  Ok(())
+}
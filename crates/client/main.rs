@@ -1,8 +1,11 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use std::env;
 
 mod deploy;
 mod dev;
+mod history;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -35,6 +38,44 @@ enum Commands {
     #[arg(short, long, default_value_t = String::from("."))]
     dir: String,
   },
+  /// Lists the deploys of an environment, newest first,
+  /// or shows the codes, routes and vars of one deploy.
+  Deploys {
+    /// The environment's id.
+    #[arg(short, long)]
+    env: String,
+    /// Shows the deploy with this deploy_seq.
+    #[arg(short, long)]
+    seq: Option<i64>,
+    /// Lists deploys older than this deploy_seq.
+    #[arg(short, long)]
+    before: Option<i64>,
+    /// The max number of deploys to list.
+    #[arg(short, long)]
+    limit: Option<u32>,
+  },
+}
+
+/// [`http_client`] authenticates the requests with `DARX_API_KEY`.
+pub(crate) fn http_client() -> Result<reqwest::Client> {
+  let mut headers = HeaderMap::new();
+  if let Ok(api_key) = env::var("DARX_API_KEY") {
+    let mut value =
+      HeaderValue::from_str(format!("Bearer {}", api_key).as_str())
+        .context("Invalid DARX_API_KEY")?;
+    value.set_sensitive(true);
+    headers.insert(AUTHORIZATION, value);
+  }
+  let client = reqwest::Client::builder()
+    .default_headers(headers)
+    .build()
+    .context("Failed to build http client")?;
+  Ok(client)
+}
+
+/// [`org_id`] is the org of `DARX_API_KEY`, set by `DARX_ORG_ID`.
+pub(crate) fn org_id() -> Result<String> {
+  env::var("DARX_ORG_ID").context("DARX_ORG_ID should be configured")
 }
 
 #[tokio::main]
@@ -43,6 +84,15 @@ async fn main() -> Result<()> {
   match &cli.command {
     Commands::Dev { dir } => dev::run_dev(dir).await?,
     Commands::Deploy { plugin, dir } => deploy::run_deploy(plugin, dir).await?,
+    Commands::Deploys {
+      env,
+      seq,
+      before,
+      limit,
+    } => match seq {
+      Some(seq) => history::run_show_deploy(env, *seq).await?,
+      None => history::run_list_deploys(env, before, limit).await?,
+    },
   }
   Ok(())
 }
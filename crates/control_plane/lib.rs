@@ -1,24 +1,44 @@
+mod outbox;
+
 use actix_cors::Cors;
 use actix_web::dev::Server;
-use actix_web::web::{get, post, Data, Json, Path};
+use actix_web::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use actix_web::rt::time::{interval_at, Instant};
+use actix_web::web::{get, post, Data, Json, Path, Query};
 use actix_web::{App, HttpResponse, HttpResponseBuilder, HttpServer};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use sqlx::{MySql, MySqlPool, Transaction};
+use std::collections::HashMap;
 use std::env;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 
 use darx_core::api::{
-  add_code_deploy_url, add_plugin_deploy_url, add_tenant_db_url,
-  add_var_deploy_url, AddCodeDeployReq, AddPluginDeployReq, AddTenantDBReq,
-  AddVarDeployReq, ApiError, DeployCodeReq, DeployCodeRsp, DeployPluginReq,
-  DeployVarReq, EnvInfo, ListApiRsp, ListCodeRsp, ListProjectRsp,
-  NewPluginProjectReq, NewProjectRsp, NewTenantProjectReq, ProjectInfo,
+  AddCodeDeployReq, AddMemberReq, AddPluginDeployReq, AddTenantDBReq,
+  AddVarDeployReq, ApiError, ChangesReq, ChangesRsp, CorsConfig, DeployCodeReq,
+  DeployCodeRsp, DeployPluginReq, DeployVarReq, DiffDeployReq, DiffDeployRsp,
+  DomainInfo, EgressPolicy, EnvInfo, EnvLimits, EventKind, GcDeployReq,
+  GcDeployRsp, GetDeployRsp, ListApiKeyRsp, ListApiRsp, ListCodeRsp,
+  ListDeployReq, ListDeployRsp, ListDomainRsp, ListEnvRsp, ListMemberRsp,
+  ListNodeRsp, ListProjectRsp, ListVarRsp, LoadEnvReq, MissingBlobsReq,
+  MissingBlobsRsp, NewApiKeyReq, NewApiKeyRsp, NewEnvReq, NewOrgReq,
+  NewPluginProjectReq, NewProjectRsp, NewTenantProjectReq, OrgInfo,
+  PlaceEnvReq, ProjectInfo, PromoteReq, PromoteRsp, RemoveEnvReq, Role,
+  SetCorsReq, SetDomainsReq, SetLimitsReq, SetMemberRoleReq, SetRetentionReq,
+  SetVarReq, UnsetVarReq, VarDeployRsp, VarHistoryRsp, VarInfo,
 };
-use darx_core::code::control;
+use darx_core::auth::{self, ApiKeyAuth, Auth};
+use darx_core::code::{blob, control, gc};
+use darx_core::env::Env;
+use darx_core::env_vars::Var;
 use darx_core::plugin::plugin_env_id;
-use darx_core::Project;
+use darx_core::{cors, domain, limit, node, org, DeploySeq, Project};
 
 pub async fn run_server(socket_addr: SocketAddr) -> Result<Server> {
+  // fails fast if the secret is not configured or too short.
+  auth::internal_secret();
+
   let db_pool = sqlx::MySqlPool::connect(
     env::var("DATABASE_URL")
       .expect("DATABASE_URL should be configured")
@@ -27,17 +47,23 @@ pub async fn run_server(socket_addr: SocketAddr) -> Result<Server> {
   .await
   .context("Failed to connect database")?;
 
+  if let Some(period) = gc::gc_interval() {
+    actix_web::rt::spawn(run_gc(db_pool.clone(), period));
+  }
+  actix_web::rt::spawn(outbox::run_delivery(
+    db_pool.clone(),
+    outbox::delivery_interval(),
+  ));
+
   let server_state = ServerState { db_pool };
   tracing::info!("listen on {}", socket_addr);
 
   Ok(
     HttpServer::new(move || {
-      let cors = Cors::default()
-        .allow_any_method()
-        .allow_any_header()
-        .allow_any_origin();
+      let cors = control_cors();
 
       App::new()
+        .wrap(ApiKeyAuth::new(server_state.db_pool.clone()))
         .wrap(TracingLogger::default())
         .wrap(cors)
         .app_data(Data::new(server_state.clone()))
@@ -46,10 +72,53 @@ pub async fn run_server(socket_addr: SocketAddr) -> Result<Server> {
         .route("/new_tenant_project", post().to(new_tenant_project))
         .route("/new_plugin_project", post().to(new_plugin_project))
         .route("/load_env/{project_id}", get().to(load_env))
+        .route("/new_env/{project_id}", post().to(new_env))
+        .route("/list_env/{project_id}", get().to(list_env))
+        .route("/delete_env/{env_id}", post().to(delete_env))
+        .route("/missing_blobs/{env_id}", post().to(missing_blobs))
         .route("/deploy_code/{env_id}", post().to(deploy_code))
+        .route("/promote/{env_id}", post().to(promote))
+        .route("/list_deploys/{env_id}", get().to(list_deploys))
+        .route("/deploy/{env_id}/{deploy_seq}", get().to(get_deploy))
+        .route("/diff_deploy/{env_id}", get().to(diff_deploy))
+        .route("/gc_deploys/{env_id}", post().to(gc_deploys))
+        .route("/set_retention/{env_id}", post().to(set_retention))
         .route("/deploy_var/{env_id}", post().to(deploy_var))
+        .route("/list_var/{env_id}", get().to(list_var))
+        .route("/get_var/{env_id}/{key}", get().to(get_var))
+        .route("/set_var/{env_id}", post().to(set_var))
+        .route("/unset_var/{env_id}", post().to(unset_var))
+        .route("/var_history/{env_id}", get().to(var_history))
         .route("/list_api/{env_id}", get().to(list_api))
         .route("/deploy_plugin/{plugin_name}", post().to(deploy_plugin))
+        .route("/new_org", post().to(new_org))
+        .route("/list_members/{org_id}", get().to(list_members))
+        .route("/add_member/{org_id}", post().to(add_member))
+        .route(
+          "/set_member_role/{org_id}/{user_id}",
+          post().to(set_member_role),
+        )
+        .route(
+          "/remove_member/{org_id}/{user_id}",
+          post().to(remove_member),
+        )
+        .route("/new_api_key/{org_id}", post().to(new_api_key))
+        .route("/list_api_keys/{org_id}", get().to(list_api_keys))
+        .route(
+          "/revoke_api_key/{org_id}/{key_id}",
+          post().to(revoke_api_key),
+        )
+        .route("/list_nodes", get().to(list_nodes))
+        .route("/changes", get().to(changes))
+        .route("/place_env/{env_id}", post().to(place_env))
+        .route("/add_domain/{env_id}", post().to(add_domain))
+        .route("/remove_domain/{env_id}", post().to(remove_domain))
+        .route("/list_domains/{env_id}", get().to(list_domains))
+        .route("/set_limits/{env_id}", post().to(set_limits))
+        .route("/get_limits/{env_id}", get().to(get_limits))
+        .route("/set_egress/{env_id}", post().to(set_egress))
+        .route("/set_cors/{env_id}", post().to(set_cors))
+        .route("/get_cors/{env_id}", get().to(get_cors))
     })
     .bind(&socket_addr)?
     .run(),
@@ -58,16 +127,29 @@ pub async fn run_server(socket_addr: SocketAddr) -> Result<Server> {
 
 async fn deploy_code(
   server_state: Data<ServerState>,
+  auth: Auth,
   env_id: Path<String>,
   req: Json<DeployCodeReq>,
 ) -> Result<Json<DeployCodeRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let codes = blob::resolve_codes(
+    &server_state.db_pool,
+    env_id.as_str(),
+    &req.codes,
+    &req.code_refs,
+  )
+  .await?;
+  // vendoring fetches the modules, no connection is held meanwhile.
+  let codes = control::vendor_codes(&codes).await?;
   let txn = server_state
     .db_pool
     .begin()
     .await
     .context("Failed to start transaction")?;
-  let (deploy_seq, codes, http_routes, txn) =
-    control::deploy_code(txn, env_id.as_str(), &req.codes, &req.tag, &req.desc)
+  let (deploy_seq, codes, http_routes, mut txn) =
+    control::deploy_code(txn, env_id.as_str(), &codes, &req.tag, &req.desc)
       .await?;
 
   let req = AddCodeDeployReq {
@@ -76,33 +158,190 @@ async fn deploy_code(
     codes,
     http_routes: http_routes.clone(),
   };
-  let url = add_code_deploy_url();
-  let rsp = reqwest::Client::new()
-    .post(url)
-    .json(&req)
-    .send()
-    .await
-    .context("Failed to send add_code_deploy request")?;
-  if !rsp.status().is_success() {
-    return Err(ApiError::Internal(anyhow!(
-      "Failed to add deployment: {}",
-      rsp.text().await.unwrap()
-    )));
-  }
+  outbox::add_event(&mut txn, EventKind::AddCodeDeploy, env_id.as_str(), &req)
+    .await?;
   txn
     .commit()
     .await
     .context("Failed to commit transaction when deploy_code")?;
+  deliver_events(&server_state.db_pool).await;
   Ok(Json(DeployCodeRsp { http_routes }))
 }
 
+async fn missing_blobs(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<MissingBlobsReq>,
+) -> Result<Json<MissingBlobsRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let missing = blob::missing_blobs(
+    &server_state.db_pool,
+    env_id.as_str(),
+    req.hashes.as_slice(),
+  )
+  .await?;
+  Ok(Json(MissingBlobsRsp { missing }))
+}
+
+async fn run_gc(db_pool: MySqlPool, period: Duration) {
+  let mut interval = interval_at(Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    if let Err(e) = gc::gc_all_envs(&db_pool).await {
+      tracing::error!("Failed to gc deploys: {:?}", e);
+    }
+  }
+}
+
+async fn gc_deploys(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<GcDeployReq>,
+) -> Result<Json<GcDeployRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (deploys, txn) =
+    gc::gc_deploys(txn, env_id.as_str(), req.dry_run).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when gc_deploys")?;
+  Ok(Json(GcDeployRsp {
+    dry_run: req.dry_run,
+    deploys,
+  }))
+}
+
+async fn set_retention(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<SetRetentionReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  gc::set_retention(&server_state.db_pool, env_id.as_str(), req.keep_deploys)
+    .await?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn promote(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<PromoteReq>,
+) -> Result<Json<PromoteRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  auth
+    .check_env(
+      &server_state.db_pool,
+      req.from_env_id.as_str(),
+      Role::Viewer,
+    )
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (deploy_seq, codes, http_routes, mut txn) = control::promote_deploy(
+    txn,
+    req.from_env_id.as_str(),
+    env_id.as_str(),
+    req.deploy_seq,
+    &req.desc,
+  )
+  .await?;
+
+  let req = AddCodeDeployReq {
+    env_id: env_id.to_string(),
+    deploy_seq,
+    codes,
+    http_routes: http_routes.clone(),
+  };
+  outbox::add_event(&mut txn, EventKind::AddCodeDeploy, env_id.as_str(), &req)
+    .await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when promote")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(Json(PromoteRsp {
+    deploy_seq,
+    http_routes,
+  }))
+}
+
+async fn list_deploys(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Query<ListDeployReq>,
+) -> Result<Json<ListDeployRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let (deploys, next) =
+    control::list_deploy(db_pool, env_id.as_str(), req.before, req.limit)
+      .await?;
+  Ok(Json(ListDeployRsp { deploys, next }))
+}
+
+async fn get_deploy(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  path: Path<(String, DeploySeq)>,
+) -> Result<Json<GetDeployRsp>, ApiError> {
+  let (env_id, deploy_seq) = path.into_inner();
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let rsp = control::get_deploy(db_pool, env_id.as_str(), deploy_seq).await?;
+  Ok(Json(rsp))
+}
+
+async fn diff_deploy(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Query<DiffDeployReq>,
+) -> Result<Json<DiffDeployRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let rsp =
+    control::diff_deploy(db_pool, env_id.as_str(), req.from, req.to).await?;
+  Ok(Json(rsp))
+}
+
 async fn load_env(
   server_state: Data<ServerState>,
+  auth: Auth,
   proj_id: Path<String>,
+  req: Query<LoadEnvReq>,
 ) -> Result<Json<ListCodeRsp>, ApiError> {
+  auth
+    .check_project(&server_state.db_pool, proj_id.as_str(), Role::Viewer)
+    .await?;
   let db_pool = &server_state.db_pool;
   let (codes, http_routes, project, env) =
-    control::load_env(db_pool, proj_id.as_str()).await?;
+    control::load_env(db_pool, proj_id.as_str(), req.env.as_deref()).await?;
   Ok(Json(ListCodeRsp {
     codes,
     http_routes,
@@ -113,47 +352,171 @@ async fn load_env(
 
 async fn deploy_var(
   server_state: Data<ServerState>,
+  auth: Auth,
   env_id: Path<String>,
   req: Json<DeployVarReq>,
 ) -> Result<HttpResponseBuilder, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
   let txn = server_state
     .db_pool
     .begin()
     .await
     .context("Failed to start transaction")?;
 
-  let (deploy_seq, vars, txn) =
-    control::deploy_var(txn, env_id.as_str(), &req.vars, &req.desc).await?;
+  let (deploy_seq, vars, mut txn) = control::deploy_var(
+    txn,
+    env_id.as_str(),
+    &req.vars,
+    &req.secrets,
+    &req.desc,
+  )
+  .await?;
 
-  let req = AddVarDeployReq {
-    env_id: env_id.to_string(),
-    deploy_seq,
-    vars,
-  };
-  let url = add_var_deploy_url();
-  let rsp = reqwest::Client::new()
-    .post(url)
-    .json(&req)
-    .send()
-    .await
-    .context("Failed to send add_var_deploy request")?;
-  if !rsp.status().is_success() {
-    return Err(ApiError::Internal(anyhow!(
-      "Failed to add var deploy: {}",
-      rsp.text().await.unwrap()
-    )));
-  }
+  add_var_deploy(&mut txn, env_id.as_str(), deploy_seq, vars).await?;
   txn
     .commit()
     .await
     .context("Failed to commit transaction when deploy_var")?;
+  deliver_events(&server_state.db_pool).await;
   Ok(HttpResponse::Ok())
 }
 
+async fn list_var(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+) -> Result<Json<ListVarRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let vars = control::list_var(db_pool, env_id.as_str()).await?;
+  Ok(Json(ListVarRsp { vars }))
+}
+
+async fn get_var(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  path: Path<(String, String)>,
+) -> Result<Json<VarInfo>, ApiError> {
+  let (env_id, key) = path.into_inner();
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let var = control::get_var(db_pool, env_id.as_str(), key.as_str()).await?;
+  Ok(Json(var))
+}
+
+async fn set_var(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<SetVarReq>,
+) -> Result<Json<VarDeployRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let var = if req.is_secret {
+    Var::new_secret(req.key.as_str(), req.value.as_str())
+  } else {
+    Var::new(req.key.as_str(), req.value.as_str())
+  };
+  let txn = control::set_var(txn, env_id.as_str(), &var).await?;
+  let (deploy_seq, vars, mut txn) = control::deploy_var(
+    txn,
+    env_id.as_str(),
+    &Default::default(),
+    &Default::default(),
+    &req.desc,
+  )
+  .await?;
+
+  add_var_deploy(&mut txn, env_id.as_str(), deploy_seq, vars).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when set_var")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(Json(VarDeployRsp { deploy_seq }))
+}
+
+async fn unset_var(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<UnsetVarReq>,
+) -> Result<Json<VarDeployRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let txn = control::unset_var(txn, env_id.as_str(), req.key.as_str()).await?;
+  let (deploy_seq, vars, mut txn) = control::deploy_var(
+    txn,
+    env_id.as_str(),
+    &Default::default(),
+    &Default::default(),
+    &req.desc,
+  )
+  .await?;
+
+  add_var_deploy(&mut txn, env_id.as_str(), deploy_seq, vars).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when unset_var")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(Json(VarDeployRsp { deploy_seq }))
+}
+
+async fn var_history(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+) -> Result<Json<VarHistoryRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let deploys = control::var_history(db_pool, env_id.as_str()).await?;
+  Ok(Json(VarHistoryRsp { deploys }))
+}
+
+async fn add_var_deploy(
+  txn: &mut Transaction<'_, MySql>,
+  env_id: &str,
+  deploy_seq: DeploySeq,
+  vars: HashMap<String, String>,
+) -> Result<(), ApiError> {
+  let req = AddVarDeployReq {
+    env_id: env_id.to_string(),
+    deploy_seq,
+    vars,
+  };
+  outbox::add_event(txn, EventKind::AddVarDeploy, env_id, &req).await?;
+  Ok(())
+}
+
 async fn list_api(
   server_state: Data<ServerState>,
+  auth: Auth,
   env_id: Path<String>,
 ) -> Result<Json<ListApiRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
   let db_pool = &server_state.db_pool;
   let http_routes = control::list_api(db_pool, env_id.as_str()).await?;
   Ok(Json(ListApiRsp { http_routes }))
@@ -161,18 +524,22 @@ async fn list_api(
 
 async fn deploy_plugin(
   server_state: Data<ServerState>,
+  auth: Auth,
   plugin_name: Path<String>,
   req: Json<DeployPluginReq>,
 ) -> Result<HttpResponseBuilder, ApiError> {
+  let env_id = plugin_env_id(plugin_name.as_str());
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let codes = control::vendor_codes(&req.codes).await?;
   let txn = server_state
     .db_pool
     .begin()
     .await
     .context("Failed to start transaction")?;
-  let env_id = plugin_env_id(plugin_name.as_str());
-  let (deploy_seq, codes, http_routes, txn) =
-    control::deploy_code(txn, env_id.as_str(), &req.codes, &None, &None)
-      .await?;
+  let (deploy_seq, codes, http_routes, mut txn) =
+    control::deploy_code(txn, env_id.as_str(), &codes, &None, &None).await?;
   let req = AddPluginDeployReq {
     name: plugin_name.clone(),
     env_id: env_id.to_string(),
@@ -180,32 +547,29 @@ async fn deploy_plugin(
     codes,
     http_routes: http_routes.clone(),
   };
-  let url = add_plugin_deploy_url();
-  let rsp = reqwest::Client::new()
-    .post(url)
-    .json(&req)
-    .send()
-    .await
-    .context("Failed to send add deployment request")?;
-  if !rsp.status().is_success() {
-    return Err(ApiError::Internal(anyhow!(
-      "Failed to add deployment: {}",
-      rsp.text().await.unwrap()
-    )));
-  }
+  outbox::add_event(
+    &mut txn,
+    EventKind::AddPluginDeploy,
+    env_id.as_str(),
+    &req,
+  )
+  .await?;
 
   txn
     .commit()
     .await
     .context("Failed to commit transaction when deploy_code")?;
+  deliver_events(&server_state.db_pool).await;
 
   Ok(HttpResponse::Ok())
 }
 
 async fn list_project(
   server_state: Data<ServerState>,
+  auth: Auth,
   org_id: Path<String>,
 ) -> Result<Json<ListProjectRsp>, ApiError> {
+  auth.check_org(org_id.as_str(), Role::Viewer)?;
   let db_pool = &server_state.db_pool;
   let projects = Project::list_proj_info(org_id.as_str(), db_pool).await?;
   Ok(Json(ListProjectRsp { projects }))
@@ -213,33 +577,25 @@ async fn list_project(
 
 async fn new_tenant_project(
   server_state: Data<ServerState>,
+  auth: Auth,
   req: Json<NewTenantProjectReq>,
 ) -> Result<Json<NewProjectRsp>, ApiError> {
+  auth.check_org(req.org_id.as_str(), Role::Developer)?;
   let db_pool = &server_state.db_pool;
   let project =
     Project::new_tenant_proj(req.org_id.as_str(), req.project_name.as_str());
   let txn = db_pool.begin().await.context("Failed to start txn")?;
-  let txn = project.save(txn).await?;
-  let req = AddTenantDBReq {
-    env_id: project.env_id().to_string(),
-    db_info: project.db_info().as_ref().unwrap().clone(),
-  };
-  let url = add_tenant_db_url();
-  let rsp = reqwest::Client::new()
-    .post(url)
-    .json(&req)
-    .send()
-    .await
-    .context("Failed to send add_tenant_db request")?;
-
-  if !rsp.status().is_success() {
-    return Err(ApiError::Internal(anyhow!(
-      "Failed to add tenant db: {}",
-      rsp.text().await.unwrap()
-    )));
-  }
-
+  let mut txn = project.save(txn).await?;
+  add_tenant_db(
+    &mut txn,
+    &AddTenantDBReq {
+      env_id: project.env_id().to_string(),
+      db_info: project.db_info().as_ref().unwrap().clone(),
+    },
+  )
+  .await?;
   txn.commit().await.context("Failed to commit txn")?;
+  deliver_events(db_pool).await;
 
   tracing::info!("tenant db added: {:?}", project.db_info());
 
@@ -257,8 +613,10 @@ async fn new_tenant_project(
 
 async fn new_plugin_project(
   server_state: Data<ServerState>,
+  auth: Auth,
   req: Json<NewPluginProjectReq>,
 ) -> Result<Json<NewProjectRsp>, ApiError> {
+  auth.check_org(req.org_id.as_str(), Role::Developer)?;
   let db_pool = &server_state.db_pool;
   let project =
     Project::new_plugin_proj(req.org_id.as_str(), req.plugin_name.as_str());
@@ -279,6 +637,506 @@ async fn new_plugin_project(
   }))
 }
 
+async fn new_env(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  project_id: Path<String>,
+  req: Json<NewEnvReq>,
+) -> Result<Json<EnvInfo>, ApiError> {
+  auth
+    .check_project(&server_state.db_pool, project_id.as_str(), Role::Developer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let mut txn = db_pool.begin().await.context("Failed to start txn")?;
+  sqlx::query!("SELECT id FROM projects WHERE id = ?", project_id.as_str())
+    .fetch_optional(&mut *txn)
+    .await
+    .context("Failed to query projects table")?
+    .ok_or(ApiError::ProjectNotFound(project_id.to_string()))?;
+
+  let env = Env::new_tenant_env(project_id.as_str(), req.name.as_str());
+  let mut txn = env.save(txn).await?;
+  add_tenant_db(
+    &mut txn,
+    &AddTenantDBReq {
+      env_id: env.id().to_string(),
+      db_info: env.db_info().as_ref().unwrap().clone(),
+    },
+  )
+  .await?;
+  txn.commit().await.context("Failed to commit txn")?;
+  deliver_events(db_pool).await;
+
+  tracing::info!("env {} added: {:?}", env.name(), env.db_info());
+  Ok(Json(env.env_info()))
+}
+
+async fn list_env(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  project_id: Path<String>,
+) -> Result<Json<ListEnvRsp>, ApiError> {
+  auth
+    .check_project(&server_state.db_pool, project_id.as_str(), Role::Viewer)
+    .await?;
+  let db_pool = &server_state.db_pool;
+  let envs = darx_core::env::list_env(db_pool, project_id.as_str()).await?;
+  Ok(Json(ListEnvRsp { envs }))
+}
+
+async fn delete_env(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let mut txn = darx_core::env::delete_env(txn, env_id.as_str()).await?;
+
+  let req = RemoveEnvReq {
+    env_id: env_id.to_string(),
+  };
+  outbox::add_event(&mut txn, EventKind::RemoveEnv, env_id.as_str(), &req)
+    .await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when delete_env")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(HttpResponse::Ok())
+}
+
+async fn new_org(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  req: Json<NewOrgReq>,
+) -> Result<Json<OrgInfo>, ApiError> {
+  auth.check_internal()?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (org, txn) =
+    org::new_org(txn, req.org_id.as_str(), req.owner_id.as_str()).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when new_org")?;
+  Ok(Json(org))
+}
+
+async fn list_members(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  org_id: Path<String>,
+) -> Result<Json<ListMemberRsp>, ApiError> {
+  auth.check_org(org_id.as_str(), Role::Viewer)?;
+  let members =
+    org::list_members(&server_state.db_pool, org_id.as_str()).await?;
+  Ok(Json(ListMemberRsp { members }))
+}
+
+async fn add_member(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  org_id: Path<String>,
+  req: Json<AddMemberReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth.check_org(org_id.as_str(), Role::Owner)?;
+  org::add_member(
+    &server_state.db_pool,
+    org_id.as_str(),
+    req.user_id.as_str(),
+    req.role,
+  )
+  .await?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn set_member_role(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  path: Path<(String, String)>,
+  req: Json<SetMemberRoleReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  let (org_id, user_id) = path.into_inner();
+  auth.check_org(org_id.as_str(), Role::Owner)?;
+  org::set_member_role(
+    &server_state.db_pool,
+    org_id.as_str(),
+    user_id.as_str(),
+    req.role,
+  )
+  .await?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn remove_member(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  path: Path<(String, String)>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  let (org_id, user_id) = path.into_inner();
+  auth.check_org(org_id.as_str(), Role::Owner)?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let txn = org::remove_member(txn, org_id.as_str(), user_id.as_str()).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when remove_member")?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn new_api_key(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  org_id: Path<String>,
+  req: Json<NewApiKeyReq>,
+) -> Result<Json<NewApiKeyRsp>, ApiError> {
+  // a member creates keys for itself, the internal secret creates keys
+  // for the org owner to bootstrap the org.
+  let user_id = match (req.user_id.as_deref(), auth.user_id()) {
+    (Some(user_id), _) | (None, Some(user_id)) => user_id.to_string(),
+    (None, None) => {
+      org::get_org(&server_state.db_pool, org_id.as_str())
+        .await?
+        .owner_id
+    }
+  };
+  auth.check_member(org_id.as_str(), user_id.as_str())?;
+  let (key, secret) = auth::new_api_key(
+    &server_state.db_pool,
+    org_id.as_str(),
+    user_id.as_str(),
+    req.name.as_str(),
+  )
+  .await?;
+  Ok(Json(NewApiKeyRsp { key, secret }))
+}
+
+/// [`own_keys`] returns the member whose keys the caller can manage,
+/// or none if the caller can manage all keys of the org.
+fn own_keys<'a>(auth: &'a Auth, org_id: &str) -> Option<&'a str> {
+  match auth.check_org(org_id, Role::Owner) {
+    Ok(_) => None,
+    Err(_) => auth.user_id(),
+  }
+}
+
+async fn list_api_keys(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  org_id: Path<String>,
+) -> Result<Json<ListApiKeyRsp>, ApiError> {
+  auth.check_org(org_id.as_str(), Role::Viewer)?;
+  let keys = auth::list_api_keys(
+    &server_state.db_pool,
+    org_id.as_str(),
+    own_keys(&auth, org_id.as_str()),
+  )
+  .await?;
+  Ok(Json(ListApiKeyRsp { keys }))
+}
+
+async fn revoke_api_key(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  path: Path<(String, String)>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  let (org_id, key_id) = path.into_inner();
+  auth.check_org(org_id.as_str(), Role::Viewer)?;
+  auth::revoke_api_key(
+    &server_state.db_pool,
+    org_id.as_str(),
+    key_id.as_str(),
+    own_keys(&auth, org_id.as_str()),
+  )
+  .await?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn list_nodes(
+  server_state: Data<ServerState>,
+  auth: Auth,
+) -> Result<Json<ListNodeRsp>, ApiError> {
+  auth.check_internal()?;
+  let nodes = outbox::list_nodes(&server_state.db_pool).await?;
+  Ok(Json(ListNodeRsp { nodes }))
+}
+
+async fn changes(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  req: Query<ChangesReq>,
+) -> Result<Json<ChangesRsp>, ApiError> {
+  auth.check_internal()?;
+  let wait = Duration::from_secs(req.wait_secs.unwrap_or(0))
+    .min(outbox::MAX_CHANGES_WAIT);
+  let rsp = outbox::changes(
+    &server_state.db_pool,
+    req.since,
+    req.node_id.as_deref(),
+    wait,
+  )
+  .await?;
+  Ok(Json(rsp))
+}
+
+async fn place_env(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<PlaceEnvReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth.check_internal()?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let txn = node::place_env(txn, env_id.as_str(), &req.node_ids).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when place_env")?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn add_domain(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<DomainInfo>,
+) -> Result<Json<ListDomainRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (domains, mut txn) =
+    domain::add_domain(txn, env_id.as_str(), &req).await?;
+  set_domains(&mut txn, env_id.as_str(), &domains).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when add_domain")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(Json(ListDomainRsp { domains }))
+}
+
+async fn remove_domain(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<DomainInfo>,
+) -> Result<Json<ListDomainRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (domains, mut txn) =
+    domain::remove_domain(txn, env_id.as_str(), &req).await?;
+  set_domains(&mut txn, env_id.as_str(), &domains).await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when remove_domain")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(Json(ListDomainRsp { domains }))
+}
+
+async fn list_domains(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+) -> Result<Json<ListDomainRsp>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let domains =
+    domain::list_domains(&server_state.db_pool, env_id.as_str()).await?;
+  Ok(Json(ListDomainRsp { domains }))
+}
+
+async fn set_domains(
+  txn: &mut Transaction<'_, MySql>,
+  env_id: &str,
+  domains: &[DomainInfo],
+) -> Result<(), ApiError> {
+  let req = SetDomainsReq {
+    env_id: env_id.to_string(),
+    domains: domains.to_vec(),
+  };
+  outbox::add_event(txn, EventKind::SetDomains, env_id, &req).await?;
+  Ok(())
+}
+
+/// [`set_limits`] is for operators only, tenants can't lift their limits.
+async fn set_limits(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<EnvLimits>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth.check_internal()?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (mut txn, limits) = limit::set_limits(txn, env_id.as_str(), &req).await?;
+  let req = SetLimitsReq {
+    env_id: env_id.to_string(),
+    limits,
+  };
+  outbox::add_event(&mut txn, EventKind::SetLimits, env_id.as_str(), &req)
+    .await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when set_limits")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(HttpResponse::Ok())
+}
+
+/// [`set_egress`] takes `null` to use the default policy,
+/// the other limits stay with operators.
+async fn set_egress(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<Option<EgressPolicy>>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let (mut txn, limits) =
+    limit::set_egress(txn, env_id.as_str(), req.as_ref()).await?;
+  let req = SetLimitsReq {
+    env_id: env_id.to_string(),
+    limits,
+  };
+  outbox::add_event(&mut txn, EventKind::SetLimits, env_id.as_str(), &req)
+    .await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when set_egress")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(HttpResponse::Ok())
+}
+
+async fn get_limits(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+) -> Result<Json<EnvLimits>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let limits =
+    limit::get_limits(&server_state.db_pool, env_id.as_str()).await?;
+  Ok(Json(limits))
+}
+
+/// [`set_cors`] takes `null` to use the default policy.
+async fn set_cors(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+  req: Json<Option<CorsConfig>>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Developer)
+    .await?;
+  let txn = server_state
+    .db_pool
+    .begin()
+    .await
+    .context("Failed to start transaction")?;
+  let mut txn = cors::set_cors(txn, env_id.as_str(), req.as_ref()).await?;
+  let req = SetCorsReq {
+    env_id: env_id.to_string(),
+    cors: req.into_inner(),
+  };
+  outbox::add_event(&mut txn, EventKind::SetCors, env_id.as_str(), &req)
+    .await?;
+  txn
+    .commit()
+    .await
+    .context("Failed to commit transaction when set_cors")?;
+  deliver_events(&server_state.db_pool).await;
+  Ok(HttpResponse::Ok())
+}
+
+async fn get_cors(
+  server_state: Data<ServerState>,
+  auth: Auth,
+  env_id: Path<String>,
+) -> Result<Json<Option<CorsConfig>>, ApiError> {
+  auth
+    .check_env(&server_state.db_pool, env_id.as_str(), Role::Viewer)
+    .await?;
+  let cors = cors::get_cors(&server_state.db_pool, env_id.as_str()).await?;
+  Ok(Json(cors))
+}
+
+async fn add_tenant_db(
+  txn: &mut Transaction<'_, MySql>,
+  req: &AddTenantDBReq,
+) -> Result<(), ApiError> {
+  outbox::add_event(txn, EventKind::AddTenantDB, req.env_id.as_str(), req)
+    .await?;
+  Ok(())
+}
+
+/// [`deliver_events`] delivers the committed events right away,
+/// the failed ones are retried by [`outbox::run_delivery`].
+/// Pull nodes waiting for changes are notified as well.
+async fn deliver_events(db_pool: &MySqlPool) {
+  outbox::notify_changes();
+  if let Err(e) = outbox::deliver_events(db_pool).await {
+    tracing::warn!("Failed to deliver deploy events: {:?}", e);
+  }
+}
+
+/// [`control_cors`] only allows the origins in `DARX_CONTROL_CORS_ORIGINS`,
+/// comma separated, like the console of darx. None is allowed by default.
+fn control_cors() -> Cors {
+  let origins = env::var("DARX_CONTROL_CORS_ORIGINS").unwrap_or_default();
+  origins
+    .split(',')
+    .map(str::trim)
+    .filter(|origin| !origin.is_empty())
+    .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+    .allowed_methods(vec!["GET", "POST"])
+    .allowed_headers(vec![AUTHORIZATION, CONTENT_TYPE])
+    .max_age(3600)
+}
+
 #[derive(Clone)]
 struct ServerState {
   db_pool: sqlx::MySqlPool,
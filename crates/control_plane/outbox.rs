@@ -0,0 +1,364 @@
+use anyhow::{anyhow, Context, Result};
+use darx_core::api::{ChangeEvent, ChangesRsp, EventKind, NodeInfo};
+use darx_core::auth;
+use darx_core::node::{self, Node, NODE_TIMEOUT};
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sqlx::{MySql, MySqlPool, Transaction};
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+
+/// Only one delivery runs at a time, so events of an env are sent in order.
+static DELIVERY: Mutex<()> = Mutex::const_new(());
+
+static CHANGES: Lazy<Notify> = Lazy::new(Notify::new);
+
+const MAX_BACKOFF_SECS: u64 = 300;
+const BATCH_SIZE: i64 = 100;
+const DELIVER_TIMEOUT: Duration = Duration::from_secs(30);
+const CHANGES_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest a [`changes`] request waits.
+pub(crate) const MAX_CHANGES_WAIT: Duration = Duration::from_secs(30);
+
+/// [`delivery_interval`] reads how often undelivered events are retried from
+/// `DARX_DELIVERY_INTERVAL_SECS`, defaults to 5 seconds.
+pub(crate) fn delivery_interval() -> Duration {
+  let secs = env::var("DARX_DELIVERY_INTERVAL_SECS")
+    .map(|s| {
+      s.parse::<u64>()
+        .expect("Failed to parse DARX_DELIVERY_INTERVAL_SECS")
+    })
+    .unwrap_or(5);
+  Duration::from_secs(secs.max(1))
+}
+
+/// [`add_event`] saves an event in the transaction of the change,
+/// it's delivered by [`deliver_events`] after the commit.
+pub(crate) async fn add_event<T: Serialize>(
+  txn: &mut Transaction<'_, MySql>,
+  kind: EventKind,
+  env_id: &str,
+  payload: &T,
+) -> Result<()> {
+  let payload =
+    serde_json::to_value(payload).context("Failed to serialize payload")?;
+  sqlx::query!(
+    "INSERT INTO deploy_events (env_id, kind, payload) VALUES (?, ?, ?)",
+    env_id,
+    kind.as_str(),
+    payload
+  )
+  .execute(&mut **txn)
+  .await
+  .context("Failed to insert into deploy_events table")?;
+  Ok(())
+}
+
+/// [`deliver_events`] sends the pending events to every live push node,
+/// the nodes are delivered to concurrently.
+pub(crate) async fn deliver_events(db_pool: &MySqlPool) -> Result<usize> {
+  let _guard = DELIVERY.lock().await;
+  let nodes = node::push_nodes(db_pool).await?;
+  let results =
+    join_all(nodes.iter().map(|n| deliver_node_events(db_pool, n))).await;
+  let mut delivered = 0;
+  for (n, r) in nodes.iter().zip(results.into_iter()) {
+    match r {
+      Ok(cnt) => delivered += cnt,
+      Err(e) => {
+        tracing::warn!(node = n.id, "Failed to deliver deploy events: {:?}", e)
+      }
+    }
+  }
+  Ok(delivered)
+}
+
+/// [`deliver_node_events`] sends the pending events of a node in order.
+/// A failed event is retried with backoff,
+/// the later events of its env wait until it's delivered.
+async fn deliver_node_events(
+  db_pool: &MySqlPool,
+  node: &Node,
+) -> Result<usize> {
+  let events = sqlx::query!(
+    "SELECT e.id AS id, e.env_id AS env_id, e.kind AS kind, e.payload AS payload FROM deploy_events e WHERE e.id > ? AND NOT EXISTS (SELECT 1 FROM deploy_event_deliveries d WHERE d.event_id = e.id AND d.node_id = ? AND d.delivered_at IS NOT NULL) AND (NOT EXISTS (SELECT 1 FROM env_placements p WHERE p.env_id = e.env_id) OR EXISTS (SELECT 1 FROM env_placements p WHERE p.env_id = e.env_id AND p.node_id = ?)) ORDER BY e.id LIMIT ?",
+    node.first_event_id,
+    node.id,
+    node.id,
+    BATCH_SIZE
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query deploy_events table")?;
+  if events.is_empty() {
+    return Ok(0);
+  }
+  let waiting: HashSet<i64> = sqlx::query!(
+    "SELECT event_id FROM deploy_event_deliveries WHERE node_id = ? AND delivered_at IS NULL AND next_attempt_at > CURRENT_TIMESTAMP(3)",
+    node.id
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query deploy_event_deliveries table")?
+  .into_iter()
+  .map(|d| d.event_id)
+  .collect();
+
+  let mut delivered = 0;
+  let mut blocked_envs = HashSet::new();
+  for event in events.iter() {
+    if blocked_envs.contains(&event.env_id) {
+      continue;
+    }
+    if waiting.contains(&event.id) {
+      blocked_envs.insert(event.env_id.clone());
+      continue;
+    }
+    match deliver(node.url.as_str(), event.kind.as_str(), &event.payload).await
+    {
+      Ok(_) => {
+        sqlx::query!(
+          "INSERT INTO deploy_event_deliveries (event_id, node_id, attempts, delivered_at) VALUES (?, ?, 1, CURRENT_TIMESTAMP(3)) ON DUPLICATE KEY UPDATE attempts = attempts + 1, delivered_at = VALUES(delivered_at)",
+          event.id,
+          node.id
+        )
+        .execute(db_pool)
+        .await
+        .context("Failed to update deploy_event_deliveries table")?;
+        delivered += 1;
+      }
+      Err(e) => {
+        tracing::warn!(
+          env = event.env_id,
+          node = node.id,
+          "Failed to deliver deploy event {}: {:?}",
+          event.id,
+          e
+        );
+        // the backoff doubles with the attempts.
+        sqlx::query!(
+          "INSERT INTO deploy_event_deliveries (event_id, node_id, attempts, last_error, next_attempt_at) VALUES (?, ?, 1, ?, DATE_ADD(CURRENT_TIMESTAMP(3), INTERVAL 1 SECOND)) ON DUPLICATE KEY UPDATE next_attempt_at = DATE_ADD(CURRENT_TIMESTAMP(3), INTERVAL LEAST(POW(2, attempts), ?) SECOND), attempts = attempts + 1, last_error = VALUES(last_error)",
+          event.id,
+          node.id,
+          format!("{:?}", e),
+          MAX_BACKOFF_SECS
+        )
+        .execute(db_pool)
+        .await
+        .context("Failed to update deploy_event_deliveries table")?;
+        blocked_envs.insert(event.env_id.clone());
+      }
+    }
+  }
+  Ok(delivered)
+}
+
+/// [`list_nodes`] returns the nodes with their delivery status.
+pub(crate) async fn list_nodes(db_pool: &MySqlPool) -> Result<Vec<NodeInfo>> {
+  let nodes = sqlx::query!(
+    "SELECT id, url, pull, first_event_id, synced_event_id, heartbeat_at FROM data_plane_nodes ORDER BY id"
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query data_plane_nodes table")?;
+  let now = OffsetDateTime::now_utc();
+  let mut infos = vec![];
+  for n in nodes.into_iter() {
+    // a pull node has no delivery rows, its events are pending until synced.
+    let synced = n.first_event_id.max(n.synced_event_id);
+    let pending = sqlx::query!(
+      "SELECT COUNT(*) AS cnt FROM deploy_events e WHERE e.id > ? AND NOT EXISTS (SELECT 1 FROM deploy_event_deliveries d WHERE d.event_id = e.id AND d.node_id = ? AND d.delivered_at IS NOT NULL) AND (NOT EXISTS (SELECT 1 FROM env_placements p WHERE p.env_id = e.env_id) OR EXISTS (SELECT 1 FROM env_placements p WHERE p.env_id = e.env_id AND p.node_id = ?))",
+      synced,
+      n.id,
+      n.id
+    )
+    .fetch_one(db_pool)
+    .await
+    .context("Failed to query deploy_events table")?;
+    let failed = sqlx::query!(
+      "SELECT last_error FROM deploy_event_deliveries WHERE node_id = ? AND event_id > ? AND delivered_at IS NULL ORDER BY event_id",
+      n.id,
+      n.first_event_id
+    )
+    .fetch_all(db_pool)
+    .await
+    .context("Failed to query deploy_event_deliveries table")?;
+
+    let heartbeat_at = n.heartbeat_at.assume_utc();
+    infos.push(NodeInfo {
+      id: n.id,
+      url: n.url,
+      pull: n.pull != 0,
+      alive: now - heartbeat_at < NODE_TIMEOUT,
+      heartbeat_at,
+      pending_events: pending.cnt,
+      failed_events: failed.len() as i64,
+      last_error: failed.into_iter().next().and_then(|f| f.last_error),
+    });
+  }
+  Ok(infos)
+}
+
+/// [`changes`] returns the events after `since`, of the envs placed on
+/// `node_id` if set. It waits up to `wait` if there is no event yet.
+pub(crate) async fn changes(
+  db_pool: &MySqlPool,
+  since: i64,
+  node_id: Option<&str>,
+  wait: Duration,
+) -> Result<ChangesRsp> {
+  if let Some(node_id) = node_id {
+    node::sync_node(db_pool, node_id, since).await?;
+  }
+  // events removed by `remove_expired` can't be pulled any more.
+  let oldest = sqlx::query!("SELECT MIN(id) AS id FROM deploy_events")
+    .fetch_one(db_pool)
+    .await
+    .context("Failed to query deploy_events table")?;
+  let reset = oldest.id.map(|id| id - 1 > since).unwrap_or(false);
+
+  let deadline = Instant::now() + wait;
+  loop {
+    let events = find_changes(db_pool, since, node_id).await?;
+    let now = Instant::now();
+    if !events.is_empty() || now >= deadline {
+      let cursor = events.last().map(|e| e.id).unwrap_or(since);
+      return Ok(ChangesRsp {
+        events,
+        cursor,
+        reset,
+      });
+    }
+    // events committed by other control plane instances are not notified,
+    // so the db is polled as well.
+    let _ = timeout(
+      (deadline - now).min(CHANGES_POLL_INTERVAL),
+      CHANGES.notified(),
+    )
+    .await;
+  }
+}
+
+/// [`notify_changes`] wakes up the pending [`changes`] requests,
+/// it's called after events are committed.
+pub(crate) fn notify_changes() {
+  CHANGES.notify_waiters();
+}
+
+async fn find_changes(
+  db_pool: &MySqlPool,
+  since: i64,
+  node_id: Option<&str>,
+) -> Result<Vec<ChangeEvent>> {
+  let events = match node_id {
+    Some(node_id) => sqlx::query!(
+      "SELECT e.id AS id, e.env_id AS env_id, e.kind AS kind, e.payload AS payload FROM deploy_events e WHERE e.id > ? AND (NOT EXISTS (SELECT 1 FROM env_placements p WHERE p.env_id = e.env_id) OR EXISTS (SELECT 1 FROM env_placements p WHERE p.env_id = e.env_id AND p.node_id = ?)) ORDER BY e.id LIMIT ?",
+      since,
+      node_id,
+      BATCH_SIZE
+    )
+    .fetch_all(db_pool)
+    .await
+    .context("Failed to query deploy_events table")?
+    .into_iter()
+    .map(|e| ChangeEvent {
+      id: e.id,
+      env_id: e.env_id,
+      kind: e.kind,
+      payload: e.payload,
+    })
+    .collect(),
+    None => sqlx::query!(
+      "SELECT id, env_id, kind, payload FROM deploy_events WHERE id > ? ORDER BY id LIMIT ?",
+      since,
+      BATCH_SIZE
+    )
+    .fetch_all(db_pool)
+    .await
+    .context("Failed to query deploy_events table")?
+    .into_iter()
+    .map(|e| ChangeEvent {
+      id: e.id,
+      env_id: e.env_id,
+      kind: e.kind,
+      payload: e.payload,
+    })
+    .collect(),
+  };
+  Ok(events)
+}
+
+/// [`run_delivery`] retries the pending events periodically.
+/// The events a week ago are removed even if not delivered,
+/// the nodes missed them converge by reconciling.
+pub(crate) async fn run_delivery(db_pool: MySqlPool, period: Duration) {
+  let mut interval = interval(period);
+  interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  loop {
+    interval.tick().await;
+    if let Err(e) = deliver_events(&db_pool).await {
+      tracing::error!("Failed to deliver deploy events: {:?}", e);
+    }
+    if let Err(e) = remove_expired(&db_pool).await {
+      tracing::error!("Failed to remove expired deploy events: {:?}", e);
+    }
+  }
+}
+
+/// [`remove_expired`] removes the events and nodes inactive for a week.
+async fn remove_expired(db_pool: &MySqlPool) -> Result<()> {
+  sqlx::query!(
+    "DELETE FROM deploy_event_deliveries WHERE event_id IN (SELECT id FROM deploy_events WHERE created_at < DATE_SUB(CURRENT_TIMESTAMP(3), INTERVAL 7 DAY))"
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to delete from deploy_event_deliveries table")?;
+  sqlx::query!(
+    "DELETE FROM deploy_events WHERE created_at < DATE_SUB(CURRENT_TIMESTAMP(3), INTERVAL 7 DAY)"
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to delete from deploy_events table")?;
+  sqlx::query!(
+    "DELETE FROM data_plane_nodes WHERE heartbeat_at < DATE_SUB(CURRENT_TIMESTAMP(3), INTERVAL 7 DAY)"
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to delete from data_plane_nodes table")?;
+  sqlx::query!(
+    "DELETE FROM deploy_event_deliveries WHERE node_id NOT IN (SELECT id FROM data_plane_nodes)"
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to delete from deploy_event_deliveries table")?;
+  Ok(())
+}
+
+async fn deliver(
+  node_url: &str,
+  kind: &str,
+  payload: &serde_json::Value,
+) -> Result<()> {
+  let kind = EventKind::parse(kind)?;
+  let rsp = reqwest::Client::new()
+    .post(format!("{}/{}", node_url, kind.as_str()))
+    .bearer_auth(auth::internal_secret())
+    .timeout(DELIVER_TIMEOUT)
+    .json(payload)
+    .send()
+    .await
+    .with_context(|| format!("Failed to send {} request", kind.as_str()))?;
+  if !rsp.status().is_success() {
+    return Err(anyhow!(
+      "Failed to {}: {}",
+      kind.as_str(),
+      rsp.text().await.unwrap_or_default()
+    ));
+  }
+  Ok(())
+}
@@ -29,7 +29,6 @@ table "organizations" {
     columns = [column.id]
   }
   index "org_owner_id_idx" {
-    unique  = true
     columns = [column.owner_id]
   }
 }
@@ -61,6 +60,12 @@ table "org_members" {
     null = false
     type = varchar(255)
   }
+  # owner, developer or viewer.
+  column "role" {
+    null    = false
+    type    = varchar(32)
+    default = "viewer"
+  }
   primary_key {
     columns = [column.id]
   }
@@ -70,6 +75,52 @@ table "org_members" {
   }
 }
 
+# api keys of an org, only the hash of a key is saved.
+table "api_keys" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = varchar(255)
+  }
+  column "created_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "org_id" {
+    null = false
+    type = varchar(255)
+  }
+  # the member the key acts as, with the member's role.
+  column "user_id" {
+    null = false
+    type = varchar(255)
+  }
+  column "name" {
+    null = false
+    type = varchar(255)
+  }
+  column "key_hash" {
+    null = false
+    type = varchar(64)
+  }
+  column "revoked_at" {
+    null = true
+    type = datetime(3)
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "api_keys_key_hash_idx" {
+    unique  = true
+    columns = [column.key_hash]
+  }
+  index "api_keys_org_id_idx" {
+    columns = [column.org_id]
+  }
+}
+
 table "projects" {
   schema  = schema.darx_control
   collate = "utf8mb4_unicode_ci"
@@ -135,12 +186,24 @@ table "envs" {
     type    = bigint
     default = 0
   }
+  # the number of latest deploys kept by the deploy gc.
+  column "keep_deploys" {
+    null    = false
+    type    = int
+    default = 20
+  }
+  # the CORS policy of invoking the env's functions,
+  # any origin is allowed without it.
+  column "cors" {
+    null = true
+    type = json
+  }
   primary_key {
     columns = [column.id]
   }
-  index "envs_project_id_idx" {
+  index "envs_project_id_name_idx" {
     unique = true
-    columns = [column.project_id]
+    columns = [column.project_id, column.name]
   }
 }
 
@@ -282,10 +345,10 @@ table "codes" {
     null = false
     type = text
   }
-  column "content" {
+  # sha256 of the content, the content is stored in code_blobs.
+  column "content_hash" {
     null = false
-    # 16 MB
-    type = mediumblob
+    type = varchar(64)
   }
   column "content_size" {
     null = false
@@ -297,6 +360,36 @@ table "codes" {
   index "codes_deployment_id_idx" {
     columns = [column.deploy_id]
   }
+  index "codes_content_hash_idx" {
+    columns = [column.content_hash]
+  }
+}
+
+# code contents are stored once by hash and shared by deploys.
+table "code_blobs" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "hash" {
+    null = false
+    type = varchar(64)
+  }
+  column "created_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "content" {
+    null = false
+    # 16 MB
+    type = mediumblob
+  }
+  column "content_size" {
+    null = false
+    type = int
+  }
+  primary_key {
+    columns = [column.hash]
+  }
 }
 
 table "http_routes" {
@@ -449,9 +542,16 @@ table "env_vars" {
     type = varchar(255)
   }
 
+  # encrypted when is_secret is set
   column "value" {
     null = false
-    type = varchar(255)
+    type = text
+  }
+
+  column "is_secret" {
+    null = false
+    type = bool
+    default = false
   }
 
   column "created_at" {
@@ -467,10 +567,12 @@ table "env_vars" {
     on_update = sql("CURRENT_TIMESTAMP(3)")
   }
 
+  # 0 for a live var, the row's own id once deleted,
+  # so that the same key can be deleted more than once.
   column "is_delete" {
     null = false
-    type = bool
-    default = false
+    type = bigint
+    default = 0
   }
 
   primary_key  {
@@ -499,9 +601,15 @@ table "deploy_vars" {
     null = false
     type = varchar(255)
   }
+  # encrypted when is_secret is set
   column "value" {
     null = false
-    type = varchar(255)
+    type = text
+  }
+  column "is_secret" {
+    null    = false
+    type    = bool
+    default = false
   }
   column "created_at" {
     null    = false
@@ -523,6 +631,266 @@ table "deploy_vars" {
   }
 }
 
+# outbox of the changes to apply on the data plane,
+# saved in the same transaction as the change and delivered to every node.
+table "deploy_events" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = bigint
+    auto_increment = true
+  }
+  column "created_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "env_id" {
+    null = false
+    type = varchar(255)
+  }
+  # the data plane api to call, e.g. add_code_deploy.
+  column "kind" {
+    null = false
+    type = varchar(64)
+  }
+  column "payload" {
+    null = false
+    type = json
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "deploy_events_created_at_idx" {
+    columns = [column.created_at]
+  }
+}
+
+# delivery state of a deploy event on a data plane node,
+# there is no row until the first attempt.
+table "deploy_event_deliveries" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = bigint
+    auto_increment = true
+  }
+  column "event_id" {
+    null = false
+    type = bigint
+  }
+  column "node_id" {
+    null = false
+    type = varchar(255)
+  }
+  column "attempts" {
+    null    = false
+    type    = int
+    default = 0
+  }
+  column "last_error" {
+    null = true
+    type = text
+  }
+  column "next_attempt_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "delivered_at" {
+    null = true
+    type = datetime(3)
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "deploy_event_deliveries_event_id_node_id_idx" {
+    unique  = true
+    columns = [column.event_id, column.node_id]
+  }
+  index "deploy_event_deliveries_node_id_idx" {
+    columns = [column.node_id, column.delivered_at]
+  }
+}
+
+# data plane nodes, registered by the nodes themselves on startup.
+table "data_plane_nodes" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = varchar(255)
+  }
+  column "url" {
+    null = false
+    type = varchar(1024)
+  }
+  # the events up to it are loaded by the node on startup.
+  column "first_event_id" {
+    null    = false
+    type    = bigint
+    default = 0
+  }
+  # the node pulls the events instead of being pushed to.
+  column "pull" {
+    null    = false
+    type    = bool
+    default = false
+  }
+  # the events up to it are applied by a pull node.
+  column "synced_event_id" {
+    null    = false
+    type    = bigint
+    default = 0
+  }
+  column "created_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "heartbeat_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  primary_key {
+    columns = [column.id]
+  }
+}
+
+# the nodes an env is placed on, an env without rows is placed on all nodes.
+table "env_placements" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = bigint
+    auto_increment = true
+  }
+  column "env_id" {
+    null = false
+    type = varchar(255)
+  }
+  column "node_id" {
+    null = false
+    type = varchar(255)
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "env_placements_env_id_node_id_idx" {
+    unique  = true
+    columns = [column.env_id, column.node_id]
+  }
+}
+
+# custom hostnames of envs, a hostname can be shared by envs with
+# different path prefixes.
+table "domains" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = bigint
+    auto_increment = true
+  }
+  column "created_at" {
+    null    = false
+    type    = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "hostname" {
+    null = false
+    type = varchar(255)
+  }
+  column "path_prefix" {
+    null    = false
+    type    = varchar(255)
+    default = ""
+  }
+  column "env_id" {
+    null = false
+    type = varchar(255)
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "domains_hostname_path_prefix_idx" {
+    unique  = true
+    columns = [column.hostname, column.path_prefix]
+  }
+  index "domains_env_id_idx" {
+    columns = [column.env_id]
+  }
+}
+
+# the rate limits, concurrency cap and monthly quota of envs,
+# set by operators and enforced by data plane nodes, the egress hosts
+# and ports are set by developers.
+table "env_limits" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = bigint
+    auto_increment = true
+  }
+  column "updated_at" {
+    null = false
+    type = datetime(3)
+    default = sql("CURRENT_TIMESTAMP(3)")
+    on_update = sql("CURRENT_TIMESTAMP(3)")
+  }
+  column "env_id" {
+    null = false
+    type = varchar(255)
+  }
+  column "limits" {
+    null = false
+    type = json
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "env_limits_env_id_idx" {
+    unique  = true
+    columns = [column.env_id]
+  }
+}
+
+# the invocations of envs in a month like `2023-06`, in UTC.
+table "env_usage" {
+  schema  = schema.darx_control
+  collate = "utf8mb4_unicode_ci"
+  column "id" {
+    null = false
+    type = bigint
+    auto_increment = true
+  }
+  column "env_id" {
+    null = false
+    type = varchar(255)
+  }
+  column "month" {
+    null = false
+    type = varchar(7)
+  }
+  column "invocations" {
+    null    = false
+    type    = bigint
+    default = 0
+  }
+  primary_key {
+    columns = [column.id]
+  }
+  index "env_usage_env_id_month_idx" {
+    unique  = true
+    columns = [column.env_id, column.month]
+  }
+}
+
 table "deploy_log" {
   schema  = schema.darx_control
   collate = "utf8mb4_unicode_ci"
@@ -1,61 +1,425 @@
+use crate::code::blob::content_hash;
 use crate::{Code, DeploySeq, HttpRoute};
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use async_recursion::async_recursion;
 use darx_db::TenantDBInfo;
+use darx_isolate_runtime::IMPORT_MAP_FILE;
+pub use darx_isolate_runtime::{
+  EgressPolicy, FetchLimits, JsErrorDetails, StackFrame,
+};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::env;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::fs;
 use tracing::info;
 
-pub fn add_code_deploy_url() -> String {
-  format!(
-    "{}/add_code_deploy",
-    env::var("DATA_PLANE_URL")
-      .expect("DATA_PLANE_URL should be configured to add route"),
-  )
+///
+/// deploy_code
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployCodeReq {
+  pub tag: Option<String>,
+  pub desc: Option<String>,
+  pub codes: Vec<Code>,
+  /// codes already uploaded, see [`MissingBlobsReq`].
+  #[serde(default)]
+  pub code_refs: Vec<CodeRef>,
 }
 
-pub fn add_plugin_deploy_url() -> String {
-  format!(
-    "{}/add_plugin_deploy",
-    env::var("DATA_PLANE_URL")
-      .expect("DATA_PLANE_URL should be configured to add route"),
-  )
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CodeRef {
+  pub fs_path: String,
+  /// see [`crate::code::blob::content_hash`].
+  pub hash: String,
 }
 
-pub fn add_var_deploy_url() -> String {
-  format!(
-    "{}/add_var_deploy",
-    env::var("DATA_PLANE_URL")
-      .expect("DATA_PLANE_URL should be configured to add route"),
-  )
+///
+/// missing_blobs, the client asks which codes need to be uploaded
+/// before deploy_code.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingBlobsReq {
+  pub hashes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingBlobsRsp {
+  pub missing: Vec<String>,
 }
 
-pub fn add_tenant_db_url() -> String {
-  format!(
-    "{}/add_tenant_db",
-    env::var("DATA_PLANE_URL")
-      .expect("DATA_PLANE_URL should be configured to add route"),
-  )
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployCodeRsp {
+  pub http_routes: Vec<HttpRoute>,
 }
 
 ///
-/// deploy_code
+/// deploy history
 ///
 #[derive(Debug, Serialize, Deserialize)]
-pub struct DeployCodeReq {
+pub struct ListDeployReq {
+  /// only deploys with a smaller deploy_seq are returned,
+  /// pass [`ListDeployRsp::next`] to fetch the next page.
+  pub before: Option<DeploySeq>,
+  pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListDeployRsp {
+  pub deploys: Vec<DeployInfo>,
+  /// `None` if this is the last page.
+  pub next: Option<DeploySeq>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployInfo {
+  pub deploy_seq: DeploySeq,
   pub tag: Option<String>,
   pub desc: Option<String>,
+  #[serde(with = "time::serde::rfc3339")]
+  pub created_at: OffsetDateTime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetDeployRsp {
+  pub deploy: DeployInfo,
+  /// codes, routes and vars that were serving once this deploy took effect.
   pub codes: Vec<Code>,
+  pub http_routes: Vec<HttpRoute>,
+  pub vars: Vec<VarInfo>,
 }
 
+///
+/// deploy diff
+///
 #[derive(Debug, Serialize, Deserialize)]
-pub struct DeployCodeRsp {
+pub struct DiffDeployReq {
+  pub from: DeploySeq,
+  pub to: DeploySeq,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffDeployRsp {
+  pub codes: Vec<CodeDiff>,
+  pub http_routes: Vec<RouteDiff>,
+  pub vars: Vec<VarDiff>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum DiffKind {
+  #[serde(rename = "added")]
+  Added,
+  #[serde(rename = "removed")]
+  Removed,
+  #[serde(rename = "changed")]
+  Changed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CodeDiff {
+  pub fs_path: String,
+  pub kind: DiffKind,
+  /// unified diff of the content.
+  pub diff: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteDiff {
+  pub http_path: String,
+  pub kind: DiffKind,
+  pub from: Option<HttpRoute>,
+  pub to: Option<HttpRoute>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VarDiff {
+  pub key: String,
+  pub kind: DiffKind,
+  /// values are masked if the var is a secret.
+  pub from: Option<String>,
+  pub to: Option<String>,
+}
+
+///
+/// deploy gc
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GcDeployReq {
+  /// only reports the deploys to delete.
+  #[serde(default)]
+  pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GcDeployRsp {
+  pub dry_run: bool,
+  /// the deleted deploys, or the deploys to delete if `dry_run`.
+  pub deploys: Vec<DeployInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRetentionReq {
+  /// the number of latest deploys to keep,
+  /// tagged deploys and the deploys they use are always kept.
+  pub keep_deploys: u32,
+}
+
+// orgs and members
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewOrgReq {
+  pub org_id: String,
+  /// the user is added as the first member with [`Role::Owner`].
+  pub owner_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrgInfo {
+  pub id: String,
+  pub owner_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddMemberReq {
+  pub user_id: String,
+  pub role: Role,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMemberRoleReq {
+  pub role: Role,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListMemberRsp {
+  pub members: Vec<MemberInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemberInfo {
+  pub user_id: String,
+  pub role: Role,
+  #[serde(with = "time::serde::rfc3339")]
+  pub created_at: OffsetDateTime,
+}
+
+/// [`Role`] of an org member, a role has all permissions of the roles
+/// before it.
+#[derive(
+  Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Role {
+  /// reads projects, envs, deploys and vars.
+  #[serde(rename = "viewer")]
+  Viewer,
+  /// creates projects and envs, deploys codes and vars.
+  #[serde(rename = "developer")]
+  Developer,
+  /// manages members and their api keys.
+  #[serde(rename = "owner")]
+  Owner,
+}
+
+impl Role {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Role::Viewer => "viewer",
+      Role::Developer => "developer",
+      Role::Owner => "owner",
+    }
+  }
+
+  pub fn parse(role: &str) -> anyhow::Result<Self> {
+    match role {
+      "viewer" => Ok(Role::Viewer),
+      "developer" => Ok(Role::Developer),
+      "owner" => Ok(Role::Owner),
+      _ => anyhow::bail!("Unknown role: {}", role),
+    }
+  }
+}
+
+// api keys
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiKeyReq {
+  pub name: String,
+  /// the member the key acts as, it's the caller by default.
+  #[serde(default)]
+  pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiKeyRsp {
+  pub key: ApiKeyInfo,
+  /// the api key, sent as `Authorization: Bearer <secret>`.
+  /// It's only returned once.
+  pub secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListApiKeyRsp {
+  pub keys: Vec<ApiKeyInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+  pub id: String,
+  pub name: String,
+  pub user_id: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub created_at: OffsetDateTime,
+}
+
+///
+/// custom domains
+///
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DomainInfo {
+  pub hostname: String,
+  /// the leading segments of the function url, like `billing`,
+  /// they are removed before matching the routes of the env.
+  /// An empty prefix matches all function urls of the hostname.
+  #[serde(default)]
+  pub path_prefix: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListDomainRsp {
+  pub domains: Vec<DomainInfo>,
+}
+
+///
+/// env limits
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+  /// the requests allowed per second on average.
+  pub per_sec: f64,
+  /// the requests allowed at once, defaults to `per_sec` rounded up.
+  #[serde(default)]
+  pub burst: Option<u32>,
+}
+
+/// [`EnvLimits`] are checked by the data plane before invoking a function
+/// of the env, an unset limit is not checked.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct EnvLimits {
+  #[serde(default)]
+  pub rate_limit: Option<RateLimit>,
+  /// the limits of function urls like `foo.Hi`, besides `rate_limit`.
+  #[serde(default)]
+  pub route_rate_limits: HashMap<String, RateLimit>,
+  /// the invocations running at the same time on a node.
+  #[serde(default)]
+  pub max_concurrency: Option<u32>,
+  /// the invocations allowed in a calendar month in UTC.
+  #[serde(default)]
+  pub monthly_quota: Option<u64>,
+  /// the hosts the functions can fetch from, the default policy if none.
+  /// Developers set the hosts and ports, operators the private networks.
+  #[serde(default)]
+  pub egress: Option<EgressPolicy>,
+  /// the limits of the fetches of an invocation, the default ones if none.
+  #[serde(default)]
+  pub fetch: Option<FetchLimits>,
+}
+
+///
+/// env cors
+///
+/// [`CorsConfig`] is the CORS policy of invoking the functions of an env,
+/// `*` allows any origin, method or header.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CorsConfig {
+  /// origins like `https://example.com`.
+  #[serde(default)]
+  pub allowed_origins: Vec<String>,
+  #[serde(default = "default_cors_methods")]
+  pub allowed_methods: Vec<String>,
+  /// the request headers besides the CORS-safelisted ones.
+  #[serde(default)]
+  pub allowed_headers: Vec<String>,
+  #[serde(default)]
+  pub allow_credentials: bool,
+  /// the seconds browsers may cache a preflight response.
+  #[serde(default)]
+  pub max_age: Option<u32>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+  vec!["POST".to_string()]
+}
+
+impl Default for CorsConfig {
+  /// allows any origin, the policy of an env without one.
+  fn default() -> Self {
+    CorsConfig {
+      allowed_origins: vec!["*".to_string()],
+      allowed_methods: vec!["*".to_string()],
+      allowed_headers: vec!["*".to_string()],
+      allow_credentials: false,
+      max_age: None,
+    }
+  }
+}
+
+/// [`SetCorsReq`] sets the CORS policy of an env,
+/// the default one is used if `cors` is none.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetCorsReq {
+  pub env_id: String,
+  pub cors: Option<CorsConfig>,
+}
+
+///
+/// data plane nodes
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListNodeRsp {
+  pub nodes: Vec<NodeInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeInfo {
+  pub id: String,
+  pub url: String,
+  /// the node pulls [`ChangesRsp`] instead of being pushed to.
+  pub pull: bool,
+  /// see [`crate::node::NODE_TIMEOUT`].
+  pub alive: bool,
+  #[serde(with = "time::serde::rfc3339")]
+  pub heartbeat_at: OffsetDateTime,
+  /// the events not delivered to the node yet.
+  pub pending_events: i64,
+  /// the pending events failed at least once.
+  pub failed_events: i64,
+  /// the error of the earliest failed event.
+  pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaceEnvReq {
+  /// the env is placed on all nodes if empty.
+  pub node_ids: Vec<String>,
+}
+
+///
+/// promote
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteReq {
+  pub from_env_id: String,
+  /// promote the latest code deploy of `from_env_id` if not set.
+  pub deploy_seq: Option<DeploySeq>,
+  pub desc: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteRsp {
+  pub deploy_seq: DeploySeq,
   pub http_routes: Vec<HttpRoute>,
 }
 
@@ -66,6 +430,67 @@ pub struct DeployCodeRsp {
 pub struct DeployVarReq {
   pub desc: Option<String>,
   pub vars: HashMap<String, String>,
+  /// secret vars are write-only, they are never returned by the api.
+  #[serde(default)]
+  pub secrets: HashMap<String, String>,
+}
+
+///
+/// list vars
+///
+#[derive(Serialize, Deserialize)]
+pub struct ListVarRsp {
+  pub vars: Vec<VarInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetVarReq {
+  pub key: String,
+  pub value: String,
+  #[serde(default)]
+  pub is_secret: bool,
+  pub desc: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnsetVarReq {
+  pub key: String,
+  pub desc: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VarDeployRsp {
+  pub deploy_seq: DeploySeq,
+}
+
+///
+/// var history
+///
+#[derive(Serialize, Deserialize)]
+pub struct VarHistoryRsp {
+  pub deploys: Vec<VarDeployInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VarDeployInfo {
+  pub deploy_seq: DeploySeq,
+  pub desc: Option<String>,
+  #[serde(with = "time::serde::rfc3339")]
+  pub created_at: OffsetDateTime,
+  pub vars: Vec<VarInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VarInfo {
+  pub key: String,
+  /// `value` is masked if the var is a secret.
+  pub value: String,
+  pub is_secret: bool,
+  /// `updated_at` is when the var was last set in the env,
+  /// or when the deploy was made for the vars of a deploy,
+  /// because every var deploy saves all vars of the env again.
+  #[serde(with = "time::serde::rfc3339")]
+  pub updated_at: OffsetDateTime,
 }
 
 ///
@@ -112,6 +537,25 @@ pub struct EnvInfo {
   pub name: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct LoadEnvReq {
+  /// the project's first env is loaded if not set.
+  pub env: Option<String>,
+}
+
+///
+/// envs
+///
+#[derive(Serialize, Deserialize)]
+pub struct NewEnvReq {
+  pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListEnvRsp {
+  pub envs: Vec<EnvInfo>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct NewTenantProjectReq {
   pub org_id: String,
@@ -155,10 +599,103 @@ pub struct AddCodeDeployReq {
   pub codes: Vec<Code>,
   pub http_routes: Vec<HttpRoute>,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveEnvReq {
+  pub env_id: String,
+}
+
+/// [`SetDomainsReq`] replaces all domains of the env.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetDomainsReq {
+  pub env_id: String,
+  pub domains: Vec<DomainInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetLimitsReq {
+  pub env_id: String,
+  pub limits: EnvLimits,
+}
 ///
 /// control plane --> data plane api ends.
 ///
 
+/// [`EventKind`] is the data plane api a deploy event is applied by,
+/// all of them are idempotent, so an event can be delivered more than once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+  AddCodeDeploy,
+  AddPluginDeploy,
+  AddVarDeploy,
+  AddTenantDB,
+  RemoveEnv,
+  SetDomains,
+  SetLimits,
+  SetCors,
+}
+
+impl EventKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      EventKind::AddCodeDeploy => "add_code_deploy",
+      EventKind::AddPluginDeploy => "add_plugin_deploy",
+      EventKind::AddVarDeploy => "add_var_deploy",
+      EventKind::AddTenantDB => "add_tenant_db",
+      EventKind::RemoveEnv => "remove_env",
+      EventKind::SetDomains => "set_domains",
+      EventKind::SetLimits => "set_limits",
+      EventKind::SetCors => "set_cors",
+    }
+  }
+
+  pub fn parse(kind: &str) -> anyhow::Result<Self> {
+    match kind {
+      "add_code_deploy" => Ok(EventKind::AddCodeDeploy),
+      "add_plugin_deploy" => Ok(EventKind::AddPluginDeploy),
+      "add_var_deploy" => Ok(EventKind::AddVarDeploy),
+      "add_tenant_db" => Ok(EventKind::AddTenantDB),
+      "remove_env" => Ok(EventKind::RemoveEnv),
+      "set_domains" => Ok(EventKind::SetDomains),
+      "set_limits" => Ok(EventKind::SetLimits),
+      "set_cors" => Ok(EventKind::SetCors),
+      _ => anyhow::bail!("Unknown deploy event kind: {}", kind),
+    }
+  }
+}
+
+///
+/// changes, the feed of deploy events pulled by data plane nodes.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangesReq {
+  /// the id of the last event applied, see [`ChangesRsp::cursor`].
+  pub since: i64,
+  /// only the events of the envs placed on the node are returned if set.
+  pub node_id: Option<String>,
+  /// waits up to `wait_secs` for new events if there is none.
+  pub wait_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangesRsp {
+  pub events: Vec<ChangeEvent>,
+  /// pass it as [`ChangesReq::since`] in the next request.
+  pub cursor: i64,
+  /// the events after `since` are removed, the node should reload all
+  /// deploys from the control db.
+  pub reset: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeEvent {
+  pub id: i64,
+  pub env_id: String,
+  /// see [`EventKind`].
+  pub kind: String,
+  pub payload: serde_json::Value,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddVarDeployReq {
   pub env_id: String,
@@ -190,10 +727,49 @@ pub enum ApiError {
   Internal(anyhow::Error),
   #[error("Environment {0} not found")]
   EnvNotFound(String),
+  #[error("Environment {0} already exists")]
+  EnvAlreadyExists(String),
+  #[error("Invalid environment name: {0}")]
+  InvalidEnvName(String),
   #[error("Project {0} not found")]
   ProjectNotFound(String),
   #[error("Invalid plugin url: {0}")]
   InvalidPluginUrl(String),
+  #[error("Var {0} not found")]
+  VarNotFound(String),
+  #[error("Invalid var key: {0}")]
+  InvalidVarKey(String),
+  #[error("Code blob {0} not found")]
+  BlobNotFound(String),
+  #[error("Invalid retention: {0}")]
+  InvalidRetention(String),
+  #[error("Data plane node {0} not found")]
+  NodeNotFound(String),
+  #[error("Permission denied: {0}")]
+  PermissionDenied(String),
+  #[error("Api key {0} not found")]
+  ApiKeyNotFound(String),
+  #[error("Organization {0} not found")]
+  OrgNotFound(String),
+  #[error("Organization {0} already exists")]
+  OrgAlreadyExists(String),
+  #[error("Member {0} not found")]
+  MemberNotFound(String),
+  #[error("Member {0} already exists")]
+  MemberAlreadyExists(String),
+  #[error("Domain {0} already exists")]
+  DomainAlreadyExists(String),
+  #[error("Invalid domain: {0}")]
+  InvalidDomain(String),
+  #[error("Invalid limits: {0}")]
+  InvalidLimits(String),
+  #[error("Invalid cors: {0}")]
+  InvalidCors(String),
+  #[error("Invalid import: {0}")]
+  InvalidImport(String),
+  /// the reason, and the seconds to wait before retrying.
+  #[error("Too many requests: {0}")]
+  TooManyRequests(String, u64),
   #[error("function execution timeout")]
   Timeout,
 }
@@ -223,7 +799,7 @@ pub struct Error<T> {
 }
 
 impl ApiError {
-  pub const fn error_code(&self) -> (StatusCode, i32) {
+  pub fn error_code(&self) -> (StatusCode, i32) {
     match self {
       ApiError::AuthError => (StatusCode::UNAUTHORIZED, 40100),
       ApiError::IoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, 50000),
@@ -231,28 +807,63 @@ impl ApiError {
       ApiError::DeployNotFound(_) => (StatusCode::NOT_FOUND, 40401),
       ApiError::FunctionNotFound(_) => (StatusCode::NOT_FOUND, 40402),
       ApiError::FunctionParameterError(_) => (StatusCode::BAD_REQUEST, 40000),
-      ApiError::FunctionRuntimeError(_) => {
-        (StatusCode::INTERNAL_SERVER_ERROR, 50001)
+      ApiError::FunctionRuntimeError(e) => {
+        // the status of the error thrown by the function, if any.
+        match js_error_details(e)
+          .and_then(|details| details.status)
+          .and_then(|status| StatusCode::from_u16(status).ok())
+        {
+          Some(status) => (status, status.as_u16() as i32 * 100 + 90),
+          None => (StatusCode::INTERNAL_SERVER_ERROR, 50001),
+        }
       }
       ApiError::FunctionParseError(_) => (StatusCode::BAD_REQUEST, 40001),
       ApiError::TableNotFound(_) => (StatusCode::NOT_FOUND, 40403),
       ApiError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, 50099),
       ApiError::EnvNotFound(_) => (StatusCode::NOT_FOUND, 40404),
+      ApiError::EnvAlreadyExists(_) => (StatusCode::CONFLICT, 40900),
+      ApiError::InvalidEnvName(_) => (StatusCode::BAD_REQUEST, 40004),
       ApiError::ProjectNotFound(_) => (StatusCode::NOT_FOUND, 40405),
       ApiError::InvalidPluginUrl(_) => (StatusCode::BAD_REQUEST, 40002),
+      ApiError::VarNotFound(_) => (StatusCode::NOT_FOUND, 40406),
+      ApiError::InvalidVarKey(_) => (StatusCode::BAD_REQUEST, 40003),
+      ApiError::BlobNotFound(_) => (StatusCode::NOT_FOUND, 40407),
+      ApiError::InvalidRetention(_) => (StatusCode::BAD_REQUEST, 40005),
+      ApiError::NodeNotFound(_) => (StatusCode::NOT_FOUND, 40408),
+      ApiError::PermissionDenied(_) => (StatusCode::FORBIDDEN, 40300),
+      ApiError::ApiKeyNotFound(_) => (StatusCode::NOT_FOUND, 40409),
+      ApiError::OrgNotFound(_) => (StatusCode::NOT_FOUND, 40410),
+      ApiError::OrgAlreadyExists(_) => (StatusCode::CONFLICT, 40901),
+      ApiError::MemberNotFound(_) => (StatusCode::NOT_FOUND, 40411),
+      ApiError::MemberAlreadyExists(_) => (StatusCode::CONFLICT, 40902),
+      ApiError::DomainAlreadyExists(_) => (StatusCode::CONFLICT, 40903),
+      ApiError::InvalidDomain(_) => (StatusCode::BAD_REQUEST, 40006),
+      ApiError::InvalidLimits(_) => (StatusCode::BAD_REQUEST, 40007),
+      ApiError::InvalidCors(_) => (StatusCode::BAD_REQUEST, 40008),
+      ApiError::InvalidImport(_) => (StatusCode::BAD_REQUEST, 40009),
+      ApiError::TooManyRequests(_, _) => (StatusCode::TOO_MANY_REQUESTS, 42900),
       ApiError::Timeout => (StatusCode::INTERNAL_SERVER_ERROR, 50002),
     }
   }
 }
 
+/// [`js_error_details`] returns the details of the error thrown by
+/// a function, `xx90` codes are the errors with the status set by it.
+pub fn js_error_details(e: &anyhow::Error) -> Option<&JsErrorDetails> {
+  e.downcast_ref::<JsErrorDetails>()
+}
+
 macro_rules! build_error_response {
   ($self:expr, $typ:expr) => {
+    build_error_response!($self, $typ, None::<()>)
+  };
+  ($self:expr, $typ:expr, $details:expr) => {
     HttpResponse::build($self.error_code().0).json(ErrorResponse {
-      error: Error::<()> {
+      error: Error {
         code: $self.error_code().1,
         typ: Cow::from($typ),
         message: $self.to_string(),
-        details: None,
+        details: $details,
       },
     })
   };
@@ -275,8 +886,8 @@ impl ResponseError for ApiError {
       ApiError::FunctionParameterError(_) => {
         build_error_response!(self, "FunctionParameterError")
       }
-      ApiError::FunctionRuntimeError(_) => {
-        build_error_response!(self, "FunctionRuntimeError")
+      ApiError::FunctionRuntimeError(e) => {
+        build_error_response!(self, "FunctionRuntimeError", js_error_details(e))
       }
       ApiError::FunctionParseError(_) => {
         build_error_response!(self, "FunctionParseError")
@@ -286,12 +897,63 @@ impl ResponseError for ApiError {
       }
       ApiError::Internal(_) => build_error_response!(self, "Internal"),
       ApiError::EnvNotFound(_) => build_error_response!(self, "EnvNotFound"),
+      ApiError::EnvAlreadyExists(_) => {
+        build_error_response!(self, "EnvAlreadyExists")
+      }
+      ApiError::InvalidEnvName(_) => {
+        build_error_response!(self, "InvalidEnvName")
+      }
       ApiError::ProjectNotFound(_) => {
         build_error_response!(self, "ProjectNotFound")
       }
       ApiError::InvalidPluginUrl(_) => {
         build_error_response!(self, "InvalidPluginUrl")
       }
+      ApiError::VarNotFound(_) => build_error_response!(self, "VarNotFound"),
+      ApiError::InvalidVarKey(_) => {
+        build_error_response!(self, "InvalidVarKey")
+      }
+      ApiError::BlobNotFound(_) => build_error_response!(self, "BlobNotFound"),
+      ApiError::InvalidRetention(_) => {
+        build_error_response!(self, "InvalidRetention")
+      }
+      ApiError::NodeNotFound(_) => build_error_response!(self, "NodeNotFound"),
+      ApiError::PermissionDenied(_) => {
+        build_error_response!(self, "PermissionDenied")
+      }
+      ApiError::ApiKeyNotFound(_) => {
+        build_error_response!(self, "ApiKeyNotFound")
+      }
+      ApiError::OrgNotFound(_) => build_error_response!(self, "OrgNotFound"),
+      ApiError::OrgAlreadyExists(_) => {
+        build_error_response!(self, "OrgAlreadyExists")
+      }
+      ApiError::MemberNotFound(_) => {
+        build_error_response!(self, "MemberNotFound")
+      }
+      ApiError::MemberAlreadyExists(_) => {
+        build_error_response!(self, "MemberAlreadyExists")
+      }
+      ApiError::DomainAlreadyExists(_) => {
+        build_error_response!(self, "DomainAlreadyExists")
+      }
+      ApiError::InvalidDomain(_) => {
+        build_error_response!(self, "InvalidDomain")
+      }
+      ApiError::InvalidLimits(_) => {
+        build_error_response!(self, "InvalidLimits")
+      }
+      ApiError::InvalidCors(_) => build_error_response!(self, "InvalidCors"),
+      ApiError::InvalidImport(_) => {
+        build_error_response!(self, "InvalidImport")
+      }
+      ApiError::TooManyRequests(_, retry_after) => {
+        let mut rsp = build_error_response!(self, "TooManyRequests");
+        rsp
+          .headers_mut()
+          .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
+        rsp
+      }
       ApiError::Timeout => build_error_response!(self, "Timeout"),
     }
   }
@@ -305,10 +967,42 @@ pub async fn dir_to_deploy_code_req(
     tag: None,
     desc: None,
     codes,
+    code_refs: vec![],
   };
   Ok(req)
 }
 
+/// [`code_hashes`] returns the content hashes of the codes to deploy.
+pub fn code_hashes(req: &DeployCodeReq) -> Vec<String> {
+  req.codes.iter().map(|c| content_hash(&c.content)).collect()
+}
+
+/// [`incremental_deploy_code_req`] only keeps the content of `missing` codes,
+/// the others are sent as [`CodeRef`].
+pub fn incremental_deploy_code_req(
+  req: DeployCodeReq,
+  missing: &[String],
+) -> DeployCodeReq {
+  let mut codes = vec![];
+  let mut code_refs = req.code_refs;
+  for code in req.codes.into_iter() {
+    let hash = content_hash(&code.content);
+    if missing.contains(&hash) {
+      codes.push(code);
+    } else {
+      code_refs.push(CodeRef {
+        fs_path: code.fs_path,
+        hash,
+      });
+    }
+  }
+  DeployCodeReq {
+    codes,
+    code_refs,
+    ..req
+  }
+}
+
 pub async fn dir_to_deploy_plugin_req(
   dir: &Path,
 ) -> anyhow::Result<DeployPluginReq> {
@@ -336,7 +1030,8 @@ async fn collect_code(dir: &Path) -> anyhow::Result<Vec<Code>> {
   for (path, fs_path_str) in
     file_list_path_vec.iter().zip(fs_path_str_vec.iter())
   {
-    if fs_path_str.starts_with("functions/") {
+    if fs_path_str.starts_with("functions/") || fs_path_str.starts_with("lib/")
+    {
       let content = fs::read_to_string(path).await?;
       codes.push(Code {
         fs_path: fs_path_str.clone(),
@@ -345,11 +1040,20 @@ async fn collect_code(dir: &Path) -> anyhow::Result<Vec<Code>> {
       info!("upload: {}", fs_path_str);
     } else {
       info!(
-        "ignore code outside of functions directory: {}",
+        "ignore code outside of functions and lib directories: {}",
         fs_path_str
       );
     }
   }
+  // the import map maps the bare imports of both directories.
+  let import_map_path = dir.join(IMPORT_MAP_FILE);
+  if import_map_path.is_file() {
+    codes.push(Code {
+      fs_path: IMPORT_MAP_FILE.to_string(),
+      content: fs::read_to_string(import_map_path).await?,
+    });
+    info!("upload: {}", IMPORT_MAP_FILE);
+  }
   Ok(codes)
 }
 
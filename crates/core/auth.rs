@@ -0,0 +1,501 @@
+use crate::api::{ApiError, ApiKeyInfo, Role};
+use actix_web::dev::{
+  forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use anyhow::Context;
+use darx_utils::new_nano_id;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use nanoid::nanoid;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use sqlx::MySqlPool;
+use std::env;
+use std::rc::Rc;
+
+/// Api keys look like `dxk_...`, so they are easy to find when leaked.
+pub const API_KEY_PREFIX: &str = "dxk_";
+
+/// The internal secret is at least 16 bytes, an empty or guessable secret
+/// would let anyone access all orgs.
+const MIN_INTERNAL_SECRET_LEN: usize = 16;
+
+static INTERNAL_SECRET: Lazy<String> = Lazy::new(|| {
+  let secret = env::var("DARX_INTERNAL_SECRET")
+    .expect("DARX_INTERNAL_SECRET should be configured");
+  if let Err(e) = validate_internal_secret(secret.as_str()) {
+    panic!("{}", e);
+  }
+  secret
+});
+
+fn validate_internal_secret(secret: &str) -> anyhow::Result<()> {
+  if secret.trim().len() < MIN_INTERNAL_SECRET_LEN {
+    anyhow::bail!(
+      "DARX_INTERNAL_SECRET should be at least {} bytes",
+      MIN_INTERNAL_SECRET_LEN
+    );
+  }
+  Ok(())
+}
+
+/// [`internal_secret`] is shared by the control plane and data plane nodes,
+/// it's sent as a bearer token on the calls between them.
+pub fn internal_secret() -> &'static str {
+  INTERNAL_SECRET.as_str()
+}
+
+/// [`Auth`] is who sends a control plane request, see [`ApiKeyAuth`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Auth {
+  /// a data plane node or an operator with the internal secret,
+  /// it can access all orgs.
+  Internal,
+  /// an org member with an api key, it has the member's role in the org.
+  Member {
+    org_id: String,
+    user_id: String,
+    role: Role,
+  },
+}
+
+impl Auth {
+  pub fn user_id(&self) -> Option<&str> {
+    match self {
+      Auth::Internal => None,
+      Auth::Member { user_id, .. } => Some(user_id.as_str()),
+    }
+  }
+
+  pub fn check_internal(&self) -> Result<(), ApiError> {
+    match self {
+      Auth::Internal => Ok(()),
+      Auth::Member { .. } => Err(ApiError::PermissionDenied(
+        "internal api is not allowed".to_string(),
+      )),
+    }
+  }
+
+  /// [`Auth::check_org`] checks the caller has `role` or a higher one
+  /// in the org.
+  pub fn check_org(&self, org_id: &str, role: Role) -> Result<(), ApiError> {
+    match self {
+      Auth::Internal => Ok(()),
+      Auth::Member {
+        org_id: id,
+        role: r,
+        ..
+      } if id == org_id && *r >= role => Ok(()),
+      Auth::Member { .. } => Err(ApiError::PermissionDenied(format!(
+        "{} of org {} is required",
+        role.as_str(),
+        org_id
+      ))),
+    }
+  }
+
+  /// [`Auth::check_member`] checks the caller can act for `user_id`,
+  /// a member acts for itself and an owner acts for all members.
+  pub fn check_member(
+    &self,
+    org_id: &str,
+    user_id: &str,
+  ) -> Result<(), ApiError> {
+    if self.user_id() == Some(user_id) {
+      return self.check_org(org_id, Role::Viewer);
+    }
+    self.check_org(org_id, Role::Owner)
+  }
+
+  pub async fn check_project(
+    &self,
+    db_pool: &MySqlPool,
+    project_id: &str,
+    role: Role,
+  ) -> Result<(), ApiError> {
+    let project =
+      sqlx::query!("SELECT org_id FROM projects WHERE id = ?", project_id)
+        .fetch_optional(db_pool)
+        .await
+        .context("Failed to query projects table")?
+        .ok_or(ApiError::ProjectNotFound(project_id.to_string()))?;
+    self.check_org(project.org_id.as_str(), role)
+  }
+
+  pub async fn check_env(
+    &self,
+    db_pool: &MySqlPool,
+    env_id: &str,
+    role: Role,
+  ) -> Result<(), ApiError> {
+    let env = sqlx::query!(
+      "SELECT projects.org_id AS org_id FROM envs INNER JOIN projects ON projects.id = envs.project_id WHERE envs.id = ?",
+      env_id
+    )
+    .fetch_optional(db_pool)
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+    self.check_org(env.org_id.as_str(), role)
+  }
+}
+
+impl FromRequest for Auth {
+  type Error = ApiError;
+  type Future = Ready<Result<Self, Self::Error>>;
+
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    ready(
+      req
+        .extensions()
+        .get::<Auth>()
+        .cloned()
+        .ok_or(ApiError::AuthError),
+    )
+  }
+}
+
+/// [`new_api_key`] returns the key, it's only shown once.
+/// The key acts as the member `user_id`.
+pub async fn new_api_key(
+  db_pool: &MySqlPool,
+  org_id: &str,
+  user_id: &str,
+  name: &str,
+) -> Result<(ApiKeyInfo, String), ApiError> {
+  sqlx::query!(
+    "SELECT id FROM org_members WHERE org_id = ? AND user_id = ?",
+    org_id,
+    user_id
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query org_members table")?
+  .ok_or(ApiError::MemberNotFound(user_id.to_string()))?;
+
+  let id = new_nano_id();
+  let key = format!("{}{}", API_KEY_PREFIX, nanoid!(32));
+  sqlx::query!(
+    "INSERT INTO api_keys (id, org_id, user_id, name, key_hash) VALUES (?, ?, ?, ?, ?)",
+    id,
+    org_id,
+    user_id,
+    name,
+    key_hash(key.as_str())
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to insert into api_keys table")?;
+  let r = sqlx::query!("SELECT created_at FROM api_keys WHERE id = ?", id)
+    .fetch_one(db_pool)
+    .await
+    .context("Failed to query api_keys table")?;
+  let info = ApiKeyInfo {
+    id,
+    name: name.to_string(),
+    user_id: user_id.to_string(),
+    created_at: r.created_at.assume_utc(),
+  };
+  Ok((info, key))
+}
+
+/// [`list_api_keys`] returns the keys not revoked of an org,
+/// only the keys of `user_id` if it's set.
+pub async fn list_api_keys(
+  db_pool: &MySqlPool,
+  org_id: &str,
+  user_id: Option<&str>,
+) -> Result<Vec<ApiKeyInfo>, ApiError> {
+  let keys = sqlx::query!(
+    "SELECT id, name, user_id, created_at FROM api_keys WHERE org_id = ? AND (? IS NULL OR user_id = ?) AND revoked_at IS NULL ORDER BY created_at",
+    org_id,
+    user_id,
+    user_id
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query api_keys table")?;
+  Ok(
+    keys
+      .into_iter()
+      .map(|k| ApiKeyInfo {
+        id: k.id,
+        name: k.name,
+        user_id: k.user_id,
+        created_at: k.created_at.assume_utc(),
+      })
+      .collect(),
+  )
+}
+
+/// [`revoke_api_key`] only revokes a key of `user_id` if it's set.
+pub async fn revoke_api_key(
+  db_pool: &MySqlPool,
+  org_id: &str,
+  key_id: &str,
+  user_id: Option<&str>,
+) -> Result<(), ApiError> {
+  let r = sqlx::query!(
+    "UPDATE api_keys SET revoked_at = CURRENT_TIMESTAMP(3) WHERE id = ? AND org_id = ? AND (? IS NULL OR user_id = ?) AND revoked_at IS NULL",
+    key_id,
+    org_id,
+    user_id,
+    user_id
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to update api_keys table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::ApiKeyNotFound(key_id.to_string()));
+  }
+  Ok(())
+}
+
+fn key_hash(key: &str) -> String {
+  format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// [`bearer_token`] returns the token of `Authorization: Bearer <token>`.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+  req
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+fn is_internal_secret(token: &str) -> bool {
+  let secret = internal_secret().as_bytes();
+  let token = token.as_bytes();
+  // compares all bytes, so the time doesn't tell the matched prefix.
+  secret.len() == token.len()
+    && secret
+      .iter()
+      .zip(token)
+      .fold(0, |acc, (a, b)| acc | (a ^ b))
+      == 0
+}
+
+async fn authenticate(
+  db_pool: &MySqlPool,
+  req: &HttpRequest,
+) -> Result<Auth, ApiError> {
+  let token = bearer_token(req).ok_or(ApiError::AuthError)?;
+  if is_internal_secret(token) {
+    return Ok(Auth::Internal);
+  }
+  if !token.starts_with(API_KEY_PREFIX) {
+    return Err(ApiError::AuthError);
+  }
+  // a key stops working once its member is removed from the org.
+  let key = sqlx::query!(
+    "SELECT api_keys.org_id AS org_id, api_keys.user_id AS user_id, org_members.role AS role FROM api_keys INNER JOIN org_members ON org_members.org_id = api_keys.org_id AND org_members.user_id = api_keys.user_id WHERE api_keys.key_hash = ? AND api_keys.revoked_at IS NULL",
+    key_hash(token)
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query api_keys table")?
+  .ok_or(ApiError::AuthError)?;
+  Ok(Auth::Member {
+    org_id: key.org_id,
+    user_id: key.user_id,
+    role: Role::parse(key.role.as_str())?,
+  })
+}
+
+/// [`ApiKeyAuth`] authenticates control plane requests by the bearer token,
+/// an api key or the internal secret, and saves the [`Auth`] for handlers.
+/// The health check `/` is not authenticated.
+pub struct ApiKeyAuth {
+  db_pool: MySqlPool,
+}
+
+impl ApiKeyAuth {
+  pub fn new(db_pool: MySqlPool) -> Self {
+    ApiKeyAuth { db_pool }
+  }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeyAuth
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = actix_web::Error,
+    > + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = ApiKeyAuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(ApiKeyAuthMiddleware {
+      service: Rc::new(service),
+      db_pool: self.db_pool.clone(),
+    }))
+  }
+}
+
+pub struct ApiKeyAuthMiddleware<S> {
+  service: Rc<S>,
+  db_pool: MySqlPool,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeyAuthMiddleware<S>
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = actix_web::Error,
+    > + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let db_pool = self.db_pool.clone();
+    Box::pin(async move {
+      if req.path() != "/" {
+        let auth = authenticate(&db_pool, req.request()).await?;
+        req.extensions_mut().insert(auth);
+      }
+      service.call(req).await
+    })
+  }
+}
+
+/// [`InternalAuth`] only allows requests with the internal secret,
+/// it guards the data plane apis called by the control plane.
+pub struct InternalAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for InternalAuth
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = actix_web::Error,
+    > + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = InternalAuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(InternalAuthMiddleware { service }))
+  }
+}
+
+pub struct InternalAuthMiddleware<S> {
+  service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for InternalAuthMiddleware<S>
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = actix_web::Error,
+    > + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let allowed = bearer_token(req.request())
+      .map(is_internal_secret)
+      .unwrap_or(false);
+    if !allowed {
+      return Box::pin(ready(Err(ApiError::AuthError.into())));
+    }
+    Box::pin(self.service.call(req))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::test::TestRequest;
+
+  #[test]
+  fn test_bearer_token() {
+    env::set_var("DARX_INTERNAL_SECRET", "internal_secret_1");
+    let req = TestRequest::default()
+      .insert_header((AUTHORIZATION, "Bearer internal_secret_1"))
+      .to_http_request();
+    let token = bearer_token(&req).unwrap();
+    assert!(is_internal_secret(token));
+    assert!(!is_internal_secret(""));
+    assert!(!is_internal_secret("internal_secret_"));
+    assert!(!is_internal_secret("internal_secret_2"));
+
+    let req = TestRequest::default()
+      .insert_header((AUTHORIZATION, "Basic secret"))
+      .to_http_request();
+    assert_eq!(None, bearer_token(&req));
+  }
+
+  #[test]
+  fn test_validate_internal_secret() {
+    assert!(validate_internal_secret("").is_err());
+    assert!(validate_internal_secret("change-me").is_err());
+    assert!(validate_internal_secret("                    ").is_err());
+    assert!(validate_internal_secret("Zmd1cXd0bHdwc3RyZW9kY3Fv").is_ok());
+  }
+
+  #[test]
+  fn test_check_org() {
+    assert!(Auth::Internal.check_org("org1", Role::Owner).is_ok());
+    assert!(Auth::Internal.check_internal().is_ok());
+    let auth = Auth::Member {
+      org_id: "org1".to_string(),
+      user_id: "user1".to_string(),
+      role: Role::Developer,
+    };
+    assert!(auth.check_org("org1", Role::Viewer).is_ok());
+    assert!(auth.check_org("org1", Role::Developer).is_ok());
+    assert!(matches!(
+      auth.check_org("org1", Role::Owner),
+      Err(ApiError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+      auth.check_org("org2", Role::Viewer),
+      Err(ApiError::PermissionDenied(_))
+    ));
+    assert!(auth.check_internal().is_err());
+  }
+
+  #[test]
+  fn test_check_member() {
+    let auth = Auth::Member {
+      org_id: "org1".to_string(),
+      user_id: "user1".to_string(),
+      role: Role::Viewer,
+    };
+    assert!(auth.check_member("org1", "user1").is_ok());
+    assert!(auth.check_member("org1", "user2").is_err());
+    assert!(auth.check_member("org2", "user1").is_err());
+    let owner = Auth::Member {
+      org_id: "org1".to_string(),
+      user_id: "user0".to_string(),
+      role: Role::Owner,
+    };
+    assert!(owner.check_member("org1", "user1").is_ok());
+    assert!(Auth::Internal.check_member("org1", "user1").is_ok());
+  }
+}
@@ -0,0 +1,137 @@
+use crate::api::ApiError;
+use crate::api::CodeRef;
+use crate::Code;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use sqlx::{MySql, MySqlExecutor, QueryBuilder, Row};
+use std::collections::{HashMap, HashSet};
+
+/// [`content_hash`] returns the hex encoded sha256 of a code's content,
+/// codes are stored once in `code_blobs` by this hash.
+pub fn content_hash(content: &str) -> String {
+  format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// [`missing_blobs`] returns the hashes not stored yet for the org of
+/// the env, only these need to be uploaded with the codes.
+pub async fn missing_blobs<'c>(
+  exe: impl MySqlExecutor<'c>,
+  env_id: &str,
+  hashes: &[String],
+) -> Result<Vec<String>> {
+  let found = find_blobs(exe, env_id, hashes).await?;
+  let mut missing: Vec<String> = hashes
+    .iter()
+    .filter(|h| !found.contains_key(h.as_str()))
+    .cloned()
+    .collect::<HashSet<_>>()
+    .into_iter()
+    .collect();
+  missing.sort();
+  Ok(missing)
+}
+
+/// [`resolve_codes`] returns `codes` together with the codes referenced by
+/// `code_refs`, whose content is read from `code_blobs`.
+/// Only the blobs of the org of the env are referenced.
+pub async fn resolve_codes<'c>(
+  exe: impl MySqlExecutor<'c>,
+  env_id: &str,
+  codes: &[Code],
+  code_refs: &[CodeRef],
+) -> Result<Vec<Code>, ApiError> {
+  let mut final_codes = codes.to_vec();
+  if code_refs.is_empty() {
+    return Ok(final_codes);
+  }
+  let hashes: Vec<String> = code_refs.iter().map(|r| r.hash.clone()).collect();
+  let blobs = find_blobs(exe, env_id, hashes.as_slice()).await?;
+  for r in code_refs.iter() {
+    let content = blobs
+      .get(r.hash.as_str())
+      .ok_or_else(|| ApiError::BlobNotFound(r.hash.clone()))?;
+    final_codes.push(Code {
+      fs_path: r.fs_path.clone(),
+      content: content.clone(),
+    });
+  }
+  Ok(final_codes)
+}
+
+/// [`save_blob`] stores the content if it's not stored yet.
+pub(crate) async fn save_blob<'c>(
+  exe: impl MySqlExecutor<'c>,
+  content: &str,
+) -> Result<String> {
+  let hash = content_hash(content);
+  sqlx::query!(
+    "INSERT IGNORE INTO code_blobs (hash, content, content_size) VALUES (?, ?, ?)",
+    hash,
+    content,
+    content.len() as i64,
+  )
+  .execute(exe)
+  .await
+  .context("Failed to insert into code_blobs table")?;
+  Ok(hash)
+}
+
+// find_blobs finds the blobs referenced by the deploys of the org of
+// the env, blobs are shared by all orgs, but an org can't tell or use the
+// codes of the others by their hashes.
+async fn find_blobs<'c>(
+  exe: impl MySqlExecutor<'c>,
+  env_id: &str,
+  hashes: &[String],
+) -> Result<HashMap<String, String>> {
+  if hashes.is_empty() {
+    return Ok(HashMap::new());
+  }
+  let mut query = QueryBuilder::<MySql>::new(
+    "SELECT hash, content FROM code_blobs WHERE hash IN (",
+  );
+  let mut separated = query.separated(", ");
+  for h in hashes.iter() {
+    separated.push_bind(h);
+  }
+  separated.push_unseparated(
+    ") AND EXISTS (SELECT 1 FROM codes \
+    INNER JOIN deploys ON deploys.id = codes.deploy_id \
+    INNER JOIN envs ON envs.id = deploys.env_id \
+    INNER JOIN projects ON projects.id = envs.project_id \
+    WHERE codes.content_hash = code_blobs.hash AND projects.org_id = \
+    (SELECT p.org_id FROM envs e INNER JOIN projects p ON p.id = e.project_id WHERE e.id = ",
+  );
+  separated.push_bind_unseparated(env_id);
+  separated.push_unseparated("))");
+  let rows = query
+    .build()
+    .fetch_all(exe)
+    .await
+    .context("Failed to query code_blobs table")?;
+
+  let mut blobs = HashMap::new();
+  for row in rows.iter() {
+    let hash: String = row.try_get("hash")?;
+    let content: Vec<u8> = row.try_get("content")?;
+    let content = String::from_utf8(content)
+      .context("Failed to convert code content to string")?;
+    blobs.insert(hash, content);
+  }
+  Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_content_hash() {
+    assert_eq!(
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+      content_hash("")
+    );
+    assert_eq!(content_hash("foo"), content_hash("foo"));
+    assert_ne!(content_hash("foo"), content_hash("foo "));
+  }
+}
@@ -1,21 +1,49 @@
-use crate::api::{ApiError, EnvInfo, ProjectInfo};
+use crate::api::{
+  ApiError, DeployInfo, DiffDeployRsp, EnvInfo, GetDeployRsp, ProjectInfo,
+  VarDeployInfo, VarInfo,
+};
+use crate::code::blob::save_blob;
+use crate::code::diff::{diff_codes, diff_routes, diff_vars};
 use crate::code::esm_parser::parse_module_export;
-use crate::env_vars::var::{Var, VarKind};
+use crate::code::vendor::vendor_modules;
+use crate::env::list_env;
+use crate::env_vars::var::{is_valid_key, Var, VarKind};
 use crate::env_vars::var_list::VarList;
+use crate::env_vars::MASKED_VALUE;
 use crate::plugin::plugin_http_path;
 use crate::route_builder::build_route;
 use crate::{
   unique_js_export, Code, DeployId, DeploySeq, HttpRoute, REGISTRY_FILE_NAME,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use darx_isolate_runtime::{ImportMap, IMPORT_MAP_FILE, VENDOR_DIR};
 use darx_utils::new_nano_id;
 use handlebars::Handlebars;
 use serde::Serialize;
 use serde_json::json;
-use sqlx::{MySql, MySqlPool, Transaction};
+use sqlx::{MySql, MySqlExecutor, MySqlPool, Transaction};
 use std::collections::{BTreeMap, HashMap};
 use std::mem::swap;
+use std::path::Path;
+use time::PrimitiveDateTime;
 
+/// [`vendor_codes`] returns the codes with the modules their remote imports
+/// are vendored to, directly or by the import map. It fetches the modules,
+/// so it's called before the transaction of [`deploy_code`].
+pub async fn vendor_codes(codes: &[Code]) -> Result<Vec<Code>, ApiError> {
+  let import_map = match codes.iter().find(|c| c.fs_path == IMPORT_MAP_FILE) {
+    Some(code) => ImportMap::parse(code.content.as_str())
+      .map_err(|e| ApiError::InvalidImport(format!("{:#}", e)))?,
+    None => ImportMap::default(),
+  };
+  let vendored = vendor_modules(codes, &import_map)
+    .await
+    .map_err(|e| ApiError::InvalidImport(format!("{:#}", e)))?;
+  Ok(codes.iter().cloned().chain(vendored).collect())
+}
+
+/// [`deploy_code`] takes the codes returned by [`vendor_codes`] if they
+/// have remote imports.
 pub async fn deploy_code<'c>(
   txn: Transaction<'c, MySql>,
   env_id: &str,
@@ -26,13 +54,24 @@ pub async fn deploy_code<'c>(
 {
   let mut http_routes = vec![];
   for code in codes.iter() {
+    if code.fs_path == IMPORT_MAP_FILE {
+      ImportMap::parse(code.content.as_str())
+        .map_err(|e| ApiError::InvalidImport(format!("{:#}", e)))?;
+      continue;
+    }
+    // the modules of `lib` and the vendored ones are only imported,
+    // they are not routes.
     let functions_dir = "functions/";
     if !code.fs_path.starts_with(functions_dir) {
-      tracing::warn!(
-        env = env_id,
-        "code should starts with 'functions' {}",
-        code.fs_path.as_str(),
-      );
+      if !code.fs_path.starts_with("lib/")
+        && !Path::new(code.fs_path.as_str()).starts_with(VENDOR_DIR)
+      {
+        tracing::warn!(
+          env = env_id,
+          "code should starts with 'functions' or 'lib' {}",
+          code.fs_path.as_str(),
+        );
+      }
       continue;
     }
     let content = code.content.clone();
@@ -50,18 +89,7 @@ pub async fn deploy_code<'c>(
 
   let mut final_codes = vec![];
   for code in codes.iter() {
-    let code_id = new_nano_id();
-    sqlx::query!(
-            "INSERT INTO codes (id, updated_at, deploy_id, fs_path, content, content_size) VALUES (?, CURRENT_TIMESTAMP(3), ?, ?, ?, ?)",
-            code_id,
-            deploy_id,
-            code.fs_path,
-            code.content,
-            code.content.len() as i64,
-        )
-            .execute(&mut *txn)
-            .await
-            .context("Failed to insert into codes table")?;
+    insert_code(&mut txn, deploy_id.as_str(), code).await?;
     final_codes.push(Code {
       fs_path: code.fs_path.clone(),
       content: code.content.clone(),
@@ -75,21 +103,14 @@ pub async fn deploy_code<'c>(
     );
   }
 
-  let registry_code_content = registry_code(&http_routes)?;
-  let registry_code_id = new_nano_id();
-  sqlx::query!(
-        "INSERT INTO codes (id, updated_at, deploy_id, fs_path, content, content_size) VALUES (?, CURRENT_TIMESTAMP(3), ?, ?, ?, ?)",
-        registry_code_id,
-        deploy_id,
-        REGISTRY_FILE_NAME,
-        registry_code_content,
-        registry_code_content.len() as i64,
-    ).execute(&mut *txn).await.context("Failed to insert registry code")?;
-
-  final_codes.push(Code {
+  let registry_code = Code {
     fs_path: REGISTRY_FILE_NAME.to_string(),
-    content: registry_code_content,
-  });
+    content: registry_code(&http_routes)?,
+  };
+  insert_code(&mut txn, deploy_id.as_str(), &registry_code)
+    .await
+    .context("Failed to insert registry code")?;
+  final_codes.push(registry_code);
   // create new http_routes
   for route in http_routes.iter() {
     let route_id = new_nano_id();
@@ -123,12 +144,23 @@ pub async fn deploy_code<'c>(
   Ok((deploy_seq, final_codes, http_routes, txn))
 }
 
+/// [`deploy_var`] returns the plain values of all vars including secrets,
+/// they are only used by the data plane.
 pub async fn deploy_var<'c>(
   txn: Transaction<'c, MySql>,
   env_id: &str,
   vars: &HashMap<String, String>,
+  secrets: &HashMap<String, String>,
   desc: &Option<String>,
-) -> Result<(DeploySeq, HashMap<String, String>, Transaction<'c, MySql>)> {
+) -> Result<
+  (DeploySeq, HashMap<String, String>, Transaction<'c, MySql>),
+  ApiError,
+> {
+  if let Some(key) =
+    vars.keys().chain(secrets.keys()).find(|k| !is_valid_key(k))
+  {
+    return Err(ApiError::InvalidVarKey(key.to_string()));
+  }
   let (deploy_id, deploy_seq, mut txn) =
     create_deploy(txn, env_id, &None, desc).await?;
 
@@ -136,15 +168,20 @@ pub async fn deploy_var<'c>(
     .await
     .context("Failed to find env vars")?;
   let mut var_list = var_list.env_to_deploy(&deploy_id);
-  let mut map: BTreeMap<&str, &str> =
-    var_list.vars().iter().map(|e| (e.key(), e.val())).collect();
+  let mut map: BTreeMap<String, Var> = var_list
+    .vars()
+    .iter()
+    .map(|e| (e.key().to_string(), e.clone()))
+    .collect();
 
   // merge deploy_vars with env_vars
-  for var in vars {
-    map.insert(var.0, var.1);
+  for (k, v) in vars {
+    map.insert(k.clone(), Var::new(k.as_str(), v.as_str()));
   }
-  let mut final_vars: Vec<Var> =
-    map.into_iter().map(|(k, v)| Var::new(k, v)).collect();
+  for (k, v) in secrets {
+    map.insert(k.clone(), Var::new_secret(k.as_str(), v.as_str()));
+  }
+  let mut final_vars: Vec<Var> = map.into_values().collect();
   swap(var_list.mut_vars(), &mut final_vars);
 
   var_list
@@ -154,9 +191,356 @@ pub async fn deploy_var<'c>(
   Ok((deploy_seq, var_list.into_map(), txn))
 }
 
+/// [`list_var`] returns the env level vars, values of secret vars are masked.
+/// Vars passed to [`deploy_var`] only live in that deployment,
+/// see [`var_history`] for them.
+pub async fn list_var(
+  db_pool: &MySqlPool,
+  env_id: &str,
+) -> Result<Vec<VarInfo>> {
+  let records = sqlx::query!(
+    "SELECT `key`, value, is_secret, updated_at FROM env_vars WHERE env_id = ? AND is_delete = 0 ORDER BY `key`",
+    env_id
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query env_vars table")?;
+  let mut var_infos = vec![];
+  for r in records.into_iter() {
+    var_infos.push(var_info(r.key, r.value, r.is_secret != 0, r.updated_at));
+  }
+  Ok(var_infos)
+}
+
+pub async fn get_var(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  key: &str,
+) -> Result<VarInfo, ApiError> {
+  let r = sqlx::query!(
+    "SELECT `key`, value, is_secret, updated_at FROM env_vars WHERE env_id = ? AND `key` = ? AND is_delete = 0",
+    env_id,
+    key
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query env_vars table")?
+  .ok_or_else(|| ApiError::VarNotFound(key.to_string()))?;
+  Ok(var_info(r.key, r.value, r.is_secret != 0, r.updated_at))
+}
+
+/// [`set_var`] adds or replaces an env level var, the replaced one is
+/// soft deleted. Call [`deploy_var`] in the same transaction to make it
+/// effective.
+pub async fn set_var<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  var: &Var,
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  if !is_valid_key(var.key()) {
+    return Err(ApiError::InvalidVarKey(var.key().to_string()));
+  }
+  sqlx::query!(
+    "UPDATE env_vars SET is_delete = id WHERE env_id = ? AND `key` = ? AND is_delete = 0",
+    env_id,
+    var.key()
+  )
+  .execute(&mut *txn)
+  .await
+  .context("Failed to update env_vars table")?;
+
+  VarList::new_env_vars(env_id, &vec![var.clone()])
+    .save(&mut *txn)
+    .await
+    .context("Failed to save env var")?;
+  Ok(txn)
+}
+
+/// [`unset_var`] soft deletes an env level var. Call [`deploy_var`] in the
+/// same transaction to make it effective.
+pub async fn unset_var<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  key: &str,
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  let r = sqlx::query!(
+    "UPDATE env_vars SET is_delete = id WHERE env_id = ? AND `key` = ? AND is_delete = 0",
+    env_id,
+    key
+  )
+  .execute(&mut *txn)
+  .await
+  .context("Failed to update env_vars table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::VarNotFound(key.to_string()));
+  }
+  Ok(txn)
+}
+
+/// [`var_history`] returns all var deployments of an env, newest first.
+pub async fn var_history(
+  db_pool: &MySqlPool,
+  env_id: &str,
+) -> Result<Vec<VarDeployInfo>> {
+  let records = sqlx::query!(
+    "\
+    SELECT \
+        deploys.deploy_seq AS deploy_seq, \
+        deploys.description AS description, \
+        deploys.created_at AS created_at, \
+        deploy_vars.key AS `key`, \
+        deploy_vars.value AS value, \
+        deploy_vars.is_secret AS is_secret, \
+        deploy_vars.updated_at AS updated_at \
+    FROM deploy_vars INNER JOIN deploys ON deploys.id = deploy_vars.deploy_id \
+    WHERE deploys.env_id = ? \
+    ORDER BY deploys.deploy_seq DESC, deploy_vars.key",
+    env_id
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query deploy_vars table")?;
+
+  let mut deploys: Vec<VarDeployInfo> = vec![];
+  for r in records.into_iter() {
+    let var = var_info(r.key, r.value, r.is_secret != 0, r.updated_at);
+    match deploys.last_mut() {
+      Some(d) if d.deploy_seq == r.deploy_seq => d.vars.push(var),
+      _ => deploys.push(VarDeployInfo {
+        deploy_seq: r.deploy_seq,
+        desc: r.description,
+        created_at: r.created_at.assume_utc(),
+        vars: vec![var],
+      }),
+    }
+  }
+  Ok(deploys)
+}
+
+fn var_info(
+  key: String,
+  value: String,
+  is_secret: bool,
+  updated_at: PrimitiveDateTime,
+) -> VarInfo {
+  VarInfo {
+    key,
+    value: if is_secret {
+      MASKED_VALUE.to_string()
+    } else {
+      value
+    },
+    is_secret,
+    updated_at: updated_at.assume_utc(),
+  }
+}
+
+const DEFAULT_DEPLOY_PAGE_SIZE: u32 = 20;
+const MAX_DEPLOY_PAGE_SIZE: u32 = 100;
+
+/// [`list_deploy`] returns one page of code and var deploys, newest first.
+pub async fn list_deploy(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  before: Option<DeploySeq>,
+  limit: Option<u32>,
+) -> Result<(Vec<DeployInfo>, Option<DeploySeq>)> {
+  let limit = limit
+    .unwrap_or(DEFAULT_DEPLOY_PAGE_SIZE)
+    .clamp(1, MAX_DEPLOY_PAGE_SIZE);
+  // fetch one more row to tell if there is a next page.
+  let records = sqlx::query!(
+    "SELECT deploy_seq, tag, description, created_at FROM deploys WHERE env_id = ? AND deploy_seq < ? ORDER BY deploy_seq DESC LIMIT ?",
+    env_id,
+    before.unwrap_or(DeploySeq::MAX),
+    limit + 1
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query deploys table")?;
+
+  let mut deploys: Vec<DeployInfo> = records
+    .into_iter()
+    .map(|r| DeployInfo {
+      deploy_seq: r.deploy_seq,
+      tag: r.tag,
+      desc: r.description,
+      created_at: r.created_at.assume_utc(),
+    })
+    .collect();
+  let next = if deploys.len() > limit as usize {
+    deploys.truncate(limit as usize);
+    deploys.last().map(|d| d.deploy_seq)
+  } else {
+    None
+  };
+  Ok((deploys, next))
+}
+
+/// [`get_deploy`] returns the deploy with the codes and routes of the latest
+/// code deploy, and the vars of the latest var deploy, at or before
+/// `deploy_seq`. That is what served requests once the deploy took effect.
+pub async fn get_deploy(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  deploy_seq: DeploySeq,
+) -> Result<GetDeployRsp, ApiError> {
+  let (deploy, code_deploy_id, var_deploy_id) =
+    effective_deploy(db_pool, env_id, deploy_seq).await?;
+  let mut codes = vec![];
+  let mut http_routes = vec![];
+  if let Some(code_deploy_id) = code_deploy_id {
+    codes = find_codes(db_pool, code_deploy_id.as_str()).await?;
+    codes.retain(|c| !is_generated(c.fs_path.as_str()));
+    http_routes = find_routes(db_pool, code_deploy_id.as_str()).await?;
+  }
+
+  let mut vars = vec![];
+  if let Some(var_deploy_id) = var_deploy_id {
+    let records = sqlx::query!(
+      "SELECT `key`, value, is_secret, updated_at FROM deploy_vars WHERE deploy_id = ? ORDER BY `key`",
+      var_deploy_id
+    )
+    .fetch_all(db_pool)
+    .await
+    .context("Failed to query deploy_vars table")?;
+    for r in records.into_iter() {
+      vars.push(var_info(r.key, r.value, r.is_secret != 0, r.updated_at));
+    }
+  }
+
+  Ok(GetDeployRsp {
+    deploy,
+    codes,
+    http_routes,
+    vars,
+  })
+}
+
+/// [`diff_deploy`] compares what served requests after deploy `from` and
+/// after deploy `to`, see [`get_deploy`].
+pub async fn diff_deploy(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  from: DeploySeq,
+  to: DeploySeq,
+) -> Result<DiffDeployRsp, ApiError> {
+  let (from_codes, from_routes, from_vars) =
+    deploy_state(db_pool, env_id, from).await?;
+  let (to_codes, to_routes, to_vars) =
+    deploy_state(db_pool, env_id, to).await?;
+  Ok(DiffDeployRsp {
+    codes: diff_codes(&from_codes, &to_codes),
+    http_routes: diff_routes(&from_routes, &to_routes),
+    vars: diff_vars(&from_vars, &to_vars),
+  })
+}
+
+/// [`deploy_state`] returns the codes, routes and plain vars that served
+/// requests once the deploy took effect.
+async fn deploy_state(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  deploy_seq: DeploySeq,
+) -> Result<(Vec<Code>, Vec<HttpRoute>, Vec<Var>), ApiError> {
+  let (_, code_deploy_id, var_deploy_id) =
+    effective_deploy(db_pool, env_id, deploy_seq).await?;
+  let mut codes = vec![];
+  let mut http_routes = vec![];
+  if let Some(code_deploy_id) = code_deploy_id {
+    codes = find_codes(db_pool, code_deploy_id.as_str()).await?;
+    codes.retain(|c| !is_generated(c.fs_path.as_str()));
+    http_routes = find_routes(db_pool, code_deploy_id.as_str()).await?;
+  }
+  let mut vars = vec![];
+  if let Some(var_deploy_id) = var_deploy_id {
+    vars = VarList::find(db_pool, var_deploy_id.as_str(), VarKind::Deploy)
+      .await
+      .context("Failed to find deploy vars")?
+      .vars()
+      .clone();
+  }
+  Ok((codes, http_routes, vars))
+}
+
+/// [`effective_deploy`] returns the deploy and ids of the latest code deploy
+/// and var deploy at or before `deploy_seq`.
+async fn effective_deploy(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  deploy_seq: DeploySeq,
+) -> Result<(DeployInfo, Option<DeployId>, Option<DeployId>), ApiError> {
+  let deploy = sqlx::query!(
+    "SELECT deploy_seq, tag, description, created_at FROM deploys WHERE env_id = ? AND deploy_seq = ?",
+    env_id,
+    deploy_seq
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query deploys table")?
+  .ok_or(ApiError::DeployNotFound(format!(
+    "env_id: {}, deploy_seq: {}",
+    env_id, deploy_seq
+  )))?;
+  let deploy = DeployInfo {
+    deploy_seq: deploy.deploy_seq,
+    tag: deploy.tag,
+    desc: deploy.description,
+    created_at: deploy.created_at.assume_utc(),
+  };
+
+  // every code deploy has a registry file, var deploys have no codes.
+  let code_deploy = sqlx::query!(
+    "SELECT deploys.id AS id FROM deploys INNER JOIN codes ON codes.deploy_id = deploys.id WHERE deploys.env_id = ? AND deploys.deploy_seq <= ? AND codes.fs_path = ? ORDER BY deploys.deploy_seq DESC LIMIT 1",
+    env_id,
+    deploy_seq,
+    REGISTRY_FILE_NAME,
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query deploys table")?;
+
+  let var_deploy = sqlx::query!(
+    "SELECT deploys.id AS id FROM deploys LEFT JOIN codes ON codes.deploy_id = deploys.id AND codes.fs_path = ? WHERE deploys.env_id = ? AND deploys.deploy_seq <= ? AND codes.id IS NULL ORDER BY deploys.deploy_seq DESC LIMIT 1",
+    REGISTRY_FILE_NAME,
+    env_id,
+    deploy_seq,
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query deploys table")?;
+  Ok((deploy, code_deploy.map(|d| d.id), var_deploy.map(|d| d.id)))
+}
+
+/// [`latest_code_deploy`] returns the id of the code deploy in effect,
+/// the latest deploy may be a var deploy without codes.
+async fn latest_code_deploy(
+  db_pool: &MySqlPool,
+  env_id: &str,
+) -> Result<Option<DeployId>, ApiError> {
+  let latest = sqlx::query!(
+    "SELECT deploy_seq FROM deploys WHERE env_id = ? ORDER BY deploy_seq DESC LIMIT 1",
+    env_id
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query deploys table")?;
+  match latest {
+    Some(latest) => {
+      let (_, code_deploy_id, _) =
+        effective_deploy(db_pool, env_id, latest.deploy_seq).await?;
+      Ok(code_deploy_id)
+    }
+    None => Ok(None),
+  }
+}
+
+/// [`load_env`] returns the latest code of the env named `env_name`,
+/// or the project's first env if `env_name` is not set.
 pub async fn load_env(
   db_pool: &MySqlPool,
   proj_id: &str,
+  env_name: Option<&str>,
 ) -> Result<(Vec<Code>, Vec<HttpRoute>, ProjectInfo, EnvInfo)> {
   let project =
     sqlx::query!("SELECT id, name FROM projects WHERE id = ?", proj_id)
@@ -165,67 +549,129 @@ pub async fn load_env(
       .context("Failed to query projects table")?
       .ok_or(ApiError::ProjectNotFound(proj_id.to_string()))?;
 
-  // todo: only fetch the first environment right now.
-  let env =
-    sqlx::query!("SELECT id, name FROM envs WHERE project_id = ?", project.id)
-      .fetch_optional(db_pool)
-      .await
-      .context("Failed to query envs table")?
-      .ok_or(ApiError::EnvNotFound(format!("project_id: {}", proj_id)))?;
+  let envs = list_env(db_pool, project.id.as_str()).await?;
+  let env = match env_name {
+    Some(name) => envs.into_iter().find(|e| e.name == name),
+    None => envs.into_iter().next(),
+  }
+  .ok_or(ApiError::EnvNotFound(format!(
+    "project_id: {}, env: {}",
+    proj_id,
+    env_name.unwrap_or_default()
+  )))?;
 
   let env_id = env.id.clone();
   let proj_info = ProjectInfo {
     id: project.id,
     name: project.name,
   };
-  let env_info = EnvInfo {
-    id: env_id.clone(),
-    name: env.name,
-  };
+  let env_info = env;
 
-  let deploy_id = sqlx::query!(
-    "SELECT id FROM deploys WHERE env_id = ? ORDER BY deploy_seq DESC LIMIT 1",
-    env_id
-  )
-  .fetch_optional(db_pool)
-  .await
-  .context("Failed to query deploys table")?;
   let mut codes = vec![];
   let mut http_routes = vec![];
-  if let Some(deploy_id) = deploy_id {
-    let records = sqlx::query!("
-        SELECT id, fs_path, content FROM codes WHERE deploy_id = ? AND fs_path != '__registry.js'",
-            deploy_id.id).fetch_all(db_pool).await.context("Failed to query codes table")?;
-    for r in records.iter() {
-      codes.push(Code {
-        fs_path: r.fs_path.clone(),
-        content: String::from_utf8(r.content.clone()).map_err(|e| {
-          ApiError::Internal(anyhow!(
-            "Failed to convert code content to string: {}",
-            e
-          ))
-        })?,
-      });
-    }
-    let routes = sqlx::query!("\
-        SELECT http_path, method, js_entry_point, js_export, func_sig_version, func_sig FROM http_routes WHERE deploy_id = ?",
-            deploy_id.id).fetch_all(db_pool).await.context("Failed to query http_routes table")?;
-    for r in routes.iter() {
-      http_routes.push(HttpRoute {
-        http_path: r.http_path.clone(),
-        method: r.method.clone(),
-        js_entry_point: r.js_entry_point.clone(),
-        js_export: r.js_export.clone(),
-        func_sig_version: r.func_sig_version,
-        func_sig: serde_json::from_value(r.func_sig.clone()).map_err(|e| {
-          ApiError::Internal(anyhow!("Failed to parse func_sig: {}", e))
-        })?,
-      });
-    }
+  if let Some(deploy_id) = latest_code_deploy(db_pool, env_id.as_str()).await? {
+    codes = find_codes(db_pool, deploy_id.as_str()).await?;
+    codes.retain(|c| !is_generated(c.fs_path.as_str()));
+    http_routes = find_routes(db_pool, deploy_id.as_str()).await?;
   }
   Ok((codes, http_routes, proj_info, env_info))
 }
 
+/// [`promote_deploy`] copies the codes and routes of a code deploy in
+/// `from_env_id` to a new deploy of `to_env_id`, both envs should belong to
+/// the same project. Vars are not copied, each env keeps its own vars.
+pub async fn promote_deploy<'c>(
+  mut txn: Transaction<'c, MySql>,
+  from_env_id: &str,
+  to_env_id: &str,
+  deploy_seq: Option<DeploySeq>,
+  desc: &Option<String>,
+) -> Result<
+  (DeploySeq, Vec<Code>, Vec<HttpRoute>, Transaction<'c, MySql>),
+  ApiError,
+> {
+  let from_env =
+    sqlx::query!("SELECT project_id FROM envs WHERE id = ?", from_env_id)
+      .fetch_optional(&mut *txn)
+      .await
+      .context("Failed to query envs table")?
+      .ok_or(ApiError::EnvNotFound(from_env_id.to_string()))?;
+  let to_env =
+    sqlx::query!("SELECT project_id FROM envs WHERE id = ?", to_env_id)
+      .fetch_optional(&mut *txn)
+      .await
+      .context("Failed to query envs table")?
+      .ok_or(ApiError::EnvNotFound(to_env_id.to_string()))?;
+  if from_env.project_id != to_env.project_id {
+    return Err(ApiError::PermissionDenied(format!(
+      "promoting to env {} of another project is not allowed",
+      to_env_id
+    )));
+  }
+
+  // every code deploy has a registry file, var deploys have no codes.
+  let from_deploy = match deploy_seq {
+    Some(deploy_seq) => sqlx::query!(
+      "SELECT deploys.id AS id, deploys.tag AS tag FROM deploys INNER JOIN codes ON codes.deploy_id = deploys.id WHERE deploys.env_id = ? AND deploys.deploy_seq = ? AND codes.fs_path = ?",
+      from_env_id,
+      deploy_seq,
+      REGISTRY_FILE_NAME,
+    )
+    .fetch_optional(&mut *txn)
+    .await
+    .context("Failed to query deploys table")?
+    .map(|d| (d.id, d.tag)),
+    None => sqlx::query!(
+      "SELECT deploys.id AS id, deploys.tag AS tag FROM deploys INNER JOIN codes ON codes.deploy_id = deploys.id WHERE deploys.env_id = ? AND codes.fs_path = ? ORDER BY deploys.deploy_seq DESC LIMIT 1",
+      from_env_id,
+      REGISTRY_FILE_NAME,
+    )
+    .fetch_optional(&mut *txn)
+    .await
+    .context("Failed to query deploys table")?
+    .map(|d| (d.id, d.tag)),
+  };
+  let (from_deploy_id, tag) = from_deploy.ok_or(ApiError::DeployNotFound(
+    format!("env_id: {}, deploy_seq: {:?}", from_env_id, deploy_seq),
+  ))?;
+
+  let codes = find_codes(&mut *txn, from_deploy_id.as_str()).await?;
+  let http_routes = find_routes(&mut *txn, from_deploy_id.as_str()).await?;
+
+  let (deploy_id, deploy_seq, mut txn) =
+    create_deploy(txn, to_env_id, &tag, desc).await?;
+  // the content is already stored, only the reference is copied.
+  for code in codes.iter() {
+    insert_code(&mut txn, deploy_id.as_str(), code).await?;
+  }
+  for route in http_routes.iter() {
+    sqlx::query!(
+      "INSERT INTO http_routes (id, updated_at, method, js_entry_point, js_export, deploy_id, http_path, func_sig_version, func_sig) VALUES (?, CURRENT_TIMESTAMP(3), ?, ?, ?, ?, ?, ?, ?)",
+      new_nano_id(),
+      route.method,
+      route.js_entry_point,
+      route.js_export,
+      deploy_id,
+      route.http_path,
+      route.func_sig_version,
+      serde_json::to_string(&route.func_sig).context("Failed to serialize func_sig")?,
+    )
+    .execute(&mut *txn)
+    .await
+    .context("Failed to insert into http_routes table")?;
+  }
+
+  tracing::info!(
+    env = to_env_id,
+    seq = deploy_seq,
+    "promoted deployment from env {}, {} codes, {} routes",
+    from_env_id,
+    codes.len(),
+    http_routes.len()
+  );
+  Ok((deploy_seq, codes, http_routes, txn))
+}
+
 pub async fn deploy_plugin<'c>(
   mut txn: Transaction<'c, MySql>,
   env_id: &str,
@@ -325,30 +771,84 @@ async fn api_for_env(
   db_pool: &MySqlPool,
   env_id: &str,
 ) -> Result<Vec<HttpRoute>> {
-  let mut http_routes = vec![];
-  let deploy_id = sqlx::query!(
-    "SELECT id FROM deploys WHERE env_id = ? ORDER BY deploy_seq DESC LIMIT 1",
-    env_id
+  match latest_code_deploy(db_pool, env_id).await? {
+    Some(deploy_id) => find_routes(db_pool, deploy_id.as_str()).await,
+    None => Ok(vec![]),
+  }
+}
+
+/// [`is_generated`] returns whether the code is generated by the deploy,
+/// the registry and the vendored modules.
+fn is_generated(fs_path: &str) -> bool {
+  fs_path == REGISTRY_FILE_NAME || Path::new(fs_path).starts_with(VENDOR_DIR)
+}
+
+/// [`insert_code`] stores the content in `code_blobs` if it's new,
+/// and references it from the deploy.
+async fn insert_code(
+  txn: &mut Transaction<'_, MySql>,
+  deploy_id: &str,
+  code: &Code,
+) -> Result<()> {
+  let hash = save_blob(&mut **txn, code.content.as_str()).await?;
+  sqlx::query!(
+    "INSERT INTO codes (id, updated_at, deploy_id, fs_path, content_hash, content_size) VALUES (?, CURRENT_TIMESTAMP(3), ?, ?, ?, ?)",
+    new_nano_id(),
+    deploy_id,
+    code.fs_path,
+    hash,
+    code.content.len() as i64,
   )
-  .fetch_optional(db_pool)
+  .execute(&mut **txn)
   .await
-  .context("Failed to query deploys table")?;
-  if let Some(deploy_id) = deploy_id {
-    let routes = sqlx::query!("\
-        SELECT http_path, method, js_entry_point, js_export, func_sig_version, func_sig FROM http_routes WHERE deploy_id = ?",
-            deploy_id.id).fetch_all(db_pool).await.context("Failed to query http_routes table")?;
-    for r in routes.iter() {
-      http_routes.push(HttpRoute {
-        http_path: r.http_path.clone(),
-        method: r.method.clone(),
-        js_entry_point: r.js_entry_point.clone(),
-        js_export: r.js_export.clone(),
-        func_sig_version: r.func_sig_version,
-        func_sig: serde_json::from_value(r.func_sig.clone()).map_err(|e| {
-          ApiError::Internal(anyhow!("Failed to parse func_sig: {}", e))
-        })?,
-      });
-    }
+  .context("Failed to insert into codes table")?;
+  Ok(())
+}
+
+async fn find_codes<'c>(
+  exe: impl MySqlExecutor<'c>,
+  deploy_id: &str,
+) -> Result<Vec<Code>> {
+  let records = sqlx::query!(
+    "SELECT codes.fs_path AS fs_path, code_blobs.content AS content FROM codes INNER JOIN code_blobs ON code_blobs.hash = codes.content_hash WHERE codes.deploy_id = ?",
+    deploy_id
+  )
+  .fetch_all(exe)
+  .await
+  .context("Failed to query codes table")?;
+  let mut codes = vec![];
+  for r in records.into_iter() {
+    codes.push(Code {
+      fs_path: r.fs_path,
+      content: String::from_utf8(r.content)
+        .context("Failed to convert code content to string")?,
+    });
+  }
+  Ok(codes)
+}
+
+async fn find_routes<'c>(
+  exe: impl MySqlExecutor<'c>,
+  deploy_id: &str,
+) -> Result<Vec<HttpRoute>> {
+  let records = sqlx::query!(
+    "SELECT http_path, method, js_entry_point, js_export, func_sig_version, func_sig FROM http_routes WHERE deploy_id = ?",
+    deploy_id
+  )
+  .fetch_all(exe)
+  .await
+  .context("Failed to query http_routes table")?;
+  let mut http_routes = vec![];
+  for r in records.into_iter() {
+    http_routes.push(HttpRoute {
+      http_path: r.http_path,
+      method: r.method,
+      js_entry_point: r.js_entry_point,
+      js_export: r.js_export,
+      func_sig_version: r.func_sig_version,
+      func_sig: serde_json::from_value(r.func_sig)
+        .context("Failed to parse func_sig")?,
+    });
   }
   Ok(http_routes)
 }
@@ -404,6 +904,7 @@ mod tests {
         func_sig: FunctionSignatureV1 {
           export_name: "default".to_string(),
           param_names: vec![],
+          auth_required: false,
         },
       },
       HttpRoute {
@@ -415,6 +916,7 @@ mod tests {
         func_sig: FunctionSignatureV1 {
           export_name: "foo".to_string(),
           param_names: vec![],
+          auth_required: false,
         },
       },
     ];
@@ -0,0 +1,203 @@
+use crate::api::{CodeDiff, DiffKind, RouteDiff, VarDiff};
+use crate::env_vars::{Var, MASKED_VALUE};
+use crate::{Code, HttpRoute};
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// [`diff_codes`] compares codes by `fs_path`, unchanged codes are skipped.
+pub fn diff_codes(from: &[Code], to: &[Code]) -> Vec<CodeDiff> {
+  let from: BTreeMap<_, _> = from
+    .iter()
+    .map(|c| (c.fs_path.as_str(), c.content.as_str()))
+    .collect();
+  let to: BTreeMap<_, _> = to
+    .iter()
+    .map(|c| (c.fs_path.as_str(), c.content.as_str()))
+    .collect();
+
+  let mut diffs = vec![];
+  for path in union_keys(&from, &to) {
+    let (kind, old, new) = match (from.get(path), to.get(path)) {
+      (Some(old), Some(new)) if old == new => continue,
+      (Some(old), Some(new)) => (DiffKind::Changed, *old, *new),
+      (Some(old), None) => (DiffKind::Removed, *old, ""),
+      (None, Some(new)) => (DiffKind::Added, "", *new),
+      (None, None) => unreachable!(),
+    };
+    let diff = TextDiff::from_lines(old, new)
+      .unified_diff()
+      .header(path, path)
+      .to_string();
+    diffs.push(CodeDiff {
+      fs_path: path.to_string(),
+      kind,
+      diff,
+    });
+  }
+  diffs
+}
+
+/// [`diff_routes`] compares routes by `http_path`, a route is changed if its
+/// entry point, export or signature is changed.
+pub fn diff_routes(from: &[HttpRoute], to: &[HttpRoute]) -> Vec<RouteDiff> {
+  let from: BTreeMap<_, _> =
+    from.iter().map(|r| (r.http_path.as_str(), r)).collect();
+  let to: BTreeMap<_, _> =
+    to.iter().map(|r| (r.http_path.as_str(), r)).collect();
+
+  let mut diffs = vec![];
+  for path in union_keys(&from, &to) {
+    let old = from.get(path).cloned();
+    let new = to.get(path).cloned();
+    let kind = match (old, new) {
+      (Some(old), Some(new)) if old == new => continue,
+      (Some(_), Some(_)) => DiffKind::Changed,
+      (Some(_), None) => DiffKind::Removed,
+      (None, Some(_)) => DiffKind::Added,
+      (None, None) => unreachable!(),
+    };
+    diffs.push(RouteDiff {
+      http_path: path.to_string(),
+      kind,
+      from: old.cloned(),
+      to: new.cloned(),
+    });
+  }
+  diffs
+}
+
+/// [`diff_vars`] compares the plain values of vars by key,
+/// the values of secret vars are masked in the result.
+pub fn diff_vars(from: &[Var], to: &[Var]) -> Vec<VarDiff> {
+  let from: BTreeMap<_, _> = from.iter().map(|v| (v.key(), v)).collect();
+  let to: BTreeMap<_, _> = to.iter().map(|v| (v.key(), v)).collect();
+
+  let mut diffs = vec![];
+  for key in union_keys(&from, &to) {
+    let old = from.get(key);
+    let new = to.get(key);
+    let kind = match (old, new) {
+      (Some(old), Some(new)) if old == new => continue,
+      (Some(_), Some(_)) => DiffKind::Changed,
+      (Some(_), None) => DiffKind::Removed,
+      (None, Some(_)) => DiffKind::Added,
+      (None, None) => unreachable!(),
+    };
+    diffs.push(VarDiff {
+      key: key.to_string(),
+      kind,
+      from: old.map(|v| masked(v)),
+      to: new.map(|v| masked(v)),
+    });
+  }
+  diffs
+}
+
+fn masked(var: &Var) -> String {
+  if var.is_secret() {
+    MASKED_VALUE.to_string()
+  } else {
+    var.val().to_string()
+  }
+}
+
+fn union_keys<'a, V>(
+  a: &BTreeMap<&'a str, V>,
+  b: &BTreeMap<&'a str, V>,
+) -> BTreeSet<&'a str> {
+  a.keys().chain(b.keys()).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::FunctionSignatureV1;
+
+  fn code(fs_path: &str, content: &str) -> Code {
+    Code {
+      fs_path: fs_path.to_string(),
+      content: content.to_string(),
+    }
+  }
+
+  fn route(http_path: &str, param_names: &[&str]) -> HttpRoute {
+    HttpRoute {
+      http_path: http_path.to_string(),
+      method: "POST".to_string(),
+      js_entry_point: format!("functions/{}.js", http_path),
+      js_export: "default".to_string(),
+      func_sig_version: 1,
+      func_sig: FunctionSignatureV1 {
+        export_name: "default".to_string(),
+        param_names: param_names.iter().map(|p| p.to_string()).collect(),
+        auth_required: false,
+      },
+    }
+  }
+
+  #[test]
+  fn test_diff_codes() {
+    let from = vec![
+      code("functions/a.js", "a\n"),
+      code("functions/b.js", "b\n"),
+      code("functions/c.js", "c\n"),
+    ];
+    let to = vec![
+      code("functions/a.js", "a\n"),
+      code("functions/b.js", "b2\n"),
+      code("functions/d.js", "d\n"),
+    ];
+    let diffs = diff_codes(&from, &to);
+    let kinds: Vec<_> =
+      diffs.iter().map(|d| (d.fs_path.as_str(), d.kind)).collect();
+    assert_eq!(
+      vec![
+        ("functions/b.js", DiffKind::Changed),
+        ("functions/c.js", DiffKind::Removed),
+        ("functions/d.js", DiffKind::Added),
+      ],
+      kinds
+    );
+    assert_eq!(
+      "--- functions/b.js\n+++ functions/b.js\n@@ -1 +1 @@\n-b\n+b2\n",
+      diffs[0].diff
+    );
+  }
+
+  #[test]
+  fn test_diff_routes() {
+    let from = vec![route("a", &["x"]), route("b", &[])];
+    let to = vec![route("a", &["x", "y"]), route("c", &[])];
+    let diffs = diff_routes(&from, &to);
+    let kinds: Vec<_> = diffs
+      .iter()
+      .map(|d| (d.http_path.as_str(), d.kind))
+      .collect();
+    assert_eq!(
+      vec![
+        ("a", DiffKind::Changed),
+        ("b", DiffKind::Removed),
+        ("c", DiffKind::Added),
+      ],
+      kinds
+    );
+    assert_eq!(
+      vec!["x", "y"],
+      diffs[0].to.as_ref().unwrap().func_sig.param_names
+    );
+  }
+
+  #[test]
+  fn test_diff_vars() {
+    let from = vec![Var::new("k1", "v1"), Var::new_secret("s1", "p1")];
+    let to = vec![Var::new("k1", "v2"), Var::new_secret("s1", "p2")];
+    let diffs = diff_vars(&from, &to);
+    assert_eq!(2, diffs.len());
+    assert_eq!(Some("v1".to_string()), diffs[0].from);
+    assert_eq!(Some("v2".to_string()), diffs[0].to);
+    assert_eq!(Some(MASKED_VALUE.to_string()), diffs[1].from);
+    assert_eq!(Some(MASKED_VALUE.to_string()), diffs[1].to);
+
+    assert!(diff_vars(&to, &to).is_empty());
+  }
+}
@@ -5,38 +5,25 @@ use std::sync::{Arc, Mutex};
 use swc_common::errors::Handler;
 use swc_common::sync::Lrc;
 use swc_common::{FileName, SourceMap};
-use swc_ecma_ast::{Decl, DefaultDecl, Function, ModuleDecl, ModuleItem, Pat};
+use swc_ecma_ast::{
+  Decl, DefaultDecl, Expr, Function, Lit, Module, ModuleDecl, ModuleItem, Pat,
+  VarDecl,
+};
 use swc_ecma_parser::parse_file_as_module;
 
+/// A module exporting `requireAuth = true` requires end-user auth
+/// for all its functions.
+const REQUIRE_AUTH_EXPORT: &str = "requireAuth";
+
 // todo: handle Javascript syntax error
 pub(crate) fn parse_module_export(
   file_name: &str,
   source: &str,
 ) -> Result<Vec<FunctionSignatureV1>> {
-  let cm: Lrc<SourceMap> = Default::default();
-
-  let fm = cm.new_source_file(
-    FileName::Custom(file_name.to_string()),
-    source.to_string(),
-  );
-  let writer = Box::<LockedWriter>::default();
-  let handler = Handler::with_emitter_writer(writer.clone(), Some(cm.clone()));
-
-  let module = parse_file_as_module(
-    &fm,
-    Default::default(),
-    Default::default(),
-    None,
-    &mut vec![],
-  )
-  .map_err(|err| {
-    err.into_diagnostic(&handler).emit();
-    let s = writer.0.lock().unwrap();
-    let s = String::from_utf8_lossy(&s);
-    anyhow!(s.to_string())
-  })?;
+  let module = parse(file_name, source)?;
 
   let mut sigs = vec![];
+  let mut auth_required = false;
 
   for item in module.body.iter() {
     match item {
@@ -47,8 +34,12 @@ pub(crate) fn parse_module_export(
             sigs.push(FunctionSignatureV1 {
               export_name: fn_decl.ident.sym.to_string(),
               param_names: params,
+              auth_required: false,
             });
           }
+          Decl::Var(var_decl) => {
+            auth_required |= exports_require_auth(var_decl);
+          }
           _ => {}
         },
         ModuleDecl::ExportDefaultDecl(export_default_decl) => {
@@ -58,6 +49,7 @@ pub(crate) fn parse_module_export(
               sigs.push(FunctionSignatureV1 {
                 export_name: "default".to_string(),
                 param_names: params,
+                auth_required: false,
               });
             }
             _ => {}
@@ -68,9 +60,79 @@ pub(crate) fn parse_module_export(
       _ => {}
     }
   }
+  for sig in sigs.iter_mut() {
+    sig.auth_required = auth_required;
+  }
   Ok(sigs)
 }
 
+/// [`parse_module_imports`] returns the specifiers of the static imports and
+/// re-exports of a module, type-only imports excluded.
+pub(crate) fn parse_module_imports(
+  file_name: &str,
+  source: &str,
+) -> Result<Vec<String>> {
+  let module = parse(file_name, source)?;
+  let mut imports = vec![];
+  for item in module.body.iter() {
+    let src = match item {
+      ModuleItem::ModuleDecl(ModuleDecl::Import(import)) => {
+        (!import.type_only).then_some(&import.src)
+      }
+      ModuleItem::ModuleDecl(ModuleDecl::ExportAll(export)) => {
+        (!export.type_only).then_some(&export.src)
+      }
+      ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(export)) => {
+        export.src.as_ref().filter(|_| !export.type_only)
+      }
+      _ => None,
+    };
+    if let Some(src) = src {
+      imports.push(src.value.to_string());
+    }
+  }
+  Ok(imports)
+}
+
+fn parse(file_name: &str, source: &str) -> Result<Module> {
+  let cm: Lrc<SourceMap> = Default::default();
+
+  let fm = cm.new_source_file(
+    FileName::Custom(file_name.to_string()),
+    source.to_string(),
+  );
+  let writer = Box::<LockedWriter>::default();
+  let handler = Handler::with_emitter_writer(writer.clone(), Some(cm.clone()));
+
+  parse_file_as_module(
+    &fm,
+    Default::default(),
+    Default::default(),
+    None,
+    &mut vec![],
+  )
+  .map_err(|err| {
+    err.into_diagnostic(&handler).emit();
+    let s = writer.0.lock().unwrap();
+    let s = String::from_utf8_lossy(&s);
+    anyhow!(s.to_string())
+  })
+}
+
+fn exports_require_auth(var_decl: &VarDecl) -> bool {
+  var_decl.decls.iter().any(|d| {
+    let is_require_auth = match &d.name {
+      Pat::Ident(ident) => &*ident.sym == REQUIRE_AUTH_EXPORT,
+      _ => false,
+    };
+    let is_true = matches!(
+      d.init.as_deref(),
+      Some(Expr::Lit(Lit::Bool(b))) if b.value
+    );
+    is_require_auth && is_true
+  })
+}
+
 #[derive(Clone, Default)]
 struct LockedWriter(Arc<Mutex<Vec<u8>>>);
 
@@ -128,5 +190,49 @@ mod tests {
     assert_eq!(sigs[1].param_names, ["c", "d"]);
     assert_eq!(sigs[2].export_name, "default");
     assert_eq!(sigs[2].param_names, ["e", "f"]);
+    assert!(sigs.iter().all(|s| !s.auth_required));
+  }
+
+  #[test]
+  fn test_parse_require_auth() {
+    let source = r#"
+        export const requireAuth = true;
+        export function me() {
+            return Darx.auth.sub;
+        }
+        "#;
+    let sigs = parse_module_export("test.js", source).unwrap();
+    assert_eq!(sigs.len(), 1);
+    assert!(sigs[0].auth_required);
+
+    let source = r#"
+        export const requireAuth = false;
+        export function me() {
+            return null;
+        }
+        "#;
+    let sigs = parse_module_export("test.js", source).unwrap();
+    assert!(!sigs[0].auth_required);
+  }
+
+  #[test]
+  fn test_parse_module_imports() {
+    let source = r#"
+        import { nanoid } from "npm:nanoid@4";
+        import "./setup.js";
+        export * from "https://deno.land/std@0.190.0/uuid/mod.ts";
+        export { a } from "./a.js";
+        const b = await import("./b.js");
+        "#;
+    let imports = parse_module_imports("test.ts", source).unwrap();
+    assert_eq!(
+      imports,
+      [
+        "npm:nanoid@4",
+        "./setup.js",
+        "https://deno.land/std@0.190.0/uuid/mod.ts",
+        "./a.js"
+      ]
+    );
   }
 }
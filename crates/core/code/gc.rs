@@ -0,0 +1,264 @@
+use crate::api::{ApiError, DeployInfo};
+use crate::{DeploySeq, REGISTRY_FILE_NAME};
+use anyhow::{Context, Result};
+use sqlx::{MySql, MySqlPool, Transaction};
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::ops::DerefMut;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// [`gc_interval`] reads how often the gc runs from `DARX_GC_INTERVAL_SECS`,
+/// defaults to an hour, `0` disables it.
+pub fn gc_interval() -> Option<Duration> {
+  let secs = env::var("DARX_GC_INTERVAL_SECS")
+    .map(|s| {
+      s.parse::<u64>()
+        .expect("Failed to parse DARX_GC_INTERVAL_SECS")
+    })
+    .unwrap_or(3600);
+  if secs == 0 {
+    None
+  } else {
+    Some(Duration::from_secs(secs))
+  }
+}
+
+/// [`set_retention`] sets the number of latest deploys the gc keeps.
+pub async fn set_retention(
+  db_pool: &MySqlPool,
+  env_id: &str,
+  keep_deploys: u32,
+) -> Result<(), ApiError> {
+  if keep_deploys == 0 {
+    return Err(ApiError::InvalidRetention(
+      "keep_deploys should be at least 1".to_string(),
+    ));
+  }
+  let r = sqlx::query!(
+    "UPDATE envs SET keep_deploys = ? WHERE id = ?",
+    keep_deploys,
+    env_id
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to update envs table")?;
+  if r.rows_affected() == 0 {
+    // the value may be unchanged.
+    sqlx::query!("SELECT id FROM envs WHERE id = ?", env_id)
+      .fetch_optional(db_pool)
+      .await
+      .context("Failed to query envs table")?
+      .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+  }
+  Ok(())
+}
+
+/// [`gc_deploys`] deletes the deploys of an env outside its retention,
+/// together with their codes, routes, vars and the blobs no longer used.
+/// Nothing is deleted if `dry_run`.
+pub async fn gc_deploys<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  dry_run: bool,
+) -> Result<(Vec<DeployInfo>, Transaction<'c, MySql>), ApiError> {
+  let env = sqlx::query!(
+    "SELECT keep_deploys FROM envs WHERE id = ? FOR UPDATE",
+    env_id
+  )
+  .fetch_optional(txn.deref_mut())
+  .await
+  .context("Failed to query envs table")?
+  .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+
+  let records = sqlx::query!(
+    "SELECT id, deploy_seq, tag, description, created_at FROM deploys WHERE env_id = ? ORDER BY deploy_seq DESC",
+    env_id
+  )
+  .fetch_all(txn.deref_mut())
+  .await
+  .context("Failed to query deploys table")?;
+
+  let code_deploys: HashSet<DeploySeq> = sqlx::query!(
+    "SELECT deploys.deploy_seq AS deploy_seq FROM deploys INNER JOIN codes ON codes.deploy_id = deploys.id WHERE deploys.env_id = ? AND codes.fs_path = ?",
+    env_id,
+    REGISTRY_FILE_NAME
+  )
+  .fetch_all(txn.deref_mut())
+  .await
+  .context("Failed to query deploys table")?
+  .into_iter()
+  .map(|r| r.deploy_seq)
+  .collect();
+
+  let deploys: Vec<RetentionEntry> = records
+    .iter()
+    .map(|r| RetentionEntry {
+      deploy_seq: r.deploy_seq,
+      tagged: r.tag.is_some(),
+      is_code: code_deploys.contains(&r.deploy_seq),
+    })
+    .collect();
+  let retained = retained_deploys(&deploys, env.keep_deploys as usize);
+
+  let mut deleted = vec![];
+  let mut deleted_hashes = HashSet::new();
+  for r in records.into_iter() {
+    if retained.contains(&r.deploy_seq) {
+      continue;
+    }
+    if !dry_run {
+      let hashes = sqlx::query!(
+        "SELECT content_hash FROM codes WHERE deploy_id = ?",
+        r.id
+      )
+      .fetch_all(txn.deref_mut())
+      .await
+      .context("Failed to query codes table")?;
+      deleted_hashes.extend(hashes.into_iter().map(|h| h.content_hash));
+      txn = delete_deploy(txn, r.id.as_str()).await?;
+    }
+    deleted.push(DeployInfo {
+      deploy_seq: r.deploy_seq,
+      tag: r.tag,
+      desc: r.description,
+      created_at: r.created_at.assume_utc(),
+    });
+  }
+
+  // blobs are shared by deploys of all envs,
+  // only the ones without any reference left are deleted.
+  for hash in deleted_hashes.iter() {
+    sqlx::query!(
+      "DELETE FROM code_blobs WHERE hash = ? AND NOT EXISTS (SELECT 1 FROM codes WHERE content_hash = ?)",
+      hash,
+      hash
+    )
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from code_blobs table")?;
+  }
+  Ok((deleted, txn))
+}
+
+/// [`gc_all_envs`] runs [`gc_deploys`] for every env,
+/// an env failed to gc is logged and skipped.
+pub async fn gc_all_envs(db_pool: &MySqlPool) -> Result<usize> {
+  let envs = sqlx::query!("SELECT id FROM envs")
+    .fetch_all(db_pool)
+    .await
+    .context("Failed to query envs table")?;
+  let mut total = 0;
+  for env in envs.iter() {
+    let txn = db_pool
+      .begin()
+      .await
+      .context("Failed to start transaction")?;
+    match gc_deploys(txn, env.id.as_str(), false).await {
+      Ok((deleted, txn)) => {
+        txn
+          .commit()
+          .await
+          .context("Failed to commit transaction when gc deploys")?;
+        total += deleted.len();
+      }
+      Err(e) => warn!(env = env.id, "Failed to gc deploys: {:?}", e),
+    }
+  }
+  info!("gc deleted {} deploys", total);
+  Ok(total)
+}
+
+async fn delete_deploy<'c>(
+  mut txn: Transaction<'c, MySql>,
+  deploy_id: &str,
+) -> Result<Transaction<'c, MySql>> {
+  sqlx::query!("DELETE FROM codes WHERE deploy_id = ?", deploy_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from codes table")?;
+  sqlx::query!("DELETE FROM http_routes WHERE deploy_id = ?", deploy_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from http_routes table")?;
+  sqlx::query!("DELETE FROM deploy_vars WHERE deploy_id = ?", deploy_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from deploy_vars table")?;
+  sqlx::query!("DELETE FROM deploys WHERE id = ?", deploy_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from deploys table")?;
+  Ok(txn)
+}
+
+struct RetentionEntry {
+  deploy_seq: DeploySeq,
+  tagged: bool,
+  is_code: bool,
+}
+
+/// [`retained_deploys`] keeps the latest `keep_deploys` deploys and the
+/// tagged ones, together with the code and var deploys each of them uses.
+/// `deploys` are ordered by deploy_seq desc.
+fn retained_deploys(
+  deploys: &[RetentionEntry],
+  keep_deploys: usize,
+) -> BTreeSet<DeploySeq> {
+  let mut retained = BTreeSet::new();
+  for (i, d) in deploys.iter().enumerate() {
+    if i >= keep_deploys && !d.tagged {
+      continue;
+    }
+    retained.insert(d.deploy_seq);
+    // the code deploy and var deploy serving when `d` took effect.
+    let rest = &deploys[i..];
+    if let Some(c) = rest.iter().find(|e| e.is_code) {
+      retained.insert(c.deploy_seq);
+    }
+    if let Some(v) = rest.iter().find(|e| !e.is_code) {
+      retained.insert(v.deploy_seq);
+    }
+  }
+  retained
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(
+    deploy_seq: DeploySeq,
+    tagged: bool,
+    is_code: bool,
+  ) -> RetentionEntry {
+    RetentionEntry {
+      deploy_seq,
+      tagged,
+      is_code,
+    }
+  }
+
+  #[test]
+  fn test_retained_deploys() {
+    // 6: var, 5: code, 4: code, 3: code (tagged), 2: var, 1: code, 0: var
+    let deploys = vec![
+      entry(6, false, false),
+      entry(5, false, true),
+      entry(4, false, true),
+      entry(3, true, true),
+      entry(2, false, false),
+      entry(1, false, true),
+      entry(0, false, false),
+    ];
+    let retained = retained_deploys(&deploys, 2);
+    assert_eq!(vec![2, 3, 5, 6], retained.into_iter().collect::<Vec<_>>());
+
+    // the latest var deploy is kept with the latest code deploy.
+    let retained = retained_deploys(&deploys[1..], 1);
+    assert_eq!(vec![2, 3, 5], retained.into_iter().collect::<Vec<_>>());
+
+    let retained = retained_deploys(&deploys, 10);
+    assert_eq!(7, retained.len());
+  }
+}
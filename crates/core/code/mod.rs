@@ -1,2 +1,6 @@
+pub mod blob;
 pub mod control;
+pub mod diff;
 mod esm_parser;
+pub mod gc;
+mod vendor;
@@ -0,0 +1,313 @@
+use crate::code::esm_parser::parse_module_imports;
+use crate::tenants::cache::LruCache;
+use crate::Code;
+use anyhow::{anyhow, bail, Context, Result};
+use darx_isolate_runtime::{
+  is_remote, EgressClient, EgressPolicy, ImportMap, VendoredModules,
+  IMPORT_MAP_FILE, VENDOR_DIR, VENDOR_MODULES_FILE,
+};
+use deno_core::url::Url;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::env;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The modules vendored by a deploy at most.
+const MAX_MODULES: usize = 1000;
+const MAX_MODULE_BYTES: usize = 10 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+// the extensions the module loader recognizes, a module without one of
+// them is stored as javascript.
+const MODULE_EXTENSIONS: [&str; 5] = ["js", "mjs", "ts", "tsx", "json"];
+
+#[derive(Clone)]
+struct Fetched {
+  url: String,
+  content: String,
+  typescript: bool,
+}
+
+// the control plane fetches the modules, so private networks are never
+// allowed, the hosts are the ones of `DARX_VENDOR_ALLOWED_HOSTS`, comma
+// separated, or any public host by default.
+static CLIENT: Lazy<EgressClient> = Lazy::new(|| {
+  let allowed_hosts = env::var("DARX_VENDOR_ALLOWED_HOSTS")
+    .map(|hosts| hosts.split(',').map(|h| h.trim().to_string()).collect())
+    .unwrap_or_else(|_| vec!["*".to_string()]);
+  let policy = EgressPolicy {
+    allowed_hosts,
+    allowed_ports: vec![443],
+    allowed_networks: vec![],
+  };
+  EgressClient::new(&policy, "darx-deploy", Duration::from_secs(30))
+    .expect("Failed to create http client of vendoring modules")
+});
+
+// the modules fetched by the recent deploys, the versions of packages and
+// the urls imported are usually pinned.
+static CACHE: Lazy<Mutex<LruCache<String, Fetched, 512>>> =
+  Lazy::new(|| Mutex::new(LruCache::new()));
+
+/// [`npm_registry`] is the registry `npm:` imports are fetched from as ES
+/// modules, e.g. `npm:nanoid@4` is fetched from `{registry}/nanoid@4`.
+/// It's `DARX_NPM_REGISTRY`, or https://esm.sh by default.
+fn npm_registry() -> String {
+  env::var("DARX_NPM_REGISTRY")
+    .unwrap_or_else(|_| "https://esm.sh".to_string())
+    .trim_end_matches('/')
+    .to_string()
+}
+
+/// [`vendor_modules`] fetches the `https:` and `npm:` modules imported by
+/// the codes, directly or by the import map, and the modules imported by
+/// them. The modules are stored with the deploy under [`VENDOR_DIR`] and
+/// locked by [`VENDOR_MODULES_FILE`], so loading the deploy never fetches.
+pub(crate) async fn vendor_modules(
+  codes: &[Code],
+  import_map: &ImportMap,
+) -> Result<Vec<Code>> {
+  let mut queue = VecDeque::new();
+  for code in codes.iter().filter(|c| c.fs_path != IMPORT_MAP_FILE) {
+    let imports =
+      parse_module_imports(code.fs_path.as_str(), code.content.as_str())?;
+    for import in imports.into_iter() {
+      let import = import_map.resolve(import.as_str()).unwrap_or(import);
+      if !is_remote(import.as_str()) {
+        if is_bare(import.as_str()) {
+          bail!(
+            "Bare import {} of {} is not mapped by {}",
+            import,
+            code.fs_path,
+            IMPORT_MAP_FILE
+          );
+        }
+        continue;
+      }
+      let url = Url::parse(import.as_str())
+        .with_context(|| format!("Invalid import {}", import))?;
+      queue.push_back((url.to_string(), code.fs_path.clone()));
+    }
+  }
+  if queue.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let mut vendored = VendoredModules::default();
+  let mut modules = vec![];
+  while let Some((specifier, importer)) = queue.pop_front() {
+    if vendored.imports.contains_key(&specifier) {
+      continue;
+    }
+    if vendored.imports.len() >= MAX_MODULES {
+      bail!("More than {} modules are imported", MAX_MODULES);
+    }
+    let fetched = fetch(specifier.as_str()).await.with_context(|| {
+      format!("Failed to fetch {} of {}", specifier, importer)
+    })?;
+    let fs_path = vendored_path(&fetched);
+    // the module loader loads typescript as javascript.
+    if fs_path.ends_with(".ts") || fs_path.ends_with(".tsx") {
+      bail!("TypeScript module {} is not supported", fetched.url);
+    }
+    // a module imported by different urls is stored once.
+    if !vendored.urls.contains_key(&fs_path) {
+      let base = Url::parse(fetched.url.as_str())?;
+      // the parse error quotes the module, it's not echoed back.
+      let imports = parse_module_imports(fs_path.as_str(), &fetched.content)
+        .map_err(|_| anyhow!("Failed to parse {} as a module", fetched.url))?;
+      for import in imports.into_iter() {
+        queue
+          .push_back((resolve(&base, import.as_str())?, fetched.url.clone()));
+      }
+      vendored.urls.insert(fs_path.clone(), fetched.url.clone());
+      modules.push(Code {
+        fs_path: fs_path.clone(),
+        content: fetched.content,
+      });
+    }
+    vendored.imports.insert(specifier, fs_path);
+  }
+
+  modules.push(Code {
+    fs_path: VENDOR_MODULES_FILE.to_string(),
+    content: serde_json::to_string_pretty(&vendored)?,
+  });
+  Ok(modules)
+}
+
+fn is_bare(import: &str) -> bool {
+  let relative = ["./", "../", "/"].iter().any(|p| import.starts_with(p));
+  !relative && !is_remote(import)
+}
+
+// resolve resolves an import of a vendored module by the url of the module,
+// bare imports are not supported since there is no package.json.
+fn resolve(base: &Url, import: &str) -> Result<String> {
+  if is_bare(import) {
+    bail!("Bare import {} of {} is not supported", import, base);
+  }
+  let url = base
+    .join(import)
+    .with_context(|| format!("Invalid import {} of {}", import, base))?;
+  Ok(url.to_string())
+}
+
+async fn fetch(specifier: &str) -> Result<Fetched> {
+  let url = match specifier.strip_prefix("npm:") {
+    Some(package) => format!("{}/{}", npm_registry(), package),
+    None if specifier.starts_with("https:") => specifier.to_string(),
+    None => bail!("Only https and npm imports are supported"),
+  };
+  if let Some(fetched) = CACHE.lock().unwrap().get(&url) {
+    return Ok(fetched.clone());
+  }
+
+  // each redirect is checked by the egress policy before it's followed.
+  let mut final_url = Url::parse(url.as_str())?;
+  let mut redirects = 0;
+  let mut rsp = loop {
+    if final_url.scheme() != "https" {
+      bail!("Only https modules are supported, got {}", final_url);
+    }
+    CLIENT.check_url(&final_url)?;
+    let rsp = CLIENT.client().get(final_url.clone()).send().await?;
+    if !rsp.status().is_redirection() {
+      break rsp.error_for_status()?;
+    }
+    redirects += 1;
+    if redirects > MAX_REDIRECTS {
+      bail!("More than {} redirects", MAX_REDIRECTS);
+    }
+    let location = rsp
+      .headers()
+      .get(reqwest::header::LOCATION)
+      .and_then(|l| l.to_str().ok())
+      .ok_or_else(|| anyhow!("Redirect of {} has no location", final_url))?;
+    final_url = final_url.join(location)?;
+  };
+  if rsp.content_length().unwrap_or_default() > MAX_MODULE_BYTES as u64 {
+    bail!("The module exceeds {} bytes", MAX_MODULE_BYTES);
+  }
+  let typescript = rsp
+    .headers()
+    .get(reqwest::header::CONTENT_TYPE)
+    .and_then(|t| t.to_str().ok())
+    .map_or(false, |t| t.contains("typescript"));
+  // the content length is optional, the body is limited as it's read.
+  let mut content = vec![];
+  while let Some(chunk) = rsp.chunk().await? {
+    if content.len() + chunk.len() > MAX_MODULE_BYTES {
+      bail!("The module exceeds {} bytes", MAX_MODULE_BYTES);
+    }
+    content.extend_from_slice(&chunk);
+  }
+  let fetched = Fetched {
+    url: final_url.to_string(),
+    content: String::from_utf8(content)
+      .map_err(|_| anyhow!("The module is not utf-8"))?,
+    typescript,
+  };
+  CACHE.lock().unwrap().put(url, fetched.clone());
+  Ok(fetched)
+}
+
+/// [`vendored_path`] names a module by the hash of its url, with
+/// the extension of the url, or `.ts` if it's served as typescript.
+fn vendored_path(fetched: &Fetched) -> String {
+  let hash = format!("{:x}", Sha256::digest(fetched.url.as_bytes()));
+  let ext = Url::parse(fetched.url.as_str())
+    .ok()
+    .and_then(|url| {
+      let ext = Path::new(url.path()).extension()?.to_str()?.to_string();
+      MODULE_EXTENSIONS.contains(&ext.as_str()).then_some(ext)
+    })
+    .unwrap_or_else(|| {
+      if fetched.typescript { "ts" } else { "js" }.to_string()
+    });
+  format!("{}/{}.{}", VENDOR_DIR, &hash[..16], ext)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_resolve() {
+    let base = Url::parse("https://esm.sh/nanoid@4.0.2").unwrap();
+    assert_eq!(
+      "https://esm.sh/v135/nanoid@4.0.2/es2022/nanoid.mjs",
+      resolve(&base, "/v135/nanoid@4.0.2/es2022/nanoid.mjs").unwrap()
+    );
+    assert_eq!(
+      "https://esm.sh/url-alphabet.js",
+      resolve(&base, "./url-alphabet.js").unwrap()
+    );
+    assert_eq!("npm:react@18", resolve(&base, "npm:react@18").unwrap());
+    assert!(resolve(&base, "react").is_err());
+  }
+
+  #[test]
+  fn test_vendored_path() {
+    let fetched = |url: &str, typescript| Fetched {
+      url: url.to_string(),
+      content: String::new(),
+      typescript,
+    };
+    let path =
+      vendored_path(&fetched("https://deno.land/std/uuid/mod.ts", false));
+    assert!(path.starts_with("vendor/") && path.ends_with(".ts"));
+    let path = vendored_path(&fetched("https://esm.sh/nanoid@4", false));
+    assert!(path.ends_with(".js"));
+    let path = vendored_path(&fetched("https://x.dev/mod", true));
+    assert!(path.ends_with(".ts"));
+  }
+
+  #[tokio::test]
+  async fn test_fetch_egress() {
+    assert!(fetch("https://169.254.169.254/latest/meta-data")
+      .await
+      .is_err());
+    assert!(fetch("https://127.0.0.1/mod.js").await.is_err());
+    assert!(fetch("https://[::ffff:10.0.0.1]/mod.js").await.is_err());
+    assert!(fetch("https://example.com:8443/mod.js").await.is_err());
+    assert!(fetch("http://example.com/mod.js").await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_no_remote_imports() {
+    let codes = vec![Code {
+      fs_path: "functions/a.js".to_string(),
+      content: "import { b } from './b.js';".to_string(),
+    }];
+    let import_map = ImportMap::default();
+    assert!(vendor_modules(&codes, &import_map)
+      .await
+      .unwrap()
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_import_map() {
+    let codes = vec![
+      Code {
+        fs_path: "functions/a.js".to_string(),
+        content: "import { greet } from '@lib/greet.js';".to_string(),
+      },
+      Code {
+        fs_path: IMPORT_MAP_FILE.to_string(),
+        content: r#"{"imports": {"@lib/": "./lib/"}}"#.to_string(),
+      },
+    ];
+    let import_map = ImportMap::parse(&codes[1].content).unwrap();
+    assert!(vendor_modules(&codes, &import_map)
+      .await
+      .unwrap()
+      .is_empty());
+    let err = vendor_modules(&codes, &ImportMap::default()).await;
+    assert!(err.is_err());
+  }
+}
@@ -0,0 +1,121 @@
+use crate::api::{ApiError, CorsConfig};
+use anyhow::Context;
+use sqlx::{MySql, MySqlExecutor, Transaction};
+use std::ops::DerefMut;
+
+/// [`validate_cors`] checks the origins, methods and headers are well formed,
+/// browsers reject credentials for any origin, so they can't be combined.
+pub fn validate_cors(cors: &CorsConfig) -> Result<(), ApiError> {
+  for origin in cors.allowed_origins.iter() {
+    if origin == "*" {
+      if cors.allow_credentials {
+        return Err(ApiError::InvalidCors(
+          "credentials are not allowed for any origin".to_string(),
+        ));
+      }
+      continue;
+    }
+    let host = origin
+      .strip_prefix("https://")
+      .or_else(|| origin.strip_prefix("http://"));
+    let valid = host
+      .map(|host| !host.is_empty() && !host.contains('/'))
+      .unwrap_or(false);
+    if !valid {
+      return Err(ApiError::InvalidCors(format!("invalid origin {}", origin)));
+    }
+  }
+  for method in cors.allowed_methods.iter() {
+    if method != "*" && !is_token(method) {
+      return Err(ApiError::InvalidCors(format!("invalid method {}", method)));
+    }
+  }
+  for header in cors.allowed_headers.iter() {
+    if header != "*" && !is_token(header) {
+      return Err(ApiError::InvalidCors(format!("invalid header {}", header)));
+    }
+  }
+  Ok(())
+}
+
+fn is_token(s: &str) -> bool {
+  !s.is_empty()
+    && s
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// [`set_cors`] sets the CORS policy of the env,
+/// the default one is used if `cors` is none.
+pub async fn set_cors<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  cors: Option<&CorsConfig>,
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  if let Some(cors) = cors {
+    validate_cors(cors)?;
+  }
+  sqlx::query!("SELECT id FROM envs WHERE id = ? FOR UPDATE", env_id)
+    .fetch_optional(txn.deref_mut())
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+  let cors = cors
+    .map(serde_json::to_value)
+    .transpose()
+    .context("Failed to serialize cors")?;
+  sqlx::query!("UPDATE envs SET cors = ? WHERE id = ?", cors, env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to update envs table")?;
+  Ok(txn)
+}
+
+pub async fn get_cors<'c>(
+  exe: impl MySqlExecutor<'c>,
+  env_id: &str,
+) -> Result<Option<CorsConfig>, ApiError> {
+  let env = sqlx::query!("SELECT cors FROM envs WHERE id = ?", env_id)
+    .fetch_optional(exe)
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+  let cors = env
+    .cors
+    .map(serde_json::from_value)
+    .transpose()
+    .context("Failed to parse cors")?;
+  Ok(cors)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cors(origins: &[&str], allow_credentials: bool) -> CorsConfig {
+    CorsConfig {
+      allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+      allowed_methods: vec!["POST".to_string()],
+      allowed_headers: vec!["Authorization".to_string()],
+      allow_credentials,
+      max_age: Some(600),
+    }
+  }
+
+  #[test]
+  fn test_validate_cors() {
+    assert!(validate_cors(&CorsConfig::default()).is_ok());
+    assert!(validate_cors(&cors(
+      &["https://example.com", "http://localhost:3000"],
+      true
+    ))
+    .is_ok());
+    assert!(validate_cors(&cors(&["*"], true)).is_err());
+    assert!(validate_cors(&cors(&["example.com"], false)).is_err());
+    assert!(validate_cors(&cors(&["https://example.com/"], false)).is_err());
+
+    let mut invalid = cors(&["*"], false);
+    invalid.allowed_headers.push("X Header".to_string());
+    assert!(validate_cors(&invalid).is_err());
+  }
+}
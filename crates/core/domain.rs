@@ -0,0 +1,147 @@
+use crate::api::{ApiError, DomainInfo};
+use anyhow::Context;
+use sqlx::{MySql, MySqlExecutor, Transaction};
+use std::ops::DerefMut;
+
+/// [`validate_domain`] lowercases the hostname and trims the slashes
+/// around the path prefix.
+pub fn validate_domain(domain: &DomainInfo) -> Result<DomainInfo, ApiError> {
+  let hostname = domain.hostname.trim().to_lowercase();
+  let valid_hostname = !hostname.is_empty()
+    && hostname.len() <= 253
+    && hostname.split('.').all(|label| {
+      !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+  if !valid_hostname {
+    return Err(ApiError::InvalidDomain(domain.hostname.clone()));
+  }
+
+  let path_prefix = domain.path_prefix.trim_matches('/');
+  let valid_prefix = path_prefix.split('/').all(|segment| {
+    path_prefix.is_empty()
+      || (!segment.is_empty()
+        && segment
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)))
+  });
+  if !valid_prefix {
+    return Err(ApiError::InvalidDomain(domain.path_prefix.clone()));
+  }
+  Ok(DomainInfo {
+    hostname,
+    path_prefix: path_prefix.to_string(),
+  })
+}
+
+/// [`add_domain`] maps the domain to the env,
+/// and returns all domains of the env.
+pub async fn add_domain<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  domain: &DomainInfo,
+) -> Result<(Vec<DomainInfo>, Transaction<'c, MySql>), ApiError> {
+  let domain = validate_domain(domain)?;
+  sqlx::query!("SELECT id FROM envs WHERE id = ? FOR UPDATE", env_id)
+    .fetch_optional(txn.deref_mut())
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+
+  let r = sqlx::query!(
+    "INSERT IGNORE INTO domains (hostname, path_prefix, env_id) VALUES (?, ?, ?)",
+    domain.hostname,
+    domain.path_prefix,
+    env_id
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to insert into domains table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::DomainAlreadyExists(format!(
+      "{}/{}",
+      domain.hostname, domain.path_prefix
+    )));
+  }
+  let domains = list_domains(txn.deref_mut(), env_id).await?;
+  Ok((domains, txn))
+}
+
+/// [`remove_domain`] returns the remaining domains of the env.
+pub async fn remove_domain<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  domain: &DomainInfo,
+) -> Result<(Vec<DomainInfo>, Transaction<'c, MySql>), ApiError> {
+  let domain = validate_domain(domain)?;
+  let r = sqlx::query!(
+    "DELETE FROM domains WHERE hostname = ? AND path_prefix = ? AND env_id = ?",
+    domain.hostname,
+    domain.path_prefix,
+    env_id
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to delete from domains table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::DomainNotFound(format!(
+      "{}/{}",
+      domain.hostname, domain.path_prefix
+    )));
+  }
+  let domains = list_domains(txn.deref_mut(), env_id).await?;
+  Ok((domains, txn))
+}
+
+pub async fn list_domains<'c>(
+  exe: impl MySqlExecutor<'c>,
+  env_id: &str,
+) -> Result<Vec<DomainInfo>, ApiError> {
+  let domains = sqlx::query!(
+    "SELECT hostname, path_prefix FROM domains WHERE env_id = ? ORDER BY hostname, path_prefix",
+    env_id
+  )
+  .fetch_all(exe)
+  .await
+  .context("Failed to query domains table")?
+  .into_iter()
+  .map(|d| DomainInfo {
+    hostname: d.hostname,
+    path_prefix: d.path_prefix,
+  })
+  .collect();
+  Ok(domains)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn domain(hostname: &str, path_prefix: &str) -> DomainInfo {
+    DomainInfo {
+      hostname: hostname.to_string(),
+      path_prefix: path_prefix.to_string(),
+    }
+  }
+
+  #[test]
+  fn test_validate_domain() {
+    assert_eq!(
+      validate_domain(&domain("API.Example.com", "/billing/")).unwrap(),
+      domain("api.example.com", "billing")
+    );
+    assert_eq!(
+      validate_domain(&domain("example.com", "")).unwrap(),
+      domain("example.com", "")
+    );
+    assert!(validate_domain(&domain("", "")).is_err());
+    assert!(validate_domain(&domain("example.com:8080", "")).is_err());
+    assert!(validate_domain(&domain("-a.example.com", "")).is_err());
+    assert!(validate_domain(&domain("a..com", "")).is_err());
+    assert!(validate_domain(&domain("example.com", "a//b")).is_err());
+    assert!(validate_domain(&domain("example.com", "a?b")).is_err());
+  }
+}
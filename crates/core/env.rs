@@ -0,0 +1,313 @@
+use crate::api::{ApiError, EnvInfo};
+use crate::code::control::deploy_var;
+use crate::env_vars::{Var, VarList};
+use crate::EnvId;
+use anyhow::{Context, Result};
+use darx_db::{drop_tenant_db, save_tenant_db, TenantDBInfo};
+use darx_utils::new_nano_id;
+use sqlx::{MySql, MySqlPool, Transaction};
+use std::env;
+use std::ops::DerefMut;
+
+/// The env created together with a new project.
+pub const DEFAULT_ENV_NAME: &str = "dev";
+
+pub struct Env {
+  id: EnvId,
+  project_id: String,
+  name: String,
+  db_info: Option<TenantDBInfo>,
+  var_list: VarList,
+}
+
+impl Env {
+  /// [`Env::new_tenant_env`] creates an env with its own tenant db.
+  pub fn new_tenant_env(project_id: &str, name: &str) -> Self {
+    let mut env = Env::new_minimal_env(project_id, new_nano_id(), name);
+    let env_id = env.id.as_str();
+    let db_host =
+      env::var("DATA_PLANE_DB_HOST").expect("DATA_PLANE_DB_HOST not set");
+    let db_port =
+      env::var("DATA_PLANE_DB_PORT").expect("DATA_PLANE_DB_PORT not set");
+    let db_user = env_id.to_string();
+    let db_name = format!("dx_{}", env_id);
+
+    // todo: encrypt this.
+    let db_password = new_nano_id();
+    let db_info = TenantDBInfo {
+      host: db_host,
+      port: db_port.parse::<u16>().expect("Failed to parse db port"),
+      user: db_user,
+      password: db_password,
+      database: db_name.clone(),
+    };
+
+    env.var_list = VarList::new_env_vars(
+      env_id,
+      &vec![Var::new("DX_DB_NAME", db_name.as_str())],
+    );
+    env.db_info = Some(db_info);
+    env
+  }
+
+  pub(crate) fn new_minimal_env(
+    project_id: &str,
+    env_id: EnvId,
+    name: &str,
+  ) -> Self {
+    Env {
+      var_list: VarList::new_env_vars(env_id.as_str(), &vec![]),
+      id: env_id,
+      project_id: project_id.to_string(),
+      name: name.to_string(),
+      db_info: None,
+    }
+  }
+
+  pub fn id(&self) -> &EnvId {
+    &self.id
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn db_info(&self) -> &Option<TenantDBInfo> {
+    &self.db_info
+  }
+
+  pub fn env_info(&self) -> EnvInfo {
+    EnvInfo {
+      id: self.id.clone(),
+      name: self.name.clone(),
+    }
+  }
+
+  pub(crate) fn set_project_id(&mut self, project_id: &str) {
+    self.project_id = project_id.to_string();
+  }
+
+  pub(crate) fn set_id(&mut self, env_id: &str) {
+    self.id = env_id.to_string();
+    self.var_list = VarList::new_env_vars(env_id, &vec![]);
+  }
+
+  pub async fn save<'c>(
+    &self,
+    txn: Transaction<'c, MySql>,
+  ) -> Result<Transaction<'c, MySql>, ApiError> {
+    if !is_valid_env_name(self.name.as_str()) {
+      return Err(ApiError::InvalidEnvName(self.name.clone()));
+    }
+    let mut txn = save_env(
+      txn,
+      self.project_id.as_str(),
+      self.id.as_str(),
+      self.name.as_str(),
+    )
+    .await?;
+
+    if let Some(db_info) = &self.db_info {
+      save_tenant_db(&mut txn, self.id.as_str(), db_info).await?;
+    }
+
+    let txn =
+      save_default_env_vars(txn, self.id.as_str(), &self.var_list).await?;
+    Ok(txn)
+  }
+}
+
+pub async fn list_env(
+  db_pool: &MySqlPool,
+  project_id: &str,
+) -> Result<Vec<EnvInfo>> {
+  let envs = sqlx::query!(
+    "SELECT id, name FROM envs WHERE project_id = ? ORDER BY created_at",
+    project_id
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query envs table")?;
+  Ok(
+    envs
+      .into_iter()
+      .map(|e| EnvInfo {
+        id: e.id,
+        name: e.name,
+      })
+      .collect(),
+  )
+}
+
+/// [`delete_env`] removes the env with its deploys, vars and tenant db.
+pub async fn delete_env<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  sqlx::query!("SELECT id FROM envs WHERE id = ? FOR UPDATE", env_id)
+    .fetch_optional(txn.deref_mut())
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+
+  let db = sqlx::query!(
+    "SELECT db_host, db_port, db_user, db_password, db_name FROM env_dbs WHERE env_id = ?",
+    env_id
+  )
+  .fetch_optional(txn.deref_mut())
+  .await
+  .context("Failed to query env_dbs table")?;
+  if let Some(db) = db {
+    let db_info = TenantDBInfo {
+      host: db.db_host,
+      port: db.db_port as u16,
+      user: db.db_user,
+      password: db.db_password,
+      database: db.db_name,
+    };
+    drop_tenant_db(&mut txn, env_id, &db_info).await?;
+  }
+
+  let txn = drop_env(txn, env_id).await?;
+  Ok(txn)
+}
+
+/// [`is_valid_env_name`] allows names like `dev`, `staging` or `prod-eu`.
+pub(crate) fn is_valid_env_name(name: &str) -> bool {
+  !name.is_empty()
+    && name.len() <= 64
+    && name.starts_with(|c: char| c.is_ascii_lowercase())
+    && name
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+async fn save_env<'c>(
+  mut txn: Transaction<'c, MySql>,
+  project_id: &str,
+  env_id: &str,
+  env_name: &str,
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  let exist = sqlx::query!(
+    "SELECT id FROM envs WHERE project_id = ? AND name = ?",
+    project_id,
+    env_name
+  )
+  .fetch_optional(txn.deref_mut())
+  .await
+  .context("Failed to query envs table")?;
+  if exist.is_some() {
+    return Err(ApiError::EnvAlreadyExists(env_name.to_string()));
+  }
+
+  sqlx::query!(
+    "INSERT INTO `envs` (`id`, `project_id`, `name`) VALUES (?, ?, ?)",
+    env_id,
+    project_id,
+    env_name
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to insert into envs table")?;
+  Ok(txn)
+}
+
+pub(crate) async fn drop_env<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+) -> Result<Transaction<'c, MySql>> {
+  let deploys = sqlx::query!("SELECT id FROM deploys WHERE env_id = ?", env_id)
+    .fetch_all(txn.deref_mut())
+    .await
+    .context("Failed to select from deploys table")?;
+
+  sqlx::query!("DELETE FROM `envs` WHERE `id` = ?", env_id,)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from envs table")?;
+
+  sqlx::query!("DELETE FROM `env_vars` WHERE `env_id` = ?", env_id,)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from env_vars table")?;
+
+  sqlx::query!("DELETE FROM env_placements WHERE env_id = ?", env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from env_placements table")?;
+
+  sqlx::query!("DELETE FROM domains WHERE env_id = ?", env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from domains table")?;
+
+  sqlx::query!("DELETE FROM env_limits WHERE env_id = ?", env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from env_limits table")?;
+
+  sqlx::query!("DELETE FROM env_usage WHERE env_id = ?", env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from env_usage table")?;
+
+  // codes, http_routes, deploy_vars, deploys
+  for deploy in deploys.iter() {
+    let deploy_id = &deploy.id;
+    sqlx::query!("DELETE FROM `codes` WHERE `deploy_id` = ?", deploy_id,)
+      .execute(txn.deref_mut())
+      .await
+      .context("Failed to delete from codes table")?;
+
+    sqlx::query!("DELETE FROM `http_routes` WHERE `deploy_id` = ?", deploy_id,)
+      .execute(txn.deref_mut())
+      .await
+      .context("Failed to delete from http_routes table")?;
+
+    sqlx::query!("DELETE FROM `deploy_vars` WHERE `deploy_id` = ?", deploy_id,)
+      .execute(txn.deref_mut())
+      .await
+      .context("Failed to delete from deploy_vars table")?;
+  }
+
+  sqlx::query!("DELETE FROM deploys WHERE env_id = ?", env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from deploys table")?;
+
+  Ok(txn)
+}
+
+async fn save_default_env_vars<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  var_list: &VarList,
+) -> Result<Transaction<'c, MySql>> {
+  var_list.save(txn.deref_mut()).await?;
+  let (_, _, txn) = deploy_var(
+    txn,
+    env_id,
+    &Default::default(),
+    &Default::default(),
+    &Some("default env deploy".to_string()),
+  )
+  .await?;
+  Ok(txn)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_valid_env_name() {
+    assert!(is_valid_env_name("dev"));
+    assert!(is_valid_env_name("staging"));
+    assert!(is_valid_env_name("prod-eu1"));
+    assert!(!is_valid_env_name(""));
+    assert!(!is_valid_env_name("Prod"));
+    assert!(!is_valid_env_name("1dev"));
+    assert!(!is_valid_env_name("dev_1"));
+    assert!(!is_valid_env_name(&"a".repeat(65)));
+  }
+}
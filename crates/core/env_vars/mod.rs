@@ -1,5 +1,7 @@
+pub(crate) mod secret;
 pub(crate) mod var;
 pub(crate) mod var_list;
 
+pub use secret::MASKED_VALUE;
 pub use var::Var;
 pub use var_list::VarList;
@@ -0,0 +1,82 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::env;
+
+/// The placeholder returned instead of a secret var's value.
+pub const MASKED_VALUE: &str = "********";
+
+const NONCE_LEN: usize = 12;
+
+/// [`encrypt_secret`] encrypts a secret var's value with the key configured
+/// in `DARX_VAR_SECRET_KEY`, the result is safe to store in the control db.
+pub fn encrypt_secret(plain: &str) -> Result<String> {
+  encrypt_with(&secret_key()?, plain)
+}
+
+/// [`decrypt_secret`] reverts [`encrypt_secret`].
+pub fn decrypt_secret(encrypted: &str) -> Result<String> {
+  decrypt_with(&secret_key()?, encrypted)
+}
+
+fn secret_key() -> Result<Key<Aes256Gcm>> {
+  let key = env::var("DARX_VAR_SECRET_KEY")
+    .context("DARX_VAR_SECRET_KEY should be configured to use secret vars")?;
+  let key = STANDARD
+    .decode(key)
+    .context("DARX_VAR_SECRET_KEY should be base64 encoded")?;
+  if key.len() != 32 {
+    bail!("DARX_VAR_SECRET_KEY should be 32 bytes, got {}", key.len());
+  }
+  Ok(*Key::<Aes256Gcm>::from_slice(key.as_slice()))
+}
+
+// the stored form is base64(nonce || ciphertext).
+fn encrypt_with(key: &Key<Aes256Gcm>, plain: &str) -> Result<String> {
+  let cipher = Aes256Gcm::new(key);
+  let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+  let encrypted = cipher
+    .encrypt(&nonce, plain.as_bytes())
+    .map_err(|e| anyhow!("Failed to encrypt secret var: {}", e))?;
+  let mut buf = nonce.to_vec();
+  buf.extend_from_slice(encrypted.as_slice());
+  Ok(STANDARD.encode(buf))
+}
+
+fn decrypt_with(key: &Key<Aes256Gcm>, encrypted: &str) -> Result<String> {
+  let buf = STANDARD
+    .decode(encrypted)
+    .context("Failed to decode secret var")?;
+  if buf.len() < NONCE_LEN {
+    bail!("Invalid secret var, length {}", buf.len());
+  }
+  let (nonce, encrypted) = buf.split_at(NONCE_LEN);
+  let cipher = Aes256Gcm::new(key);
+  let plain = cipher
+    .decrypt(Nonce::from_slice(nonce), encrypted)
+    .map_err(|e| anyhow!("Failed to decrypt secret var: {}", e))?;
+  String::from_utf8(plain).context("Secret var is not valid utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_encrypt_decrypt() -> Result<()> {
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    let encrypted = encrypt_with(&key, "my_password")?;
+    assert_ne!(encrypted, "my_password");
+    assert_eq!(decrypt_with(&key, encrypted.as_str())?, "my_password");
+
+    // same value encrypts differently every time.
+    assert_ne!(encrypt_with(&key, "my_password")?, encrypted);
+
+    let other_key = Aes256Gcm::generate_key(&mut OsRng);
+    assert!(decrypt_with(&other_key, encrypted.as_str()).is_err());
+    assert!(decrypt_with(&key, "abc").is_err());
+    Ok(())
+  }
+}
@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use crate::env_vars::secret::MASKED_VALUE;
+use std::fmt;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum VarKind {
@@ -30,10 +31,14 @@ impl VarKind {
   }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A [`Var`] always holds the plain value in memory, a secret var is only
+/// encrypted when it's saved to the control db. It's not serializable,
+/// so the plain value of a secret can't leak into a response or an event.
+#[derive(Clone, PartialEq)]
 pub struct Var {
   key: String,
   val: String,
+  is_secret: bool,
 }
 
 impl Var {
@@ -41,6 +46,14 @@ impl Var {
     Var {
       key: key.into(),
       val: val.into(),
+      is_secret: false,
+    }
+  }
+  pub fn new_secret<T: Into<String>>(key: T, val: T) -> Var {
+    Var {
+      key: key.into(),
+      val: val.into(),
+      is_secret: true,
     }
   }
   pub fn key(&self) -> &str {
@@ -49,4 +62,59 @@ impl Var {
   pub fn val(&self) -> &str {
     return &self.val;
   }
+  pub fn is_secret(&self) -> bool {
+    self.is_secret
+  }
+  /// [`masked_val`] returns the value that is safe to show to users.
+  pub fn masked_val(&self) -> &str {
+    if self.is_secret {
+      MASKED_VALUE
+    } else {
+      &self.val
+    }
+  }
+}
+
+/// A valid key looks like an identifier, e.g. `DX_DB_NAME`.
+pub(crate) fn is_valid_key(key: &str) -> bool {
+  let mut chars = key.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+    _ => return false,
+  }
+  key.len() <= 255 && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl fmt::Debug for Var {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Var")
+      .field("key", &self.key)
+      .field("val", &self.masked_val())
+      .field("is_secret", &self.is_secret)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_valid_key() {
+    assert!(is_valid_key("DX_DB_NAME"));
+    assert!(is_valid_key("_key1"));
+    assert!(!is_valid_key(""));
+    assert!(!is_valid_key("1key"));
+    assert!(!is_valid_key("my-key"));
+    assert!(!is_valid_key("key'"));
+    assert!(!is_valid_key(&"k".repeat(256)));
+  }
+
+  #[test]
+  fn test_secret_debug() {
+    let var = Var::new_secret("key", "my_password");
+    assert!(!format!("{:?}", var).contains("my_password"));
+    assert_eq!(var.masked_val(), MASKED_VALUE);
+    assert_eq!(Var::new("key", "val").masked_val(), "val");
+  }
 }
@@ -1,6 +1,7 @@
+use crate::env_vars::secret::{decrypt_secret, encrypt_secret};
 use crate::env_vars::var::{Var, VarKind};
 use anyhow::{ensure, Context, Result};
-use sqlx::{MySqlExecutor, Row};
+use sqlx::{MySql, MySqlExecutor, QueryBuilder, Row};
 use std::collections::HashMap;
 use tracing::info;
 
@@ -48,20 +49,30 @@ impl VarList {
     }
 
     let (tbl, parent_name, _) = self.kind.tbl_col();
-    let values = self
-      .vars
-      .iter()
-      .map(|v| {
-        format!("('{}', '{}', '{}')", &self.parent_id, v.key(), &v.val())
-      })
-      .collect::<Vec<String>>()
-      .join(", ");
-    let sql = format!(
-      "insert into `{}` (`{}`, `key`, `value`) values {}",
-      tbl, parent_name, values
-    );
-    let r = exe
-      .execute(sql.as_str())
+    let mut values = vec![];
+    for v in self.vars.iter() {
+      let val = if v.is_secret() {
+        encrypt_secret(v.val())
+          .with_context(|| format!("error encrypt var {}", v.key()))?
+      } else {
+        v.val().to_string()
+      };
+      values.push((v.key(), val, v.is_secret()));
+    }
+    // the values are bound, they are given by users.
+    let mut builder = QueryBuilder::<MySql>::new(format!(
+      "insert into `{}` (`{}`, `key`, `value`, `is_secret`) ",
+      tbl, parent_name
+    ));
+    builder.push_values(values.iter(), |mut b, (key, val, is_secret)| {
+      b.push_bind(self.parent_id.as_str())
+        .push_bind(*key)
+        .push_bind(val.as_str())
+        .push_bind(*is_secret);
+    });
+    let r = builder
+      .build()
+      .execute(exe)
       .await
       .map(|r| r.rows_affected())
       .context("error save var list");
@@ -85,12 +96,13 @@ impl VarList {
 
     let (tbl, parent_col, _) = self.kind.tbl_col();
     let sql = format!(
-      "update {} set is_delete = 1 where {} = '{}'",
-      tbl, parent_col, &self.parent_id
+      "update {} set is_delete = id where {} = ? and is_delete = 0",
+      tbl, parent_col
     );
 
-    let r = exe
-      .execute(sql.as_str())
+    let r = sqlx::query(sql.as_str())
+      .bind(self.parent_id.as_str())
+      .execute(exe)
       .await
       .map(|r| r.rows_affected())
       .context("error del var list");
@@ -111,8 +123,8 @@ impl VarList {
 
     let (tbl, parent_col, has_del) = kind.tbl_col();
     let mut sql = format!(
-      "select `key`, `value` from {} where {} = '{}'",
-      tbl, parent_col, parent_id
+      "select `key`, `value`, `is_secret` from {} where {} = ?",
+      tbl, parent_col
     );
 
     if has_del {
@@ -125,22 +137,27 @@ impl VarList {
       vars: vec![],
     };
 
-    exe
-      .fetch_all(sql.as_str())
+    let rows = sqlx::query(sql.as_str())
+      .bind(parent_id)
+      .fetch_all(exe)
       .await
       .with_context(|| {
         format!(
           "error list var list. parent {}, kind {:?}",
           parent_id, &kind
         )
-      })?
-      .iter()
-      .for_each(|r| {
-        ret.vars.push(Var::new(
-          r.get::<String, _>("key"),
-          r.get::<String, _>("value"),
-        ));
-      });
+      })?;
+    for r in rows.iter() {
+      let key = r.get::<String, _>("key");
+      let val = r.get::<String, _>("value");
+      if r.get::<bool, _>("is_secret") {
+        let val = decrypt_secret(val.as_str())
+          .with_context(|| format!("error decrypt var {}", key))?;
+        ret.vars.push(Var::new_secret(key, val));
+      } else {
+        ret.vars.push(Var::new(key, val));
+      }
+    }
 
     Ok(ret)
   }
@@ -181,6 +198,7 @@ mod tests {
       vars: vec![
         Var::new("test_key1".to_string(), "test_val1".to_string()),
         Var::new("test_key2".to_string(), "test_val2".to_string()),
+        Var::new("test_key3".to_string(), "it's'); --".to_string()),
       ],
     };
 
@@ -1,8 +1,15 @@
 use serde::{Deserialize, Serialize};
 
 pub mod api;
+pub mod auth;
 pub mod code;
+pub mod cors;
+pub mod domain;
+pub mod env;
 pub mod env_vars;
+pub mod limit;
+pub mod node;
+pub mod org;
 pub mod plugin;
 pub mod project;
 mod route_builder;
@@ -17,30 +24,13 @@ pub type EnvId = String;
 pub type DeployId = String;
 pub type DeploySeq = i64;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum EnvKind {
-  #[serde(rename = "dev")]
-  Dev,
-  #[serde(rename = "prod")]
-  Prod,
-}
-
-impl EnvKind {
-  pub fn as_str(&self) -> &str {
-    match self {
-      EnvKind::Dev => "dev",
-      EnvKind::Prod => "prod",
-    }
-  }
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Code {
   pub fs_path: String,
   pub content: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct HttpRoute {
   pub http_path: String,
   pub method: String,
@@ -52,10 +42,14 @@ pub struct HttpRoute {
   pub func_sig: FunctionSignatureV1,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FunctionSignatureV1 {
   pub export_name: String,
   pub param_names: Vec<String>,
+  /// the module exports `requireAuth = true`, calls without a valid
+  /// end-user token are rejected.
+  #[serde(default)]
+  pub auth_required: bool,
 }
 
 /// [`unique_js_export`] returns a unique function name
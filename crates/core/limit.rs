@@ -0,0 +1,245 @@
+use crate::api::{ApiError, EgressPolicy, EnvLimits, RateLimit};
+use anyhow::Context;
+use sqlx::{MySql, MySqlExecutor, Transaction};
+use std::ops::DerefMut;
+
+/// [`validate_limits`] rejects limits that would block every request,
+/// a limit is unset instead.
+pub fn validate_limits(limits: &EnvLimits) -> Result<(), ApiError> {
+  let rate_limits = limits
+    .rate_limit
+    .iter()
+    .map(|limit| ("rate_limit", limit))
+    .chain(
+      limits
+        .route_rate_limits
+        .iter()
+        .map(|(func_url, limit)| (func_url.as_str(), limit)),
+    );
+  for (name, limit) in rate_limits {
+    validate_rate_limit(limit)
+      .map_err(|e| ApiError::InvalidLimits(format!("{}: {}", name, e)))?;
+  }
+  if limits.max_concurrency == Some(0) {
+    return Err(ApiError::InvalidLimits(
+      "max_concurrency should be positive".to_string(),
+    ));
+  }
+  if let Some(egress) = limits.egress.as_ref() {
+    egress
+      .validate()
+      .map_err(|e| ApiError::InvalidLimits(format!("egress: {}", e)))?;
+  }
+  if let Some(fetch) = limits.fetch.as_ref() {
+    fetch
+      .validate()
+      .map_err(|e| ApiError::InvalidLimits(format!("fetch: {}", e)))?;
+  }
+  Ok(())
+}
+
+fn validate_rate_limit(limit: &RateLimit) -> Result<(), &'static str> {
+  if !limit.per_sec.is_finite() || limit.per_sec <= 0.0 {
+    return Err("per_sec should be positive");
+  }
+  if limit.burst == Some(0) {
+    return Err("burst should be positive");
+  }
+  Ok(())
+}
+
+/// [`set_limits`] replaces the limits of the env and returns them.
+/// Only the `allowed_networks` of the egress policy are taken,
+/// the hosts and ports are set by developers with [`set_egress`].
+pub async fn set_limits<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  limits: &EnvLimits,
+) -> Result<(Transaction<'c, MySql>, EnvLimits), ApiError> {
+  validate_limits(limits)?;
+  let current = lock_limits(&mut txn, env_id).await?;
+  let networks = limits
+    .egress
+    .as_ref()
+    .map(|e| e.allowed_networks.clone())
+    .unwrap_or_default();
+  let limits = EnvLimits {
+    egress: with_networks(current.egress, networks),
+    ..limits.clone()
+  };
+  save_limits(&mut txn, env_id, &limits).await?;
+  Ok((txn, limits))
+}
+
+/// [`set_egress`] sets the hosts and ports the functions of the env can
+/// fetch from, the default policy if none, and returns the limits of the env.
+/// The private networks are only opened by operators with [`set_limits`].
+pub async fn set_egress<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  egress: Option<&EgressPolicy>,
+) -> Result<(Transaction<'c, MySql>, EnvLimits), ApiError> {
+  if let Some(egress) = egress {
+    egress
+      .validate()
+      .map_err(|e| ApiError::InvalidLimits(format!("egress: {}", e)))?;
+  }
+  let mut limits = lock_limits(&mut txn, env_id).await?;
+  let networks = limits
+    .egress
+    .take()
+    .map(|e| e.allowed_networks)
+    .unwrap_or_default();
+  if let Some(egress) = egress {
+    if !egress.allowed_networks.is_empty()
+      && egress.allowed_networks != networks
+    {
+      return Err(ApiError::InvalidLimits(
+        "egress: allowed_networks are set by operators".to_string(),
+      ));
+    }
+  }
+  limits.egress = with_networks(egress.cloned(), networks);
+  save_limits(&mut txn, env_id, &limits).await?;
+  Ok((txn, limits))
+}
+
+// [`with_networks`] returns `egress` allowing `networks`,
+// none if it's the default policy.
+fn with_networks(
+  egress: Option<EgressPolicy>,
+  networks: Vec<String>,
+) -> Option<EgressPolicy> {
+  let egress = EgressPolicy {
+    allowed_networks: networks,
+    ..egress.unwrap_or_default()
+  };
+  if egress == EgressPolicy::default() {
+    None
+  } else {
+    Some(egress)
+  }
+}
+
+// [`lock_limits`] locks the env until the transaction ends,
+// and returns its current limits.
+async fn lock_limits(
+  txn: &mut Transaction<'_, MySql>,
+  env_id: &str,
+) -> Result<EnvLimits, ApiError> {
+  sqlx::query!("SELECT id FROM envs WHERE id = ? FOR UPDATE", env_id)
+    .fetch_optional(txn.deref_mut())
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+  get_limits(txn.deref_mut(), env_id).await
+}
+
+async fn save_limits(
+  txn: &mut Transaction<'_, MySql>,
+  env_id: &str,
+  limits: &EnvLimits,
+) -> Result<(), ApiError> {
+  let limits =
+    serde_json::to_value(limits).context("Failed to serialize limits")?;
+  sqlx::query!(
+    "INSERT INTO env_limits (env_id, limits) VALUES (?, ?) ON DUPLICATE KEY UPDATE limits = VALUES(limits)",
+    env_id,
+    limits
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to insert into env_limits table")?;
+  Ok(())
+}
+
+/// [`get_limits`] returns no limits if the env has none set.
+pub async fn get_limits<'c>(
+  exe: impl MySqlExecutor<'c>,
+  env_id: &str,
+) -> Result<EnvLimits, ApiError> {
+  let row =
+    sqlx::query!("SELECT limits FROM env_limits WHERE env_id = ?", env_id)
+      .fetch_optional(exe)
+      .await
+      .context("Failed to query env_limits table")?;
+  let limits = match row {
+    Some(row) => {
+      serde_json::from_value(row.limits).context("Failed to parse limits")?
+    }
+    None => EnvLimits::default(),
+  };
+  Ok(limits)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::{EgressPolicy, FetchLimits};
+
+  #[test]
+  fn test_validate_limits() {
+    let limit = RateLimit {
+      per_sec: 0.5,
+      burst: Some(2),
+    };
+    let mut limits = EnvLimits {
+      rate_limit: Some(limit),
+      max_concurrency: Some(10),
+      monthly_quota: Some(0),
+      ..Default::default()
+    };
+    limits.route_rate_limits.insert("foo.Hi".to_string(), limit);
+    assert!(validate_limits(&limits).is_ok());
+    assert!(validate_limits(&EnvLimits::default()).is_ok());
+
+    let mut invalid = limits.clone();
+    invalid.max_concurrency = Some(0);
+    assert!(validate_limits(&invalid).is_err());
+    let mut invalid = limits.clone();
+    invalid.route_rate_limits.insert(
+      "foo.Bar".to_string(),
+      RateLimit {
+        per_sec: 1.0,
+        burst: Some(0),
+      },
+    );
+    assert!(validate_limits(&invalid).is_err());
+    let mut invalid = limits.clone();
+    invalid.rate_limit = Some(RateLimit {
+      per_sec: f64::NAN,
+      burst: None,
+    });
+    assert!(validate_limits(&invalid).is_err());
+    let mut invalid = limits.clone();
+    invalid.egress = Some(EgressPolicy {
+      allowed_networks: vec!["10.0.0.0".to_string()],
+      ..Default::default()
+    });
+    assert!(validate_limits(&invalid).is_err());
+    let mut invalid = limits;
+    invalid.fetch = Some(FetchLimits {
+      timeout_ms: 0,
+      ..Default::default()
+    });
+    assert!(validate_limits(&invalid).is_err());
+  }
+
+  #[test]
+  fn test_with_networks() {
+    assert_eq!(None, with_networks(None, vec![]));
+    let networks = vec!["10.1.0.0/16".to_string()];
+    let egress = with_networks(None, networks.clone()).unwrap();
+    assert_eq!(networks, egress.allowed_networks);
+    assert_eq!(EgressPolicy::default().allowed_hosts, egress.allowed_hosts);
+
+    let hosts = EgressPolicy {
+      allowed_hosts: vec!["api.example.com".to_string()],
+      allowed_networks: networks,
+      ..Default::default()
+    };
+    let egress = with_networks(Some(hosts.clone()), vec![]).unwrap();
+    assert_eq!(hosts.allowed_hosts, egress.allowed_hosts);
+    assert!(egress.allowed_networks.is_empty());
+  }
+}
@@ -0,0 +1,181 @@
+use crate::api::ApiError;
+use anyhow::{Context, Result};
+use sqlx::{MySql, MySqlPool, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::ops::DerefMut;
+use std::time::Duration;
+
+/// A node missing heartbeats for [`NODE_TIMEOUT`] is down,
+/// deploy events are not delivered to it until it's back.
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// [`Node`] is a data plane node deploy events are delivered to.
+pub struct Node {
+  pub id: String,
+  pub url: String,
+  /// the events up to it were loaded by the node on startup.
+  pub first_event_id: i64,
+}
+
+/// [`register_node`] adds a node, or updates it if the node restarts.
+/// It's called before the node loads the deploys,
+/// so only the events after the returned event id are sent to it.
+/// A `pull` node fetches the events itself, see [`crate::api::ChangesReq`].
+pub async fn register_node(
+  db_pool: &MySqlPool,
+  node_id: &str,
+  url: &str,
+  pull: bool,
+) -> Result<i64> {
+  sqlx::query!(
+    "INSERT INTO data_plane_nodes (id, url, pull, first_event_id, synced_event_id, heartbeat_at) VALUES (?, ?, ?, (SELECT COALESCE(MAX(id), 0) FROM deploy_events), 0, CURRENT_TIMESTAMP(3)) ON DUPLICATE KEY UPDATE url = VALUES(url), pull = VALUES(pull), first_event_id = VALUES(first_event_id), synced_event_id = VALUES(synced_event_id), heartbeat_at = VALUES(heartbeat_at)",
+    node_id,
+    url,
+    pull
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to insert into data_plane_nodes table")?;
+  let node = sqlx::query!(
+    "SELECT first_event_id FROM data_plane_nodes WHERE id = ?",
+    node_id
+  )
+  .fetch_one(db_pool)
+  .await
+  .context("Failed to query data_plane_nodes table")?;
+  Ok(node.first_event_id)
+}
+
+pub async fn heartbeat(db_pool: &MySqlPool, node_id: &str) -> Result<()> {
+  sqlx::query!(
+    "UPDATE data_plane_nodes SET heartbeat_at = CURRENT_TIMESTAMP(3) WHERE id = ?",
+    node_id
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to update data_plane_nodes table")?;
+  Ok(())
+}
+
+/// [`sync_node`] records the events applied by a pull node,
+/// it also counts as a heartbeat.
+pub async fn sync_node(
+  db_pool: &MySqlPool,
+  node_id: &str,
+  synced_event_id: i64,
+) -> Result<()> {
+  sqlx::query!(
+    "UPDATE data_plane_nodes SET synced_event_id = ?, heartbeat_at = CURRENT_TIMESTAMP(3) WHERE id = ?",
+    synced_event_id,
+    node_id
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to update data_plane_nodes table")?;
+  Ok(())
+}
+
+/// [`push_nodes`] returns the nodes events are pushed to,
+/// which are not pull nodes and have a heartbeat in [`NODE_TIMEOUT`].
+pub async fn push_nodes(db_pool: &MySqlPool) -> Result<Vec<Node>> {
+  let nodes = sqlx::query!(
+    "SELECT id, url, first_event_id FROM data_plane_nodes WHERE pull = false AND heartbeat_at > DATE_SUB(CURRENT_TIMESTAMP(3), INTERVAL ? SECOND) ORDER BY id",
+    NODE_TIMEOUT.as_secs()
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query data_plane_nodes table")?;
+  Ok(
+    nodes
+      .into_iter()
+      .map(|n| Node {
+        id: n.id,
+        url: n.url,
+        first_event_id: n.first_event_id,
+      })
+      .collect(),
+  )
+}
+
+/// [`place_env`] places an env on `node_ids`,
+/// an empty `node_ids` places it on all nodes.
+/// The nodes pick up the change when they reconcile.
+pub async fn place_env<'c>(
+  mut txn: Transaction<'c, MySql>,
+  env_id: &str,
+  node_ids: &[String],
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  sqlx::query!("SELECT id FROM envs WHERE id = ? FOR UPDATE", env_id)
+    .fetch_optional(txn.deref_mut())
+    .await
+    .context("Failed to query envs table")?
+    .ok_or(ApiError::EnvNotFound(env_id.to_string()))?;
+
+  sqlx::query!("DELETE FROM env_placements WHERE env_id = ?", env_id)
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to delete from env_placements table")?;
+  for node_id in node_ids.iter() {
+    sqlx::query!("SELECT id FROM data_plane_nodes WHERE id = ?", node_id)
+      .fetch_optional(txn.deref_mut())
+      .await
+      .context("Failed to query data_plane_nodes table")?
+      .ok_or(ApiError::NodeNotFound(node_id.to_string()))?;
+    sqlx::query!(
+      "INSERT IGNORE INTO env_placements (env_id, node_id) VALUES (?, ?)",
+      env_id,
+      node_id
+    )
+    .execute(txn.deref_mut())
+    .await
+    .context("Failed to insert into env_placements table")?;
+  }
+  Ok(txn)
+}
+
+/// [`Placements`] maps an env to the nodes it's placed on,
+/// envs not in it are placed on all nodes.
+pub(crate) struct Placements(HashMap<String, HashSet<String>>);
+
+impl Placements {
+  pub(crate) async fn load(db_pool: &MySqlPool) -> Result<Self> {
+    let rows = sqlx::query!("SELECT env_id, node_id FROM env_placements")
+      .fetch_all(db_pool)
+      .await
+      .context("Failed to query env_placements table")?;
+    let mut placements: HashMap<String, HashSet<String>> = HashMap::new();
+    for r in rows.into_iter() {
+      placements.entry(r.env_id).or_default().insert(r.node_id);
+    }
+    Ok(Placements(placements))
+  }
+
+  /// [`Placements::is_placed`] is always true without a `node_id`.
+  pub(crate) fn is_placed(&self, env_id: &str, node_id: Option<&str>) -> bool {
+    match (node_id, self.0.get(env_id)) {
+      (Some(node_id), Some(nodes)) => nodes.contains(node_id),
+      _ => true,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_placed() {
+    let mut placements = HashMap::new();
+    placements.insert(
+      "env1".to_string(),
+      HashSet::from(["node1".to_string(), "node2".to_string()]),
+    );
+    let placements = Placements(placements);
+    assert!(placements.is_placed("env1", Some("node1")));
+    assert!(!placements.is_placed("env1", Some("node3")));
+    assert!(placements.is_placed("env1", None));
+    // an env without placement is on all nodes.
+    assert!(placements.is_placed("env2", Some("node3")));
+  }
+}
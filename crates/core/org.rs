@@ -0,0 +1,182 @@
+use crate::api::{ApiError, MemberInfo, OrgInfo, Role};
+use anyhow::Context;
+use sqlx::{MySql, MySqlPool, Transaction};
+use std::ops::DerefMut;
+
+/// [`new_org`] creates an org with `owner_id` as its first member.
+pub async fn new_org<'c>(
+  mut txn: Transaction<'c, MySql>,
+  org_id: &str,
+  owner_id: &str,
+) -> Result<(OrgInfo, Transaction<'c, MySql>), ApiError> {
+  let r = sqlx::query!(
+    "INSERT IGNORE INTO organizations (id, owner_id) VALUES (?, ?)",
+    org_id,
+    owner_id
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to insert into organizations table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::OrgAlreadyExists(org_id.to_string()));
+  }
+  sqlx::query!(
+    "INSERT INTO org_members (org_id, user_id, role) VALUES (?, ?, ?)",
+    org_id,
+    owner_id,
+    Role::Owner.as_str()
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to insert into org_members table")?;
+  let org = OrgInfo {
+    id: org_id.to_string(),
+    owner_id: owner_id.to_string(),
+  };
+  Ok((org, txn))
+}
+
+pub async fn get_org(
+  db_pool: &MySqlPool,
+  org_id: &str,
+) -> Result<OrgInfo, ApiError> {
+  let org = sqlx::query!(
+    "SELECT id, owner_id FROM organizations WHERE id = ?",
+    org_id
+  )
+  .fetch_optional(db_pool)
+  .await
+  .context("Failed to query organizations table")?
+  .ok_or(ApiError::OrgNotFound(org_id.to_string()))?;
+  Ok(OrgInfo {
+    id: org.id,
+    owner_id: org.owner_id,
+  })
+}
+
+pub async fn list_members(
+  db_pool: &MySqlPool,
+  org_id: &str,
+) -> Result<Vec<MemberInfo>, ApiError> {
+  let members = sqlx::query!(
+    "SELECT user_id, role, created_at FROM org_members WHERE org_id = ? ORDER BY created_at",
+    org_id
+  )
+  .fetch_all(db_pool)
+  .await
+  .context("Failed to query org_members table")?;
+  let mut infos = vec![];
+  for m in members.into_iter() {
+    infos.push(MemberInfo {
+      user_id: m.user_id,
+      role: Role::parse(m.role.as_str())?,
+      created_at: m.created_at.assume_utc(),
+    });
+  }
+  Ok(infos)
+}
+
+pub async fn add_member(
+  db_pool: &MySqlPool,
+  org_id: &str,
+  user_id: &str,
+  role: Role,
+) -> Result<(), ApiError> {
+  get_org(db_pool, org_id).await?;
+  let r = sqlx::query!(
+    "INSERT IGNORE INTO org_members (org_id, user_id, role) VALUES (?, ?, ?)",
+    org_id,
+    user_id,
+    role.as_str()
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to insert into org_members table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::MemberAlreadyExists(user_id.to_string()));
+  }
+  Ok(())
+}
+
+/// [`set_member_role`] takes effect on the member's next request,
+/// the role of the org owner can't be changed.
+pub async fn set_member_role(
+  db_pool: &MySqlPool,
+  org_id: &str,
+  user_id: &str,
+  role: Role,
+) -> Result<(), ApiError> {
+  let org = get_org(db_pool, org_id).await?;
+  if org.owner_id == user_id {
+    return Err(ApiError::PermissionDenied(
+      "the role of the org owner can't be changed".to_string(),
+    ));
+  }
+  let r = sqlx::query!(
+    "UPDATE org_members SET role = ? WHERE org_id = ? AND user_id = ?",
+    role.as_str(),
+    org_id,
+    user_id
+  )
+  .execute(db_pool)
+  .await
+  .context("Failed to update org_members table")?;
+  if r.rows_affected() == 0 {
+    let exists = sqlx::query!(
+      "SELECT id FROM org_members WHERE org_id = ? AND user_id = ?",
+      org_id,
+      user_id
+    )
+    .fetch_optional(db_pool)
+    .await
+    .context("Failed to query org_members table")?;
+    // the role is not changed if it's the same.
+    if exists.is_none() {
+      return Err(ApiError::MemberNotFound(user_id.to_string()));
+    }
+  }
+  Ok(())
+}
+
+/// [`remove_member`] also revokes the member's api keys,
+/// the org owner can't be removed.
+pub async fn remove_member<'c>(
+  mut txn: Transaction<'c, MySql>,
+  org_id: &str,
+  user_id: &str,
+) -> Result<Transaction<'c, MySql>, ApiError> {
+  let org = sqlx::query!(
+    "SELECT owner_id FROM organizations WHERE id = ? FOR UPDATE",
+    org_id
+  )
+  .fetch_optional(txn.deref_mut())
+  .await
+  .context("Failed to query organizations table")?
+  .ok_or(ApiError::OrgNotFound(org_id.to_string()))?;
+  if org.owner_id == user_id {
+    return Err(ApiError::PermissionDenied(
+      "the org owner can't be removed".to_string(),
+    ));
+  }
+
+  let r = sqlx::query!(
+    "DELETE FROM org_members WHERE org_id = ? AND user_id = ?",
+    org_id,
+    user_id
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to delete from org_members table")?;
+  if r.rows_affected() == 0 {
+    return Err(ApiError::MemberNotFound(user_id.to_string()));
+  }
+  sqlx::query!(
+    "UPDATE api_keys SET revoked_at = CURRENT_TIMESTAMP(3) WHERE org_id = ? AND user_id = ? AND revoked_at IS NULL",
+    org_id,
+    user_id
+  )
+  .execute(txn.deref_mut())
+  .await
+  .context("Failed to update api_keys table")?;
+  Ok(txn)
+}
@@ -1,14 +1,11 @@
-use crate::api::{EnvInfo, ProjectInfo};
-use crate::code::control::deploy_var;
-use crate::env_vars::{Var, VarList};
+use crate::api::ProjectInfo;
+use crate::env::{drop_env, Env, DEFAULT_ENV_NAME};
 use crate::plugin::{plugin_env_id, plugin_project_id};
 use crate::{EnvId, OrgId, ProjectId};
 use anyhow::{Context, Result};
-use darx_db::save_tenant_db;
 use darx_db::TenantDBInfo;
 use darx_utils::new_nano_id;
 use sqlx::{MySql, MySqlPool, Transaction};
-use std::env;
 use std::ops::DerefMut;
 
 pub struct Project {
@@ -16,14 +13,9 @@ pub struct Project {
   proj_name: String,
   plugin_name: Option<String>,
   org_id: OrgId,
-  env_id: EnvId,
-  env_name: String,
-  db_info: Option<TenantDBInfo>,
-  var_list: VarList,
+  env: Env,
 }
 
-const DEFAULT_ENV_NAME: &str = "production";
-
 impl Project {
   pub async fn list_proj_info(
     org_id: &str,
@@ -36,19 +28,6 @@ impl Project {
 
     let mut proj_infos = vec![];
     for proj in projects.iter() {
-      let envs =
-        sqlx::query!("SELECT id, name FROM envs WHERE project_id = ?", proj.id)
-          .fetch_all(pool)
-          .await?;
-
-      let mut env_infos = vec![];
-      for env in envs.iter() {
-        env_infos.push(EnvInfo {
-          id: env.id.clone(),
-          name: env.name.clone(),
-        });
-      }
-
       proj_infos.push(ProjectInfo {
         id: proj.id.clone(),
         name: proj.name.clone(),
@@ -59,31 +38,7 @@ impl Project {
 
   pub fn new_tenant_proj(org_id: &str, name: &str) -> Self {
     let mut proj = Project::new_minimal_proj(org_id, name);
-    let env_id = proj.env_id();
-    let db_host =
-      env::var("DATA_PLANE_DB_HOST").expect("DATA_PLANE_DB_HOST not set");
-    let db_port =
-      env::var("DATA_PLANE_DB_PORT").expect("DATA_PLANE_DB_PORT not set");
-    let db_user = env_id.clone();
-    let db_name = format!("dx_{}", env_id);
-
-    // todo: encrypt this.
-    let db_password = new_nano_id();
-    let db_info = TenantDBInfo {
-      host: db_host.clone(),
-      port: db_port.parse::<u16>().expect("Failed to parse db port"),
-      user: db_user.to_string(),
-      password: db_password.clone(),
-      database: db_name.clone(),
-    };
-
-    let var_list = VarList::new_env_vars(
-      env_id,
-      &vec![Var::new("DX_DB_NAME", format!("dx_{}", env_id).as_str())],
-    );
-
-    proj.db_info = Some(db_info);
-    proj.var_list = var_list;
+    proj.env = Env::new_tenant_env(proj.id.as_str(), DEFAULT_ENV_NAME);
     proj
   }
 
@@ -91,7 +46,8 @@ impl Project {
     let mut proj = Project::new_minimal_proj(org_id, plugin_name);
     proj.plugin_name = Some(plugin_name.to_string());
     proj.id = plugin_project_id(plugin_name);
-    proj.env_id = plugin_env_id(plugin_name);
+    proj.env.set_project_id(proj.id.as_str());
+    proj.env.set_id(plugin_env_id(plugin_name).as_str());
     proj
   }
 
@@ -104,15 +60,15 @@ impl Project {
   }
 
   pub fn env_id(&self) -> &EnvId {
-    &self.env_id
+    self.env.id()
   }
 
   pub fn env_name(&self) -> &str {
-    &self.env_name
+    self.env.name()
   }
 
   pub fn db_info(&self) -> &Option<TenantDBInfo> {
-    &self.db_info
+    self.env.db_info()
   }
 
   pub async fn save<'c>(
@@ -127,18 +83,10 @@ impl Project {
     )
     .await?;
 
-    let mut txn =
-      save_env(self.id.as_str(), self.env_id.as_str(), "dev", txn).await?;
-
-    if let Some(db_info) = &self.db_info {
-      save_tenant_db(&mut txn, self.env_id.as_str(), db_info).await?;
-    }
-
-    let txn =
-      save_default_env_vars(txn, self.env_id.as_str(), &self.var_list).await?;
+    let txn = self.env.save(txn).await?;
 
     let txn = if let Some(plugin_name) = &self.plugin_name {
-      save_plugin(txn, plugin_name, self.env_id.as_str()).await?
+      save_plugin(txn, plugin_name, self.env.id().as_str()).await?
     } else {
       txn
     };
@@ -167,8 +115,15 @@ impl Project {
   }
 
   pub async fn drop(&self, pool: &MySqlPool) -> Result<()> {
-    let txn = pool.begin().await?;
-    let txn = drop_env(txn, self.env_id.as_str()).await?;
+    let mut txn = pool.begin().await?;
+    let envs =
+      sqlx::query!("SELECT id FROM envs WHERE project_id = ?", self.id)
+        .fetch_all(txn.deref_mut())
+        .await
+        .context("Failed to query envs table")?;
+    for env in envs.iter() {
+      txn = drop_env(txn, env.id.as_str()).await?;
+    }
     let txn = drop_tenant_project(txn, self.id.as_str()).await?;
     let txn = if let Some(plugin_name) = &self.plugin_name {
       drop_plugin(txn, plugin_name).await?
@@ -181,16 +136,14 @@ impl Project {
 
   fn new_minimal_proj(ord_id: &str, proj_name: &str) -> Self {
     let id = new_nano_id();
-    let env_id = new_nano_id();
+    let env =
+      Env::new_minimal_env(id.as_str(), new_nano_id(), DEFAULT_ENV_NAME);
     Project {
       id,
       proj_name: proj_name.to_string(),
       plugin_name: None,
       org_id: ord_id.to_string(),
-      env_id: env_id.clone(),
-      env_name: DEFAULT_ENV_NAME.to_string(),
-      db_info: None,
-      var_list: VarList::new_env_vars(env_id.as_str(), &vec![]),
+      env,
     }
   }
 }
@@ -224,81 +177,6 @@ async fn drop_tenant_project<'c>(
   Ok(txn)
 }
 
-async fn save_env<'c>(
-  project_id: &str,
-  env_id: &str,
-  env_name: &str,
-  mut txn: Transaction<'c, MySql>,
-) -> Result<Transaction<'c, MySql>> {
-  sqlx::query!(
-    "INSERT INTO `envs` (`id`, `project_id`, `name`) VALUES (?, ?, ?)",
-    env_id,
-    project_id,
-    env_name
-  )
-  .execute(txn.deref_mut())
-  .await
-  .context("Failed to insert into envs table")?;
-  Ok(txn)
-}
-
-async fn drop_env<'c>(
-  mut txn: Transaction<'c, MySql>,
-  env_id: &str,
-) -> Result<Transaction<'c, MySql>> {
-  let deploys = sqlx::query!("SELECT id FROM deploys WHERE env_id = ?", env_id)
-    .fetch_all(txn.deref_mut())
-    .await
-    .context("Failed to select from deploys table")?;
-
-  sqlx::query!("DELETE FROM `envs` WHERE `id` = ?", env_id,)
-    .execute(txn.deref_mut())
-    .await
-    .context("Failed to delete from envs table")?;
-
-  sqlx::query!("DELETE FROM `env_vars` WHERE `env_id` = ?", env_id,)
-    .execute(txn.deref_mut())
-    .await
-    .context("Failed to delete from env_vars table")?;
-
-  // codes, deploy_vars, deploys
-  for deploy in deploys.iter() {
-    let deploy_id = &deploy.id;
-    sqlx::query!("DELETE FROM `codes` WHERE `deploy_id` = ?", deploy_id,)
-      .execute(txn.deref_mut())
-      .await
-      .context("Failed to delete from codes table")?;
-
-    sqlx::query!("DELETE FROM `deploy_vars` WHERE `deploy_id` = ?", deploy_id,)
-      .execute(txn.deref_mut())
-      .await
-      .context("Failed to delete from deploy_vars table")?;
-  }
-
-  sqlx::query!("DELETE FROM deploys WHERE env_id = ?", env_id)
-    .execute(txn.deref_mut())
-    .await
-    .context("Failed to delete from deploys table")?;
-
-  Ok(txn)
-}
-
-async fn save_default_env_vars<'c>(
-  mut txn: Transaction<'c, MySql>,
-  env_id: &str,
-  var_list: &VarList,
-) -> Result<Transaction<'c, MySql>> {
-  var_list.save(txn.deref_mut()).await?;
-  let (_, _, txn) = deploy_var(
-    txn,
-    env_id,
-    &Default::default(),
-    &Some("default env deploy".to_string()),
-  )
-  .await?;
-  Ok(txn)
-}
-
 async fn save_plugin<'c>(
   mut txn: Transaction<'c, MySql>,
   plugin_name: &str,
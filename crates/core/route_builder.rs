@@ -50,6 +50,7 @@ mod tests {
     let sig = FunctionSignatureV1 {
       export_name: "default".to_string(),
       param_names: vec![],
+      auth_required: false,
     };
     assert_eq!(
       build_route(None, entry_point, &sig).unwrap().http_path,
@@ -70,6 +71,7 @@ mod tests {
     let sig = FunctionSignatureV1 {
       export_name: "bar".to_string(),
       param_names: vec![],
+      auth_required: false,
     };
     assert_eq!(
       build_route(None, entry_point, &sig).unwrap().http_path,
@@ -90,6 +92,7 @@ mod tests {
     let sig = FunctionSignatureV1 {
       export_name: "default".to_string(),
       param_names: vec![],
+      auth_required: false,
     };
     assert_eq!(
       build_route(None, entry_point, &sig).unwrap().http_path,
@@ -110,6 +113,7 @@ mod tests {
     let sig = FunctionSignatureV1 {
       export_name: "baz".to_string(),
       param_names: vec![],
+      auth_required: false,
     };
     assert_eq!(
       build_route(None, entry_point, &sig).unwrap().http_path,
@@ -129,6 +133,7 @@ mod tests {
     let sig = FunctionSignatureV1 {
       export_name: "default".to_string(),
       param_names: vec![],
+      auth_required: false,
     };
     assert!(build_route(None, entry_point, &sig).is_err());
     assert!(build_route(Some("functions/"), entry_point, &sig).is_err());
@@ -0,0 +1,253 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use darx_utils::new_nano_id;
+use once_cell::sync::OnceCell;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+static ARTIFACT_STORE: OnceCell<Box<dyn ArtifactStore>> = OnceCell::new();
+
+/// [`ArtifactStore`] keeps build artifacts like snapshots,
+/// so they are built once and shared by all data plane nodes.
+#[async_trait]
+pub trait ArtifactStore: Send + Sync {
+  async fn put(&self, key: &str, content: &[u8]) -> Result<()>;
+
+  /// [`ArtifactStore::get`] returns `None` if the key doesn't exist.
+  async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+  async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// [`init_artifact_store`] sets the store used by the data plane,
+/// without it snapshots are only built and kept locally.
+pub fn init_artifact_store(store: Box<dyn ArtifactStore>) -> Result<()> {
+  if ARTIFACT_STORE.set(store).is_err() {
+    bail!("Artifact store is already initialized");
+  }
+  Ok(())
+}
+
+pub(crate) fn artifact_store() -> Option<&'static dyn ArtifactStore> {
+  ARTIFACT_STORE.get().map(|s| s.as_ref())
+}
+
+/// [`artifact_store_from_env`] returns a [`S3ArtifactStore`] if
+/// `DARX_ARTIFACT_STORE=s3`, otherwise a [`LocalArtifactStore`] in `local_dir`.
+pub fn artifact_store_from_env(
+  local_dir: &Path,
+) -> Result<Box<dyn ArtifactStore>> {
+  match env::var("DARX_ARTIFACT_STORE").as_deref() {
+    Ok("s3") => {
+      let bucket =
+        env::var("DARX_S3_BUCKET").context("DARX_S3_BUCKET not set")?;
+      let region =
+        env::var("DARX_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+      let store = S3ArtifactStore::new(
+        bucket.as_str(),
+        region.as_str(),
+        env::var("DARX_S3_ENDPOINT").ok().as_deref(),
+        env::var("DARX_S3_ACCESS_KEY").ok().as_deref(),
+        env::var("DARX_S3_SECRET_KEY").ok().as_deref(),
+      )?;
+      Ok(Box::new(store))
+    }
+    Ok("local") | Err(_) => Ok(Box::new(LocalArtifactStore::new(local_dir))),
+    Ok(other) => bail!("Unknown artifact store: {}", other),
+  }
+}
+
+/// [`snapshot_key`] includes the crate version,
+/// because snapshots can't be loaded by a different v8 build,
+/// and a hash of the absolute envs dir, because snapshots embed
+/// the `file://` urls of the modules in the deploy dir.
+/// Nodes share snapshots only if they use the same envs dir.
+pub(crate) fn snapshot_key(
+  envs_dir: &Path,
+  env_id: &str,
+  deploy_seq: i64,
+) -> Result<String> {
+  let envs_dir = if envs_dir.is_absolute() {
+    envs_dir.to_path_buf()
+  } else {
+    env::current_dir()?.join(envs_dir)
+  };
+  let root_hash = Sha256::digest(envs_dir.to_string_lossy().as_bytes());
+  Ok(format!(
+    "snapshots/{}/{}/{}/{}/SNAPSHOT.bin",
+    env!("CARGO_PKG_VERSION"),
+    &format!("{:x}", root_hash)[..16],
+    env_id,
+    deploy_seq
+  ))
+}
+
+/// [`LocalArtifactStore`] keeps artifacts in a local dir,
+/// it's used by a single data plane node or for testing.
+pub struct LocalArtifactStore {
+  root: PathBuf,
+}
+
+impl LocalArtifactStore {
+  pub fn new(root: &Path) -> Self {
+    LocalArtifactStore {
+      root: root.to_path_buf(),
+    }
+  }
+}
+
+#[async_trait]
+impl ArtifactStore for LocalArtifactStore {
+  async fn put(&self, key: &str, content: &[u8]) -> Result<()> {
+    let path = self.root.join(key);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)
+        .await
+        .context("Failed to create artifact dir")?;
+    }
+    // write to a temp file first, so readers never see a partial artifact.
+    let tmp_path = path.with_extension(format!("{}.tmp", new_nano_id()));
+    fs::write(tmp_path.as_path(), content)
+      .await
+      .with_context(|| format!("Failed to write artifact {}", key))?;
+    fs::rename(tmp_path.as_path(), path.as_path())
+      .await
+      .with_context(|| format!("Failed to write artifact {}", key))?;
+    Ok(())
+  }
+
+  async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    let path = self.root.join(key);
+    if !path.exists() {
+      return Ok(None);
+    }
+    let content = fs::read(path.as_path())
+      .await
+      .with_context(|| format!("Failed to read artifact {}", key))?;
+    Ok(Some(content))
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+    let path = self.root.join(key);
+    if path.exists() {
+      fs::remove_file(path.as_path())
+        .await
+        .with_context(|| format!("Failed to delete artifact {}", key))?;
+    }
+    Ok(())
+  }
+}
+
+/// [`S3ArtifactStore`] keeps artifacts in a S3 compatible bucket,
+/// an `endpoint` like `http://127.0.0.1:9000` is used for MinIO.
+pub struct S3ArtifactStore {
+  bucket: Bucket,
+}
+
+impl S3ArtifactStore {
+  pub fn new(
+    bucket: &str,
+    region: &str,
+    endpoint: Option<&str>,
+    access_key: Option<&str>,
+    secret_key: Option<&str>,
+  ) -> Result<Self> {
+    let credentials =
+      Credentials::new(access_key, secret_key, None, None, None)
+        .context("Failed to load s3 credentials")?;
+    let (region, path_style) = match endpoint {
+      Some(endpoint) => (
+        Region::Custom {
+          region: region.to_string(),
+          endpoint: endpoint.to_string(),
+        },
+        true,
+      ),
+      None => (region.parse().context("Invalid s3 region")?, false),
+    };
+    let mut bucket = Bucket::new(bucket, region, credentials)
+      .context("Failed to create s3 bucket")?;
+    if path_style {
+      bucket = bucket.with_path_style();
+    }
+    Ok(S3ArtifactStore { bucket })
+  }
+}
+
+#[async_trait]
+impl ArtifactStore for S3ArtifactStore {
+  async fn put(&self, key: &str, content: &[u8]) -> Result<()> {
+    let rsp = self
+      .bucket
+      .put_object(key, content)
+      .await
+      .with_context(|| format!("Failed to put artifact {}", key))?;
+    if rsp.status_code() != 200 {
+      bail!(
+        "Failed to put artifact {}, status: {}",
+        key,
+        rsp.status_code()
+      );
+    }
+    Ok(())
+  }
+
+  async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    let rsp = self
+      .bucket
+      .get_object(key)
+      .await
+      .with_context(|| format!("Failed to get artifact {}", key))?;
+    match rsp.status_code() {
+      200 => Ok(Some(rsp.to_vec())),
+      404 => Ok(None),
+      status => bail!("Failed to get artifact {}, status: {}", key, status),
+    }
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+    let rsp = self
+      .bucket
+      .delete_object(key)
+      .await
+      .with_context(|| format!("Failed to delete artifact {}", key))?;
+    if !matches!(rsp.status_code(), 200 | 204 | 404) {
+      bail!(
+        "Failed to delete artifact {}, status: {}",
+        key,
+        rsp.status_code()
+      );
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_local_artifact_store() -> Result<()> {
+    let root = env::temp_dir().join(new_nano_id());
+    let store = LocalArtifactStore::new(root.as_path());
+    let key = snapshot_key(root.as_path(), "env1", 1)?;
+    assert_ne!(key, snapshot_key(Path::new("/other"), "env1", 1)?);
+
+    assert_eq!(None, store.get(key.as_str()).await?);
+    store.put(key.as_str(), b"snapshot").await?;
+    assert_eq!(Some(b"snapshot".to_vec()), store.get(key.as_str()).await?);
+    store.put(key.as_str(), b"snapshot2").await?;
+    assert_eq!(Some(b"snapshot2".to_vec()), store.get(key.as_str()).await?);
+    store.delete(key.as_str()).await?;
+    assert_eq!(None, store.get(key.as_str()).await?);
+    // deleting a missing key is fine.
+    store.delete(key.as_str()).await?;
+
+    fs::remove_dir_all(root).await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,181 @@
+use actix_web::http::header::{
+  HeaderName, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+  ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+  ACCESS_CONTROL_MAX_AGE, VARY,
+};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::api::CorsConfig;
+
+/// The headers added to a response allowed by a CORS policy.
+pub type CorsHeaders = Vec<(HeaderName, String)>;
+
+// CORS maps an env to its policy, the envs without one use the default.
+static CORS: Lazy<RwLock<HashMap<String, CorsConfig>>> =
+  Lazy::new(Default::default);
+
+/// [`set_cors`] sets the policy of the env, the default one if none.
+pub fn set_cors(env_id: &str, cors: Option<&CorsConfig>) {
+  let mut all = CORS.write().unwrap();
+  match cors {
+    Some(cors) => all.insert(env_id.to_string(), cors.clone()),
+    None => all.remove(env_id),
+  };
+}
+
+pub(crate) fn remove_cors(env_id: &str) {
+  CORS.write().unwrap().remove(env_id);
+}
+
+pub(crate) fn replace_cors(all: HashMap<String, CorsConfig>) {
+  *CORS.write().unwrap() = all;
+}
+
+pub fn find_cors(env_id: &str) -> CorsConfig {
+  CORS
+    .read()
+    .unwrap()
+    .get(env_id)
+    .cloned()
+    .unwrap_or_default()
+}
+
+/// [`cors_headers`] returns the headers of a response to `origin`,
+/// none if the origin is not allowed.
+pub fn cors_headers(cors: &CorsConfig, origin: &str) -> Option<CorsHeaders> {
+  let any_origin = cors.allowed_origins.iter().any(|o| o == "*");
+  let allowed = any_origin
+    || cors
+      .allowed_origins
+      .iter()
+      .any(|o| o.eq_ignore_ascii_case(origin));
+  if !allowed {
+    return None;
+  }
+  let mut headers = vec![];
+  if any_origin && !cors.allow_credentials {
+    headers.push((ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()));
+  } else {
+    headers.push((ACCESS_CONTROL_ALLOW_ORIGIN, origin.to_string()));
+    headers.push((VARY, "Origin".to_string()));
+  }
+  if cors.allow_credentials {
+    headers.push((ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".to_string()));
+  }
+  Some(headers)
+}
+
+/// [`preflight_headers`] returns the headers of a response to a preflight
+/// request of `method` with the comma separated `request_headers`,
+/// none if the request is not allowed.
+pub fn preflight_headers(
+  cors: &CorsConfig,
+  origin: &str,
+  method: &str,
+  request_headers: Option<&str>,
+) -> Option<CorsHeaders> {
+  let mut headers = cors_headers(cors, origin)?;
+  let method_allowed = cors
+    .allowed_methods
+    .iter()
+    .any(|m| m == "*" || m.eq_ignore_ascii_case(method));
+  if !method_allowed {
+    return None;
+  }
+  let any_header = cors.allowed_headers.iter().any(|h| h == "*");
+  let request_headers = request_headers
+    .unwrap_or_default()
+    .split(',')
+    .map(str::trim)
+    .filter(|h| !h.is_empty())
+    .collect::<Vec<_>>();
+  let headers_allowed = any_header
+    || request_headers.iter().all(|h| {
+      cors
+        .allowed_headers
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(h))
+    });
+  if !headers_allowed {
+    return None;
+  }
+
+  headers.push((ACCESS_CONTROL_ALLOW_METHODS, method.to_string()));
+  if !request_headers.is_empty() {
+    headers.push((ACCESS_CONTROL_ALLOW_HEADERS, request_headers.join(", ")));
+  }
+  if let Some(max_age) = cors.max_age {
+    headers.push((ACCESS_CONTROL_MAX_AGE, max_age.to_string()));
+  }
+  Some(headers)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header<'a>(
+    headers: &'a CorsHeaders,
+    name: &HeaderName,
+  ) -> Option<&'a str> {
+    headers
+      .iter()
+      .find(|(n, _)| n == name)
+      .map(|(_, v)| v.as_str())
+  }
+
+  #[test]
+  fn test_default_cors() {
+    let cors = CorsConfig::default();
+    let headers = cors_headers(&cors, "https://a.com").unwrap();
+    assert_eq!(Some("*"), header(&headers, &ACCESS_CONTROL_ALLOW_ORIGIN));
+    let headers = preflight_headers(
+      &cors,
+      "https://a.com",
+      "POST",
+      Some("content-type, x-custom"),
+    )
+    .unwrap();
+    assert_eq!(
+      Some("content-type, x-custom"),
+      header(&headers, &ACCESS_CONTROL_ALLOW_HEADERS)
+    );
+  }
+
+  #[test]
+  fn test_env_cors() {
+    let cors = CorsConfig {
+      allowed_origins: vec!["https://app.example.com".to_string()],
+      allowed_methods: vec!["POST".to_string()],
+      allowed_headers: vec!["Authorization".to_string()],
+      allow_credentials: true,
+      max_age: Some(600),
+    };
+    assert!(cors_headers(&cors, "https://other.com").is_none());
+    let headers = cors_headers(&cors, "https://app.example.com").unwrap();
+    assert_eq!(
+      Some("https://app.example.com"),
+      header(&headers, &ACCESS_CONTROL_ALLOW_ORIGIN)
+    );
+    assert_eq!(
+      Some("true"),
+      header(&headers, &ACCESS_CONTROL_ALLOW_CREDENTIALS)
+    );
+
+    let origin = "https://app.example.com";
+    let headers =
+      preflight_headers(&cors, origin, "POST", Some("authorization")).unwrap();
+    assert_eq!(Some("600"), header(&headers, &ACCESS_CONTROL_MAX_AGE));
+    assert!(preflight_headers(&cors, origin, "PUT", None).is_none());
+    assert!(
+      preflight_headers(&cors, origin, "POST", Some("x-custom")).is_none()
+    );
+
+    set_cors("env1", Some(&cors));
+    assert_eq!(cors, find_cors("env1"));
+    set_cors("env1", None);
+    assert_eq!(CorsConfig::default(), find_cors("env1"));
+  }
+}
@@ -1,31 +1,47 @@
 use anyhow::{bail, Context, Result};
-use darx_db::{add_tenant_db_info, TenantDBInfo};
+use darx_db::{add_tenant_db_info, remove_tenant_db_info, TenantDBInfo};
+use darx_utils::new_nano_id;
 use dashmap::DashMap;
 use deno_core::{serde_v8, v8};
 use futures::TryStreamExt;
 use once_cell::sync::Lazy;
 use patricia_tree::StringPatriciaMap;
-use sqlx::MySqlExecutor;
+use sqlx::{MySqlExecutor, MySqlPool};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
 use time::{PrimitiveDateTime, UtcOffset};
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::OnceCell;
 use tokio::time::Instant;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use darx_isolate_runtime::{build_snapshot, DarxIsolate};
 
-use crate::api::ApiError;
+use crate::api::{ApiError, DomainInfo};
+use crate::code::blob::content_hash;
+use crate::env_vars::secret::decrypt_secret;
+use crate::node::Placements;
+use crate::tenants::artifact::{artifact_store, snapshot_key};
 use crate::tenants::cache::LruCache;
+use crate::tenants::cors::{remove_cors, replace_cors};
+use crate::tenants::domain::{remove_domains, replace_domains};
+use crate::tenants::limit::{
+  find_egress, find_fetch_limits, remove_limits, replace_limits,
+};
 use crate::{
   plugin, unique_js_export, Code, DeploySeq, HttpRoute, REGISTRY_FILE_NAME,
 };
 
+/// The dir under `envs_dir` holding code contents by hash.
+const BLOBS_DIR: &str = ".blobs";
+const BLOB_GRACE_PERIOD: Duration = Duration::from_secs(600);
+
 //TODO lru size should be configured
 type SnapshotCache = LruCache<PathBuf, Box<[u8]>, 100>;
 
@@ -55,6 +71,11 @@ static GLOBAL_VARS: Lazy<DashMap<String, Vec<VarDeploy>>> =
 // PLUGIN_REGISTRY maps a plugin's name to its env_id.
 static PLUGIN_REGISTRY: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
 
+// LOADED_DEPLOYS tracks the deploys whose codes and snapshot are on disk,
+// concurrent first invocations of a deploy wait for the same load.
+type LoadedDeploys = DashMap<(String, DeploySeq), Arc<OnceCell<()>>>;
+static LOADED_DEPLOYS: Lazy<LoadedDeploys> = Lazy::new(DashMap::new);
+
 pub(crate) const SNAPSHOT_FILE: &str = "SNAPSHOT.bin";
 
 pub async fn add_plugin_deploy(
@@ -94,7 +115,17 @@ pub async fn add_code_deploy(
   for r in http_routes {
     routes.http_routes.insert(r.http_path.clone(), r.clone());
   }
-  add_code_files(env_id, deploy_seq, envs_dir, codes).await?;
+  let loaded = LOADED_DEPLOYS
+    .get(&(env_id.to_string(), deploy_seq))
+    .map(|l| l.initialized())
+    .unwrap_or(false);
+  if !loaded {
+    add_code_files(env_id, deploy_seq, envs_dir, codes).await?;
+    LOADED_DEPLOYS.insert(
+      (env_id.to_string(), deploy_seq),
+      Arc::new(OnceCell::new_with(Some(()))),
+    );
+  }
   add_route(routes);
 
   info!(
@@ -121,16 +152,69 @@ pub async fn add_var_deploy(
   let mut entry = GLOBAL_VARS
     .entry(env_id.to_string())
     .or_insert_with(|| Vec::new());
+  // a deploy delivered again replaces the existing one.
+  entry.retain(|d| d.deploy_seq != deploy_seq);
   // newest deploy_seq stores in the front of the array
   entry.insert(0, vars);
   entry.sort_by(|a, b| b.deploy_seq.cmp(&a.deploy_seq));
   Ok(())
 }
 
+/// [`init_deploys`] only loads routes and vars on startup, the codes and
+/// snapshot of a deploy are loaded on its first invocation,
+/// except for the latest deploys of the `prewarm_envs` most recently
+/// deployed envs.
+/// Only the envs placed on `node_id` are loaded, or all envs without it.
 pub async fn init_deploys(
   envs_dir: &Path,
   pool: &sqlx::MySqlPool,
+  node_id: Option<&str>,
+  prewarm_envs: u32,
+) -> Result<()> {
+  load_deploys(pool, node_id).await?;
+  if prewarm_envs > 0 {
+    prewarm_deploys(envs_dir, pool, prewarm_envs).await?;
+  }
+  Ok(())
+}
+
+/// [`reconcile_deploys`] makes the data plane converge on the control db,
+/// the deploys missed are loaded, and the envs deleted or no longer placed on
+/// `node_id` are removed.
+pub async fn reconcile_deploys(
+  envs_dir: &Path,
+  pool: &MySqlPool,
+  node_id: Option<&str>,
 ) -> Result<()> {
+  // envs are only added after committed, so the ones seen here
+  // but not in the db are deleted.
+  let env_ids: HashSet<String> = GLOBAL_ROUTER
+    .iter()
+    .map(|e| e.key().clone())
+    .chain(GLOBAL_VARS.iter().map(|e| e.key().clone()))
+    .collect();
+  load_deploys(pool, node_id).await?;
+
+  let placements = Placements::load(pool).await?;
+  let db_env_ids: HashSet<String> = sqlx::query!("SELECT id FROM envs")
+    .fetch_all(pool)
+    .await
+    .context("Failed to query envs table")?
+    .into_iter()
+    .map(|e| e.id)
+    .filter(|env_id| placements.is_placed(env_id, node_id))
+    .collect();
+  for env_id in env_ids.difference(&db_env_ids) {
+    remove_env(envs_dir, env_id).await?;
+  }
+  Ok(())
+}
+
+/// [`load_deploys`] loads the plugins, routes, vars, tenant dbs, domains,
+/// limits and CORS policies of all deploys, a deploy loaded already is loaded
+/// again with the same result.
+async fn load_deploys(pool: &MySqlPool, node_id: Option<&str>) -> Result<()> {
+  let placements = Placements::load(pool).await?;
   let mut plugins =
     sqlx::query!("SELECT env_id, name FROM plugins").fetch(pool);
   while let Some(plugin) = plugins.try_next().await? {
@@ -154,6 +238,9 @@ pub async fn init_deploys(
   )
   .fetch(pool);
   while let Some(deploy) = deploys.try_next().await? {
+    if !placements.is_placed(deploy.env_id.as_str(), node_id) {
+      continue;
+    }
     let http_route = HttpRoute {
       http_path: deploy.http_path.clone(),
       js_entry_point: deploy.js_entry_point.clone(),
@@ -166,60 +253,36 @@ pub async fn init_deploys(
     add_one_http_route(deploy.env_id.as_str(), deploy.deploy_seq, http_route);
   }
 
-  // setup source code and snapshot in file system.
-  let mut codes = sqlx::query!(
-    "SELECT \
-            deploys.env_id AS env_id, \
-            deploys.deploy_seq AS deploy_seq, \
-            codes.id AS id, \
-            codes.fs_path AS fs_path, \
-            codes.content AS content \
-        FROM \
-            codes INNER JOIN deploys ON deploys.id = codes.deploy_id"
-  )
-  .fetch(pool);
-  let mut map: HashMap<(String, DeploySeq), Vec<Code>> = HashMap::new();
-  while let Some(code) = codes.try_next().await? {
-    map
-      .entry((code.env_id.to_string(), code.deploy_seq))
-      .or_default()
-      .push(Code {
-        fs_path: code.fs_path.clone(),
-        content: String::from_utf8(code.content.clone())?,
-      });
-  }
-
-  for ((env_id, deploy_seq), v) in &map {
-    for b in v {
-      add_single_source_code(envs_dir, env_id, *deploy_seq, b)
-        .await
-        .with_context(|| {
-          format!(
-            "Failed to add code file on startup. env_id: {}, deploy_seq: {}",
-            env_id, deploy_seq,
-          )
-        })?;
-    }
-
-    add_snapshot(envs_dir, env_id, *deploy_seq).await?;
-  }
-
   // setup GLOBAL_VARS
   let mut vars = sqlx::query!(
     "SELECT \
             deploys.env_id AS env_id, \
             deploys.deploy_seq AS deploy_seq, \
             deploy_vars.key AS `key`, \
-            deploy_vars.value AS value \
+            deploy_vars.value AS value, \
+            deploy_vars.is_secret AS is_secret \
         FROM \
             deploy_vars INNER JOIN deploys ON deploys.id = deploy_vars.deploy_id"
   ).fetch(pool);
   while let Some(row) = vars.try_next().await? {
+    if !placements.is_placed(row.env_id.as_str(), node_id) {
+      continue;
+    }
+    let value = if row.is_secret != 0 {
+      decrypt_secret(row.value.as_str()).with_context(|| {
+        format!(
+          "Failed to decrypt var on startup. env_id: {}, deploy_seq: {}, key: {}",
+          row.env_id, row.deploy_seq, row.key
+        )
+      })?
+    } else {
+      row.value
+    };
     add_one_var(
       row.env_id.as_str(),
       row.deploy_seq,
       row.key.as_str(),
-      row.value.as_str(),
+      value.as_str(),
     );
   }
 
@@ -229,6 +292,9 @@ pub async fn init_deploys(
   )
   .fetch(pool);
   while let Some(db) = dbs.try_next().await? {
+    if !placements.is_placed(db.env_id.as_str(), node_id) {
+      continue;
+    }
     let db_info = TenantDBInfo {
       host: db.db_host,
       port: db.db_port as u16,
@@ -239,6 +305,281 @@ pub async fn init_deploys(
     add_tenant_db_info(db.env_id.as_str(), db_info);
   }
 
+  // setup DOMAINS
+  let domains =
+    sqlx::query!("SELECT env_id, hostname, path_prefix FROM domains")
+      .fetch_all(pool)
+      .await
+      .context("Failed to query domains table")?
+      .into_iter()
+      .filter(|d| placements.is_placed(d.env_id.as_str(), node_id))
+      .map(|d| {
+        let domain = DomainInfo {
+          hostname: d.hostname,
+          path_prefix: d.path_prefix,
+        };
+        (d.env_id, domain)
+      })
+      .collect();
+  replace_domains(domains);
+
+  // setup limits
+  let mut limits = HashMap::new();
+  for row in sqlx::query!("SELECT env_id, limits FROM env_limits")
+    .fetch_all(pool)
+    .await
+    .context("Failed to query env_limits table")?
+  {
+    if !placements.is_placed(row.env_id.as_str(), node_id) {
+      continue;
+    }
+    let env_limits = serde_json::from_value(row.limits)
+      .with_context(|| format!("Failed to parse limits of {}", row.env_id))?;
+    limits.insert(row.env_id, env_limits);
+  }
+  replace_limits(limits);
+
+  // setup CORS
+  let mut cors = HashMap::new();
+  for env in sqlx::query!("SELECT id, cors FROM envs WHERE cors IS NOT NULL")
+    .fetch_all(pool)
+    .await
+    .context("Failed to query envs table")?
+  {
+    if !placements.is_placed(env.id.as_str(), node_id) {
+      continue;
+    }
+    if let Some(env_cors) = env.cors {
+      let env_cors = serde_json::from_value(env_cors)
+        .with_context(|| format!("Failed to parse cors of {}", env.id))?;
+      cors.insert(env.id, env_cors);
+    }
+  }
+  replace_cors(cors);
+  Ok(())
+}
+
+/// [`prewarm_deploys`] loads the latest code deploy of the `limit` most
+/// recently deployed envs, a failed env is logged and skipped.
+async fn prewarm_deploys(
+  envs_dir: &Path,
+  pool: &MySqlPool,
+  limit: u32,
+) -> Result<()> {
+  let envs = sqlx::query!(
+    "SELECT env_id FROM deploys GROUP BY env_id ORDER BY MAX(created_at) DESC LIMIT ?",
+    limit
+  )
+  .fetch_all(pool)
+  .await
+  .context("Failed to query deploys table")?;
+  for env in envs.iter() {
+    let deploy_seq = GLOBAL_ROUTER
+      .get(env.env_id.as_str())
+      .and_then(|deploys| deploys.first().map(|d| d.deploy_seq));
+    if let Some(deploy_seq) = deploy_seq {
+      if let Err(e) =
+        load_deploy(envs_dir, pool, env.env_id.as_str(), deploy_seq).await
+      {
+        warn!(
+          env = env.env_id,
+          seq = deploy_seq,
+          "Failed to prewarm deploy: {:?}",
+          e
+        );
+      }
+    }
+  }
+  info!("prewarmed {} envs", envs.len());
+  Ok(())
+}
+
+/// [`load_deploy`] writes the codes and snapshot of a deploy to disk
+/// if they are not loaded yet.
+async fn load_deploy(
+  envs_dir: &Path,
+  pool: &MySqlPool,
+  env_id: &str,
+  deploy_seq: DeploySeq,
+) -> Result<()> {
+  let loaded = LOADED_DEPLOYS
+    .entry((env_id.to_string(), deploy_seq))
+    .or_default()
+    .clone();
+  loaded
+    .get_or_try_init(|| async {
+      let mark = Instant::now();
+      let codes = find_deploy_codes(pool, env_id, deploy_seq).await?;
+      add_code_files(env_id, deploy_seq, envs_dir, &codes).await?;
+      info!(
+        env = env_id,
+        seq = deploy_seq,
+        "loaded deployment, {} codes, took {:#?}",
+        codes.len(),
+        Instant::now().saturating_duration_since(mark)
+      );
+      Ok::<(), anyhow::Error>(())
+    })
+    .await?;
+  Ok(())
+}
+
+async fn find_deploy_codes(
+  pool: &MySqlPool,
+  env_id: &str,
+  deploy_seq: DeploySeq,
+) -> Result<Vec<Code>> {
+  let records = sqlx::query!(
+    "SELECT \
+            codes.fs_path AS fs_path, \
+            code_blobs.content AS content \
+        FROM \
+            deploys \
+            INNER JOIN codes ON codes.deploy_id = deploys.id \
+            INNER JOIN code_blobs ON code_blobs.hash = codes.content_hash \
+        WHERE deploys.env_id = ? AND deploys.deploy_seq = ?",
+    env_id,
+    deploy_seq
+  )
+  .fetch_all(pool)
+  .await
+  .context("Failed to query codes table")?;
+  if records.is_empty() {
+    bail!(
+      "No codes found. env_id: {}, deploy_seq: {}",
+      env_id,
+      deploy_seq
+    );
+  }
+  let mut codes = vec![];
+  for r in records.into_iter() {
+    codes.push(Code {
+      fs_path: r.fs_path,
+      content: String::from_utf8(r.content)
+        .context("Failed to convert code content to string")?,
+    });
+  }
+  Ok(codes)
+}
+
+/// [`remove_env`] drops everything the data plane cached for the env.
+pub async fn remove_env(envs_dir: &Path, env_id: &str) -> Result<()> {
+  GLOBAL_ROUTER.remove(env_id);
+  GLOBAL_VARS.remove(env_id);
+  LOADED_DEPLOYS.retain(|(id, _), _| id != env_id);
+  remove_tenant_db_info(env_id);
+  remove_domains(env_id);
+  remove_limits(env_id);
+  remove_cors(env_id);
+  let env_dir = envs_dir.join(env_id);
+  if env_dir.exists() {
+    fs::remove_dir_all(env_dir.as_path())
+      .await
+      .with_context(|| format!("Failed to remove env dir {:?}", env_dir))?;
+  }
+  info!(env = env_id, "removed env");
+  Ok(())
+}
+
+/// [`remove_stale_deploys`] drops the routes, vars and files of the deploys
+/// deleted by the deploy gc in the control plane.
+/// Deploys newer than the latest one in the db may not be committed yet,
+/// so they are never removed.
+pub async fn remove_stale_deploys(
+  envs_dir: &Path,
+  pool: &MySqlPool,
+) -> Result<()> {
+  let mut env_ids: HashSet<String> = GLOBAL_ROUTER
+    .iter()
+    .map(|e| e.key().clone())
+    .chain(GLOBAL_VARS.iter().map(|e| e.key().clone()))
+    .collect();
+  let mut entries = fs::read_dir(envs_dir).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let name = entry.file_name().to_string_lossy().to_string();
+    if name != BLOBS_DIR && entry.file_type().await?.is_dir() {
+      env_ids.insert(name);
+    }
+  }
+
+  let mut removed = 0;
+  for env_id in env_ids.iter() {
+    let seqs: HashSet<DeploySeq> =
+      sqlx::query!("SELECT deploy_seq FROM deploys WHERE env_id = ?", env_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to query deploys table")?
+        .into_iter()
+        .map(|r| r.deploy_seq)
+        .collect();
+    let max_seq = match seqs.iter().max() {
+      Some(max_seq) => *max_seq,
+      None => continue,
+    };
+    let is_stale = |seq: DeploySeq| seq < max_seq && !seqs.contains(&seq);
+
+    if let Some(mut routes) = GLOBAL_ROUTER.get_mut(env_id) {
+      routes.retain(|d| !is_stale(d.deploy_seq));
+    }
+    if let Some(mut vars) = GLOBAL_VARS.get_mut(env_id) {
+      vars.retain(|d| !is_stale(d.deploy_seq));
+    }
+    LOADED_DEPLOYS.retain(|(id, seq), _| id != env_id || !is_stale(*seq));
+
+    let env_dir = envs_dir.join(env_id);
+    if !env_dir.exists() {
+      continue;
+    }
+    let mut deploy_dirs = fs::read_dir(env_dir.as_path()).await?;
+    while let Some(entry) = deploy_dirs.next_entry().await? {
+      let seq = entry.file_name().to_string_lossy().parse::<DeploySeq>();
+      if let Ok(seq) = seq {
+        if is_stale(seq) {
+          fs::remove_dir_all(entry.path()).await.with_context(|| {
+            format!("Failed to remove deploy dir {:?}", entry.path())
+          })?;
+          if let Some(store) = artifact_store() {
+            store
+              .delete(snapshot_key(envs_dir, env_id, seq)?.as_str())
+              .await?;
+          }
+          removed += 1;
+        }
+      }
+    }
+  }
+  remove_unused_blobs(envs_dir).await?;
+  info!("removed {} stale deploys", removed);
+  Ok(())
+}
+
+/// [`remove_unused_blobs`] removes the blobs not linked by any deploy.
+#[cfg(unix)]
+async fn remove_unused_blobs(envs_dir: &Path) -> Result<()> {
+  use std::os::unix::fs::MetadataExt;
+
+  let blobs_dir = envs_dir.join(BLOBS_DIR);
+  if !blobs_dir.exists() {
+    return Ok(());
+  }
+  let mut entries = fs::read_dir(blobs_dir.as_path()).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let metadata = entry.metadata().await?;
+    // a new blob is not linked until its deploy is written.
+    let is_new = metadata
+      .modified()?
+      .elapsed()
+      .map(|d| d < BLOB_GRACE_PERIOD)
+      .unwrap_or(true);
+    if metadata.nlink() == 1 && !is_new {
+      fs::remove_file(entry.path()).await?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+async fn remove_unused_blobs(_envs_dir: &Path) -> Result<()> {
   Ok(())
 }
 
@@ -298,31 +639,44 @@ pub fn find_vars(env_id: &str) -> Option<HashMap<String, String>> {
 
 pub async fn invoke_function(
   envs_dir: &Path,
+  db_pool: &MySqlPool,
   env_id: &str,
   target_env_id: &str,
   deploy_seq: i64,
   req: serde_json::Value,
+  auth: Option<serde_json::Value>,
   js_entry_point: &str,
   js_export: &str,
   param_names: &Vec<String>,
 ) -> Result<serde_json::Value, ApiError> {
+  load_deploy(envs_dir, db_pool, target_env_id, deploy_seq)
+    .await
+    .map_err(|e| ApiError::DeployNotFound(e.to_string()))?;
   let deploy_dir = find_deploy_dir(envs_dir, target_env_id, deploy_seq)
     .await
     .map_err(|e| ApiError::DeployNotFound(e.to_string()))?;
 
   let snapshot_path = deploy_dir.join(SNAPSHOT_FILE);
+  // the cache is not borrowed across awaits,
+  // other invocations on this thread may use it meanwhile.
   let cache = CACHE.with(Rc::clone);
-  let mut cache = cache.borrow_mut();
-  let cached = cache.get_mut(&snapshot_path);
-  let snapshot = if cached.is_none() {
-    debug!("cache miss, cur size {}", cache.len());
-
-    let snapshot = fs::read(&snapshot_path).await.map_err(ApiError::IoError)?;
-
-    cache.put(snapshot_path.clone(), snapshot.into_boxed_slice());
-    cache.get(&snapshot_path).unwrap().clone()
-  } else {
-    cached.unwrap().clone()
+  let cached = cache.borrow_mut().get_mut(&snapshot_path).cloned();
+  let snapshot = match cached {
+    Some(snapshot) => snapshot,
+    None => {
+      debug!("cache miss, cur size {}", cache.borrow().len());
+
+      let snapshot =
+        read_snapshot(envs_dir, target_env_id, deploy_seq, &snapshot_path)
+          .await
+          .map_err(ApiError::Internal)?
+          .into_boxed_slice();
+
+      cache
+        .borrow_mut()
+        .put(snapshot_path.clone(), snapshot.clone());
+      snapshot
+    }
   };
 
   // We DO NOT use target_env here.
@@ -336,15 +690,19 @@ pub async fn invoke_function(
     snapshot,
   )
   .await;
+  isolate.set_egress(&find_egress(env_id));
+  isolate.set_fetch_limits(&find_fetch_limits(env_id));
 
   let source_code = invoking_code(
     unique_js_export(js_entry_point, js_export),
     param_names.clone(),
     req,
+    auth,
   )?;
   let script_result = isolate
     .js_runtime
     .execute_script("invoke_function", source_code)
+    .map_err(|e| isolate.js_error(e))
     .context("execute script error")
     .map_err(ApiError::FunctionRuntimeError)?;
 
@@ -357,6 +715,7 @@ pub async fn invoke_function(
   {
     Err(_) => Err(ApiError::Timeout),
     Ok(res) => res
+      .map_err(|e| isolate.js_error(e))
       .context("resolve value error")
       .map_err(ApiError::FunctionRuntimeError),
   }?;
@@ -395,6 +754,8 @@ pub async fn save_log<'c>(exe: impl MySqlExecutor<'c>) -> Result<u64> {
 fn add_route(route: RouteDeploy) {
   let env_id = route.env_id.clone();
   let mut entry = GLOBAL_ROUTER.entry(env_id).or_insert_with(|| Vec::new());
+  // a deploy delivered again replaces the existing one.
+  entry.retain(|d| d.deploy_seq != route.deploy_seq);
   // newest deploy_seq stores in the front of the array
   entry.insert(0, route.clone());
   entry.sort_by(|a, b| b.deploy_seq.cmp(&a.deploy_seq));
@@ -431,11 +792,68 @@ async fn add_single_source_code(
     }
   }
 
-  let mut file = File::create(code_file.as_path()).await?;
-  file.write_all(code.content.as_ref()).await?;
+  // the same content is stored once in the blobs dir and
+  // linked into every deploy using it.
+  let blob_file = add_blob(envs_dir, code.content.as_str()).await?;
+  if code_file.exists() {
+    fs::remove_file(code_file.as_path()).await?;
+  }
+  if fs::hard_link(blob_file.as_path(), code_file.as_path())
+    .await
+    .is_err()
+  {
+    fs::copy(blob_file.as_path(), code_file.as_path()).await?;
+  }
   Ok(())
 }
 
+/// [`add_blob`] writes the content to `{envs_dir}/.blobs/{hash}` if it's not
+/// there yet, and returns the blob's path.
+async fn add_blob(envs_dir: &Path, content: &str) -> Result<PathBuf> {
+  let blobs_dir = envs_dir.join(BLOBS_DIR);
+  if !blobs_dir.exists() {
+    fs::create_dir_all(blobs_dir.as_path()).await?;
+  }
+  let hash = content_hash(content);
+  let blob_file = blobs_dir.join(hash.as_str());
+  if blob_file.exists() {
+    return Ok(blob_file);
+  }
+
+  // write to a temp file first, so a crash never leaves a partial blob.
+  let tmp_file = blobs_dir.join(format!("{}.{}.tmp", hash, new_nano_id()));
+  let mut file = File::create(tmp_file.as_path()).await?;
+  file.write_all(content.as_bytes()).await?;
+  file.sync_all().await?;
+  fs::rename(tmp_file.as_path(), blob_file.as_path()).await?;
+  Ok(blob_file)
+}
+
+/// [`read_snapshot`] downloads the snapshot from the artifact store
+/// if it's not on the local disk.
+async fn read_snapshot(
+  envs_dir: &Path,
+  env_id: &str,
+  deploy_seq: i64,
+  snapshot_path: &Path,
+) -> Result<Vec<u8>> {
+  if !snapshot_path.exists() {
+    if let Some(store) = artifact_store() {
+      let key = snapshot_key(envs_dir, env_id, deploy_seq)?;
+      let snapshot = store
+        .get(key.as_str())
+        .await?
+        .with_context(|| format!("Snapshot {} not found", key))?;
+      fs::write(snapshot_path, snapshot.as_slice()).await?;
+      return Ok(snapshot);
+    }
+  }
+  let snapshot = fs::read(snapshot_path)
+    .await
+    .with_context(|| format!("Failed to read {}", snapshot_path.display()))?;
+  Ok(snapshot)
+}
+
 async fn add_snapshot(
   envs_dir: &Path,
   env_id: &str,
@@ -443,6 +861,24 @@ async fn add_snapshot(
 ) -> Result<()> {
   let deploy_dir =
     setup_deploy_dir(envs_dir.as_ref(), env_id, deploy_seq).await?;
+  let snapshot_path = deploy_dir.join(SNAPSHOT_FILE);
+  let key = snapshot_key(envs_dir, env_id, deploy_seq)?;
+  let store = artifact_store();
+
+  // the snapshot is built by the first node and downloaded by the others.
+  if let Some(store) = store {
+    if let Some(snapshot) = store.get(key.as_str()).await? {
+      fs::write(&snapshot_path, snapshot.as_slice()).await?;
+      info!(
+        env = env_id,
+        seq = deploy_seq,
+        "Snapshot downloaded, size: {}",
+        snapshot.len()
+      );
+      return Ok(());
+    }
+  }
+
   let mut mark = Instant::now();
   let snapshot =
     build_snapshot(deploy_dir.as_path(), REGISTRY_FILE_NAME).await?;
@@ -458,8 +894,6 @@ async fn add_snapshot(
 
   mark = Instant::now();
 
-  let snapshot_path = deploy_dir.join(SNAPSHOT_FILE);
-
   fs::write(&snapshot_path, snapshot_slice).await.unwrap();
 
   info!(
@@ -470,6 +904,17 @@ async fn add_snapshot(
     snapshot_path.display(),
   );
 
+  if let Some(store) = store {
+    mark = Instant::now();
+    store.put(key.as_str(), snapshot_slice).await?;
+    info!(
+      env = env_id,
+      seq = deploy_seq,
+      "Snapshot uploaded, took: {:#?}",
+      Instant::now().saturating_duration_since(mark),
+    );
+  }
+
   Ok(())
 }
 
@@ -572,10 +1017,13 @@ async fn find_deploy_dir(
   Ok(path)
 }
 
+/// [`invoking_code`] also exposes the claims of the end user's token
+/// as `Darx.auth`, it's null if the call is not authenticated.
 fn invoking_code(
   func_name: String,
   param_names: Vec<String>,
   param_values: serde_json::Value,
+  auth: Option<serde_json::Value>,
 ) -> Result<String> {
   let vals: Vec<String> = param_names
     .into_iter()
@@ -586,7 +1034,13 @@ fn invoking_code(
         .to_string()
     })
     .collect();
-  Ok(format!("{}({})", func_name, vals.join(", ")))
+  let auth = auth.unwrap_or(serde_json::Value::Null);
+  Ok(format!(
+    "Darx.auth = Object.freeze({});\nDarx.invoke({}, [{}])",
+    auth,
+    func_name,
+    vals.join(", ")
+  ))
 }
 
 fn lookup_plugin(name: &str) -> Option<String> {
@@ -610,8 +1064,24 @@ mod tests {
           "b": "hello",
           "c": 3,
       }),
+      None,
     )?;
     println!("{}", code);
     Ok(())
   }
+
+  #[test]
+  fn test_invoking_code_auth() -> Result<()> {
+    let code = invoking_code(
+      "foo".to_string(),
+      vec!["a".to_string()],
+      json!({"a": 1}),
+      Some(json!({"sub": "alice"})),
+    )?;
+    assert_eq!(
+      code,
+      "Darx.auth = Object.freeze({\"sub\":\"alice\"});\nDarx.invoke(foo, [1])"
+    );
+    Ok(())
+  }
 }
@@ -0,0 +1,130 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::api::DomainInfo;
+
+/// (path_prefix, env_id) of a hostname.
+type DomainRoutes = Vec<(String, String)>;
+
+// DOMAINS maps a custom hostname to the envs it serves,
+// it's replaced as a whole when all deploys are loaded again.
+static DOMAINS: Lazy<RwLock<HashMap<String, DomainRoutes>>> =
+  Lazy::new(Default::default);
+
+/// [`set_domains`] replaces the domains of the env.
+pub fn set_domains(env_id: &str, domains: &[DomainInfo]) {
+  let mut all = DOMAINS.write().unwrap();
+  remove_env_domains(&mut all, env_id);
+  for d in domains.iter() {
+    add_domain(&mut all, env_id, d);
+  }
+}
+
+pub(crate) fn remove_domains(env_id: &str) {
+  remove_env_domains(&mut DOMAINS.write().unwrap(), env_id);
+}
+
+/// [`replace_domains`] takes (env_id, domain) pairs of all envs.
+pub(crate) fn replace_domains(domains: Vec<(String, DomainInfo)>) {
+  let mut new = HashMap::new();
+  for (env_id, d) in domains.iter() {
+    add_domain(&mut new, env_id, d);
+  }
+  *DOMAINS.write().unwrap() = new;
+}
+
+/// [`match_domain`] returns the env of the longest path prefix matching
+/// `func_url` on the hostname, and the function url without the prefix.
+/// The port of `host` is ignored.
+pub fn match_domain(host: &str, func_url: &str) -> Option<(String, String)> {
+  let hostname = host
+    .rsplit_once(':')
+    .map(|(h, _)| h)
+    .unwrap_or(host)
+    .to_lowercase();
+  let all = DOMAINS.read().unwrap();
+  // the prefixes are sorted from the longest.
+  all
+    .get(hostname.as_str())?
+    .iter()
+    .find_map(|(prefix, env_id)| {
+      let rest = if prefix.is_empty() {
+        Some(func_url)
+      } else {
+        func_url
+          .strip_prefix(prefix.as_str())
+          .and_then(|rest| rest.strip_prefix('/'))
+      };
+      rest.map(|rest| (env_id.clone(), rest.to_string()))
+    })
+}
+
+fn add_domain(
+  all: &mut HashMap<String, DomainRoutes>,
+  env_id: &str,
+  domain: &DomainInfo,
+) {
+  let routes = all.entry(domain.hostname.clone()).or_default();
+  routes.retain(|(prefix, _)| prefix != &domain.path_prefix);
+  routes.push((domain.path_prefix.clone(), env_id.to_string()));
+  routes.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+}
+
+fn remove_env_domains(all: &mut HashMap<String, DomainRoutes>, env_id: &str) {
+  all.retain(|_, routes| {
+    routes.retain(|(_, id)| id != env_id);
+    !routes.is_empty()
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn domain(hostname: &str, path_prefix: &str) -> DomainInfo {
+    DomainInfo {
+      hostname: hostname.to_string(),
+      path_prefix: path_prefix.to_string(),
+    }
+  }
+
+  #[test]
+  fn test_match_domain() {
+    set_domains("env1", &[domain("example.com", "")]);
+    set_domains(
+      "env2",
+      &[
+        domain("example.com", "billing"),
+        domain("billing.example.com", ""),
+      ],
+    );
+    assert_eq!(
+      match_domain("example.com", "foo.Hi"),
+      Some(("env1".to_string(), "foo.Hi".to_string()))
+    );
+    assert_eq!(
+      match_domain("Example.com:8080", "billing/charge"),
+      Some(("env2".to_string(), "charge".to_string()))
+    );
+    // only whole segments match the prefix.
+    assert_eq!(
+      match_domain("example.com", "billings"),
+      Some(("env1".to_string(), "billings".to_string()))
+    );
+    assert_eq!(
+      match_domain("billing.example.com", "charge"),
+      Some(("env2".to_string(), "charge".to_string()))
+    );
+    assert_eq!(match_domain("other.com", "foo"), None);
+
+    set_domains("env2", &[]);
+    assert_eq!(match_domain("billing.example.com", "charge"), None);
+    assert_eq!(
+      match_domain("example.com", "billing/charge"),
+      Some(("env1".to_string(), "billing/charge".to_string()))
+    );
+    remove_domains("env1");
+    assert_eq!(match_domain("example.com", "foo"), None);
+  }
+}
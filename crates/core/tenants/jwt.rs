@@ -0,0 +1,303 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::{hmac, signature};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use time::OffsetDateTime;
+use tracing::debug;
+
+use super::deploy::find_vars;
+use crate::api::ApiError;
+
+/// The env vars configuring the end-user auth of an env,
+/// tokens are verified by the shared secret or the keys in the JWKS.
+pub const AUTH_SECRET_VAR: &str = "DARX_AUTH_SECRET";
+/// The content of the JWKS file, like `{"keys": [...]}`.
+pub const AUTH_JWKS_VAR: &str = "DARX_AUTH_JWKS";
+pub const AUTH_ISSUER_VAR: &str = "DARX_AUTH_ISSUER";
+pub const AUTH_AUDIENCE_VAR: &str = "DARX_AUTH_AUDIENCE";
+
+/// The clock skew allowed when checking `exp` and `nbf`.
+const LEEWAY_SECS: i64 = 60;
+
+struct JwtConfig {
+  secret: Option<String>,
+  jwks: Option<Jwks>,
+  issuer: Option<String>,
+  audience: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+  keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+  kty: String,
+  kid: Option<String>,
+  alg: Option<String>,
+  // RSA
+  n: Option<String>,
+  e: Option<String>,
+  // EC
+  crv: Option<String>,
+  x: Option<String>,
+  y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+  alg: String,
+  kid: Option<String>,
+}
+
+/// [`authenticate`] verifies an end user's bearer token with the auth vars
+/// of the env, and returns the claims of the token.
+/// An invalid token is always rejected, a missing one only if `required`.
+pub fn authenticate(
+  env_id: &str,
+  token: Option<&str>,
+  required: bool,
+) -> Result<Option<Value>, ApiError> {
+  let token = match token {
+    Some(token) => token,
+    None if required => return Err(ApiError::AuthError),
+    None => return Ok(None),
+  };
+  let vars = find_vars(env_id).unwrap_or_default();
+  let config = match JwtConfig::from_vars(&vars)? {
+    Some(config) => config,
+    // the env doesn't verify tokens.
+    None if required => return Err(ApiError::AuthError),
+    None => return Ok(None),
+  };
+  let now = OffsetDateTime::now_utc().unix_timestamp();
+  match config.verify(token, now) {
+    Ok(claims) => Ok(Some(claims)),
+    Err(e) => {
+      debug!(env = env_id, "invalid auth token: {:?}", e);
+      Err(ApiError::AuthError)
+    }
+  }
+}
+
+impl JwtConfig {
+  fn from_vars(vars: &HashMap<String, String>) -> Result<Option<Self>> {
+    let secret = vars.get(AUTH_SECRET_VAR).cloned();
+    let jwks = match vars.get(AUTH_JWKS_VAR) {
+      Some(jwks) => Some(
+        serde_json::from_str(jwks)
+          .with_context(|| format!("Invalid {}", AUTH_JWKS_VAR))?,
+      ),
+      None => None,
+    };
+    if secret.is_none() && jwks.is_none() {
+      return Ok(None);
+    }
+    Ok(Some(JwtConfig {
+      secret,
+      jwks,
+      issuer: vars.get(AUTH_ISSUER_VAR).cloned(),
+      audience: vars.get(AUTH_AUDIENCE_VAR).cloned(),
+    }))
+  }
+
+  fn verify(&self, token: &str, now: i64) -> Result<Value> {
+    let (message, sig) = token.rsplit_once('.').context("malformed token")?;
+    let (header, payload) =
+      message.split_once('.').context("malformed token")?;
+    let header: Header = serde_json::from_slice(&decode(header)?)
+      .context("Invalid token header")?;
+    self.verify_signature(&header, message.as_bytes(), &decode(sig)?)?;
+
+    let claims: Value = serde_json::from_slice(&decode(payload)?)
+      .context("Invalid token claims")?;
+    let exp = claims
+      .get("exp")
+      .and_then(Value::as_i64)
+      .context("exp is required")?;
+    if now > exp + LEEWAY_SECS {
+      bail!("token expired");
+    }
+    if let Some(nbf) = claims.get("nbf") {
+      let nbf = nbf.as_i64().context("invalid nbf")?;
+      if now + LEEWAY_SECS < nbf {
+        bail!("token not valid yet");
+      }
+    }
+    if let Some(issuer) = self.issuer.as_deref() {
+      if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+        bail!("invalid issuer");
+      }
+    }
+    if let Some(audience) = self.audience.as_deref() {
+      let matched = match claims.get("aud") {
+        Some(Value::String(aud)) => aud == audience,
+        Some(Value::Array(auds)) => {
+          auds.iter().any(|aud| aud.as_str() == Some(audience))
+        }
+        _ => false,
+      };
+      if !matched {
+        bail!("invalid audience");
+      }
+    }
+    Ok(claims)
+  }
+
+  fn verify_signature(
+    &self,
+    header: &Header,
+    message: &[u8],
+    sig: &[u8],
+  ) -> Result<()> {
+    let alg = header.alg.as_str();
+    // `none` and unknown algorithms are rejected.
+    let hmac_alg = match alg {
+      "HS256" => Some(hmac::HMAC_SHA256),
+      "HS384" => Some(hmac::HMAC_SHA384),
+      "HS512" => Some(hmac::HMAC_SHA512),
+      _ => None,
+    };
+    if let Some(hmac_alg) = hmac_alg {
+      let secret = self.secret.as_deref().context("no secret configured")?;
+      let key = hmac::Key::new(hmac_alg, secret.as_bytes());
+      return hmac::verify(&key, message, sig)
+        .map_err(|_| anyhow!("invalid signature"));
+    }
+
+    let jwk = self.find_jwk(header)?;
+    let verified = match (alg, jwk.kty.as_str()) {
+      ("RS256" | "RS384" | "RS512", "RSA") => {
+        let params = match alg {
+          "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+          "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+          _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+        };
+        let n = decode(jwk.n.as_deref().context("n is required")?)?;
+        let e = decode(jwk.e.as_deref().context("e is required")?)?;
+        signature::RsaPublicKeyComponents { n: &n, e: &e }
+          .verify(params, message, sig)
+      }
+      ("ES256" | "ES384", "EC") => {
+        let (params, crv): (&signature::EcdsaVerificationAlgorithm, _) =
+          match alg {
+            "ES256" => (&signature::ECDSA_P256_SHA256_FIXED, "P-256"),
+            _ => (&signature::ECDSA_P384_SHA384_FIXED, "P-384"),
+          };
+        if jwk.crv.as_deref() != Some(crv) {
+          bail!("key curve doesn't match {}", alg);
+        }
+        // an uncompressed point.
+        let mut point = vec![0x04];
+        point.extend(decode(jwk.x.as_deref().context("x is required")?)?);
+        point.extend(decode(jwk.y.as_deref().context("y is required")?)?);
+        signature::UnparsedPublicKey::new(params, point).verify(message, sig)
+      }
+      _ => bail!("unsupported algorithm {} for key type {}", alg, jwk.kty),
+    };
+    verified.map_err(|_| anyhow!("invalid signature"))
+  }
+
+  fn find_jwk(&self, header: &Header) -> Result<&Jwk> {
+    let jwks = self.jwks.as_ref().context("no jwks configured")?;
+    let jwk = match header.kid.as_deref() {
+      Some(kid) => jwks.keys.iter().find(|k| k.kid.as_deref() == Some(kid)),
+      // a token without kid is only allowed for a single key.
+      None if jwks.keys.len() == 1 => jwks.keys.first(),
+      None => None,
+    }
+    .context("key not found")?;
+    if let Some(alg) = jwk.alg.as_deref() {
+      if alg != header.alg {
+        bail!("key algorithm {} doesn't match {}", alg, header.alg);
+      }
+    }
+    Ok(jwk)
+  }
+}
+
+fn decode(s: &str) -> Result<Vec<u8>> {
+  URL_SAFE_NO_PAD
+    .decode(s)
+    .context("Invalid base64url encoding")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn sign_hs256(secret: &str, header: Value, claims: Value) -> String {
+    let message = format!(
+      "{}.{}",
+      URL_SAFE_NO_PAD.encode(header.to_string()),
+      URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let sig = hmac::sign(&key, message.as_bytes());
+    format!("{}.{}", message, URL_SAFE_NO_PAD.encode(sig.as_ref()))
+  }
+
+  fn config() -> JwtConfig {
+    let mut vars = HashMap::new();
+    vars.insert(AUTH_SECRET_VAR.to_string(), "secret".to_string());
+    vars.insert(AUTH_ISSUER_VAR.to_string(), "darx".to_string());
+    vars.insert(AUTH_AUDIENCE_VAR.to_string(), "app".to_string());
+    JwtConfig::from_vars(&vars).unwrap().unwrap()
+  }
+
+  #[test]
+  fn test_verify_hs256() {
+    let config = config();
+    let header = json!({"alg": "HS256", "typ": "JWT"});
+    let claims =
+      json!({"sub": "alice", "iss": "darx", "aud": ["app"], "exp": 1000});
+    let token = sign_hs256("secret", header.clone(), claims.clone());
+    assert_eq!(claims, config.verify(token.as_str(), 900).unwrap());
+
+    // expired, after the leeway.
+    assert!(config
+      .verify(token.as_str(), 1000 + LEEWAY_SECS + 1)
+      .is_err());
+    // signed by another secret.
+    let token = sign_hs256("other", header.clone(), claims.clone());
+    assert!(config.verify(token.as_str(), 900).is_err());
+    // issued by another issuer.
+    let claims =
+      json!({"sub": "alice", "iss": "other", "aud": "app", "exp": 1000});
+    let token = sign_hs256("secret", header.clone(), claims);
+    assert!(config.verify(token.as_str(), 900).is_err());
+    // without exp.
+    let claims = json!({"sub": "alice", "iss": "darx", "aud": "app"});
+    let token = sign_hs256("secret", header, claims);
+    assert!(config.verify(token.as_str(), 900).is_err());
+  }
+
+  #[test]
+  fn test_verify_alg_none() {
+    let config = config();
+    let claims =
+      json!({"sub": "alice", "iss": "darx", "aud": "app", "exp": 1000});
+    let token = format!(
+      "{}.{}.",
+      URL_SAFE_NO_PAD.encode(json!({"alg": "none"}).to_string()),
+      URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    assert!(config.verify(token.as_str(), 900).is_err());
+    assert!(config.verify("not a token", 900).is_err());
+  }
+
+  #[test]
+  fn test_from_vars() {
+    assert!(JwtConfig::from_vars(&HashMap::new()).unwrap().is_none());
+    let mut vars = HashMap::new();
+    vars.insert(AUTH_JWKS_VAR.to_string(), "not json".to_string());
+    assert!(JwtConfig::from_vars(&vars).is_err());
+    vars.insert(AUTH_JWKS_VAR.to_string(), r#"{"keys": []}"#.to_string());
+    assert!(JwtConfig::from_vars(&vars).unwrap().is_some());
+  }
+}
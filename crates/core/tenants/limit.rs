@@ -0,0 +1,374 @@
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use sqlx::MySqlPool;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use time::{Date, Month, OffsetDateTime};
+
+use crate::api::{ApiError, EgressPolicy, EnvLimits, FetchLimits, RateLimit};
+
+/// How often the invocations counted by the node are saved to the control db.
+pub const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+#[derive(Default)]
+struct EnvState {
+  limits: EnvLimits,
+  bucket: Option<Bucket>,
+  route_buckets: HashMap<String, Bucket>,
+  running: u32,
+  // the month counted, and the invocations of the month in all nodes.
+  month: String,
+  used: u64,
+  // the invocations not saved yet by month.
+  pending: HashMap<String, u64>,
+}
+
+// ENVS tracks the limits and usage of the envs invoked on this node.
+static ENVS: Lazy<DashMap<String, EnvState>> = Lazy::new(DashMap::new);
+
+/// [`Permit`] holds a running invocation of the env until dropped.
+pub struct Permit {
+  env_id: String,
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    if let Some(mut state) = ENVS.get_mut(self.env_id.as_str()) {
+      state.running = state.running.saturating_sub(1);
+    }
+  }
+}
+
+/// [`set_limits`] replaces the limits of the env,
+/// the rate limits start over with full buckets.
+pub fn set_limits(env_id: &str, limits: &EnvLimits) {
+  let mut state = ENVS.entry(env_id.to_string()).or_default();
+  if &state.limits != limits {
+    state.limits = limits.clone();
+    state.bucket = None;
+    state.route_buckets.clear();
+  }
+}
+
+/// [`find_egress`] returns the egress policy of the env.
+pub fn find_egress(env_id: &str) -> EgressPolicy {
+  ENVS
+    .get(env_id)
+    .and_then(|state| state.limits.egress.clone())
+    .unwrap_or_default()
+}
+
+/// [`find_fetch_limits`] returns the limits of the fetches of the env.
+pub fn find_fetch_limits(env_id: &str) -> FetchLimits {
+  ENVS
+    .get(env_id)
+    .and_then(|state| state.limits.fetch.clone())
+    .unwrap_or_default()
+}
+
+pub(crate) fn remove_limits(env_id: &str) {
+  ENVS.remove(env_id);
+}
+
+/// [`replace_limits`] takes the limits of all envs,
+/// the envs not in `all` have no limits.
+pub(crate) fn replace_limits(mut all: HashMap<String, EnvLimits>) {
+  for mut state in ENVS.iter_mut() {
+    let limits = all.remove(state.key()).unwrap_or_default();
+    if state.limits != limits {
+      state.limits = limits;
+      state.bucket = None;
+      state.route_buckets.clear();
+    }
+  }
+  for (env_id, limits) in all.iter() {
+    set_limits(env_id, limits);
+  }
+}
+
+/// [`acquire`] checks the quota, concurrency and rate limits of the env
+/// before invoking `func_url`, and counts the invocation.
+pub fn acquire(env_id: &str, func_url: &str) -> Result<Permit, ApiError> {
+  let now = OffsetDateTime::now_utc();
+  let mut state = ENVS.entry(env_id.to_string()).or_default();
+  state.acquire(func_url, now, Instant::now())?;
+  Ok(Permit {
+    env_id: env_id.to_string(),
+  })
+}
+
+/// [`save_usage`] adds the invocations counted by this node to the control db,
+/// and reads back the invocations of all nodes in the current month.
+pub async fn save_usage(pool: &MySqlPool) -> Result<()> {
+  let mut pending = vec![];
+  for mut state in ENVS.iter_mut() {
+    let env_id = state.key().clone();
+    for (month, count) in state.pending.drain() {
+      pending.push((env_id.clone(), month, count));
+    }
+  }
+  for (i, (env_id, month, count)) in pending.iter().enumerate() {
+    let r = sqlx::query!(
+      "INSERT INTO env_usage (env_id, month, invocations) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE invocations = invocations + VALUES(invocations)",
+      env_id,
+      month,
+      count
+    )
+    .execute(pool)
+    .await
+    .context("Failed to insert into env_usage table");
+    if let Err(e) = r {
+      // counted again on the next save.
+      for (env_id, month, count) in pending[i..].iter() {
+        if let Some(mut state) = ENVS.get_mut(env_id.as_str()) {
+          *state.pending.entry(month.clone()).or_default() += count;
+        }
+      }
+      return Err(e);
+    }
+  }
+
+  let month = month_of(OffsetDateTime::now_utc());
+  let usage = sqlx::query!(
+    "SELECT env_id, invocations FROM env_usage WHERE month = ?",
+    month
+  )
+  .fetch_all(pool)
+  .await
+  .context("Failed to query env_usage table")?;
+  for row in usage.into_iter() {
+    if let Some(mut state) = ENVS.get_mut(row.env_id.as_str()) {
+      let pending = state.pending.get(month.as_str()).copied().unwrap_or(0);
+      state.month = month.clone();
+      state.used = row.invocations as u64 + pending;
+    }
+  }
+  Ok(())
+}
+
+impl EnvState {
+  fn acquire(
+    &mut self,
+    func_url: &str,
+    now: OffsetDateTime,
+    instant: Instant,
+  ) -> Result<(), ApiError> {
+    let month = month_of(now);
+    if self.month != month {
+      self.month = month.clone();
+      self.used = 0;
+    }
+    if let Some(quota) = self.limits.monthly_quota {
+      if self.used >= quota {
+        return Err(ApiError::TooManyRequests(
+          format!("monthly quota {} exceeded", quota),
+          secs_to_next_month(now),
+        ));
+      }
+    }
+    if let Some(max) = self.limits.max_concurrency {
+      if self.running >= max {
+        return Err(ApiError::TooManyRequests(
+          format!("max concurrency {} reached", max),
+          1,
+        ));
+      }
+    }
+
+    let route_limit = self.limits.route_rate_limits.get(func_url).copied();
+    if let Some(limit) = route_limit {
+      let bucket = self
+        .route_buckets
+        .entry(func_url.to_string())
+        .or_insert_with(|| Bucket::new(&limit, instant));
+      bucket.refill(&limit, instant);
+      bucket.check(&limit).map_err(|retry_after| {
+        ApiError::TooManyRequests(
+          format!("rate limit of {} exceeded", func_url),
+          retry_after,
+        )
+      })?;
+    }
+    if let Some(limit) = self.limits.rate_limit {
+      let bucket = self
+        .bucket
+        .get_or_insert_with(|| Bucket::new(&limit, instant));
+      bucket.refill(&limit, instant);
+      bucket.check(&limit).map_err(|retry_after| {
+        ApiError::TooManyRequests(
+          "rate limit exceeded".to_string(),
+          retry_after,
+        )
+      })?;
+    }
+
+    // all limits are checked before taking any token.
+    if route_limit.is_some() {
+      if let Some(bucket) = self.route_buckets.get_mut(func_url) {
+        bucket.tokens -= 1.0;
+      }
+    }
+    if let Some(bucket) = self.bucket.as_mut() {
+      bucket.tokens -= 1.0;
+    }
+    self.running += 1;
+    self.used += 1;
+    *self.pending.entry(month).or_default() += 1;
+    Ok(())
+  }
+}
+
+impl Bucket {
+  fn new(limit: &RateLimit, now: Instant) -> Self {
+    Bucket {
+      tokens: burst(limit),
+      updated: now,
+    }
+  }
+
+  fn refill(&mut self, limit: &RateLimit, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * limit.per_sec).min(burst(limit));
+    self.updated = now;
+  }
+
+  /// [`check`] returns the seconds to wait for a token if there is none.
+  fn check(&self, limit: &RateLimit) -> Result<(), u64> {
+    if self.tokens >= 1.0 {
+      return Ok(());
+    }
+    let secs = (1.0 - self.tokens) / limit.per_sec;
+    Err((secs.ceil() as u64).max(1))
+  }
+}
+
+fn burst(limit: &RateLimit) -> f64 {
+  match limit.burst {
+    Some(burst) => burst as f64,
+    None => limit.per_sec.ceil().max(1.0),
+  }
+}
+
+fn month_of(time: OffsetDateTime) -> String {
+  format!("{:04}-{:02}", time.year(), time.month() as u8)
+}
+
+fn secs_to_next_month(now: OffsetDateTime) -> u64 {
+  let (year, month) = match now.month() {
+    Month::December => (now.year() + 1, Month::January),
+    month => (now.year(), month.next()),
+  };
+  let next = Date::from_calendar_date(year, month, 1)
+    .expect("the first day of a month is valid")
+    .midnight()
+    .assume_utc();
+  (next - now).whole_seconds().max(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn utc(
+    year: i32,
+    month: Month,
+    day: u8,
+    hour: u8,
+    min: u8,
+  ) -> OffsetDateTime {
+    Date::from_calendar_date(year, month, day)
+      .unwrap()
+      .with_hms(hour, min, 0)
+      .unwrap()
+      .assume_utc()
+  }
+
+  fn state(limits: EnvLimits) -> EnvState {
+    EnvState {
+      limits,
+      ..Default::default()
+    }
+  }
+
+  fn retry_after(r: Result<(), ApiError>) -> u64 {
+    match r {
+      Err(ApiError::TooManyRequests(_, retry_after)) => retry_after,
+      r => panic!("unexpected result: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn test_rate_limit() {
+    let now = utc(2023, Month::June, 15, 0, 0);
+    let start = Instant::now();
+    let mut state = state(EnvLimits {
+      rate_limit: Some(RateLimit {
+        per_sec: 0.5,
+        burst: Some(2),
+      }),
+      ..Default::default()
+    });
+    assert!(state.acquire("foo.Hi", now, start).is_ok());
+    assert!(state.acquire("foo.Hi", now, start).is_ok());
+    assert_eq!(2, retry_after(state.acquire("foo.Hi", now, start)));
+    // a token is refilled every 2 seconds.
+    let later = start + Duration::from_secs(2);
+    assert!(state.acquire("foo.Hi", now, later).is_ok());
+    assert!(state.acquire("foo.Hi", now, later).is_err());
+  }
+
+  #[test]
+  fn test_route_rate_limit() {
+    let now = utc(2023, Month::June, 15, 0, 0);
+    let start = Instant::now();
+    let mut limits = EnvLimits {
+      rate_limit: Some(RateLimit {
+        per_sec: 1.0,
+        burst: Some(2),
+      }),
+      ..Default::default()
+    };
+    limits.route_rate_limits.insert(
+      "foo.Hi".to_string(),
+      RateLimit {
+        per_sec: 1.0,
+        burst: None,
+      },
+    );
+    let mut state = state(limits);
+    assert!(state.acquire("foo.Hi", now, start).is_ok());
+    assert!(state.acquire("foo.Hi", now, start).is_err());
+    // the rejected call takes no token of the env.
+    assert!(state.acquire("foo.Bar", now, start).is_ok());
+    assert!(state.acquire("foo.Bar", now, start).is_err());
+  }
+
+  #[test]
+  fn test_concurrency_and_quota() {
+    let now = utc(2023, Month::December, 31, 23, 59);
+    let start = Instant::now();
+    let mut state = state(EnvLimits {
+      max_concurrency: Some(1),
+      monthly_quota: Some(2),
+      ..Default::default()
+    });
+    assert!(state.acquire("foo.Hi", now, start).is_ok());
+    assert_eq!(1, retry_after(state.acquire("foo.Hi", now, start)));
+    state.running -= 1;
+    assert!(state.acquire("foo.Hi", now, start).is_ok());
+    state.running -= 1;
+    assert_eq!(60, retry_after(state.acquire("foo.Hi", now, start)));
+    assert_eq!(Some(&2), state.pending.get("2023-12"));
+
+    // the quota starts over in the next month.
+    let next_month = utc(2024, Month::January, 1, 0, 0);
+    assert!(state.acquire("foo.Hi", next_month, start).is_ok());
+    assert_eq!(Some(&1), state.pending.get("2024-01"));
+  }
+}
@@ -1,8 +1,23 @@
-mod cache;
+pub mod artifact;
+pub(crate) mod cache;
+mod cors;
 mod deploy;
+mod domain;
+mod jwt;
+mod limit;
 pub mod log;
 
+pub use cors::{
+  cors_headers, find_cors, preflight_headers, set_cors, CorsHeaders,
+};
 pub use deploy::{
   add_code_deploy, add_plugin_deploy, add_var_deploy, init_deploys,
-  invoke_function, match_route, save_log,
+  invoke_function, match_route, reconcile_deploys, remove_env,
+  remove_stale_deploys, save_log,
+};
+pub use domain::{match_domain, set_domains};
+pub use jwt::authenticate;
+pub use limit::{
+  acquire, find_egress, find_fetch_limits, save_usage, set_limits, Permit,
+  USAGE_SAVE_INTERVAL,
 };
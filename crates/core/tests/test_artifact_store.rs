@@ -0,0 +1,36 @@
+use anyhow::Result;
+use darx_core::tenants::artifact::{ArtifactStore, S3ArtifactStore};
+use darx_utils::new_nano_id;
+use std::env;
+
+/// Runs against a S3 compatible server like MinIO, e.g.
+/// `DARX_S3_ENDPOINT=http://127.0.0.1:9000 DARX_S3_BUCKET=darx-test`,
+/// the test is skipped if `DARX_S3_ENDPOINT` is not set.
+#[tokio::test]
+async fn test_s3_artifact_store() -> Result<()> {
+  let endpoint = match env::var("DARX_S3_ENDPOINT") {
+    Ok(endpoint) => endpoint,
+    Err(_) => return Ok(()),
+  };
+  let bucket =
+    env::var("DARX_S3_BUCKET").unwrap_or_else(|_| "darx-test".to_string());
+  let access_key =
+    env::var("DARX_S3_ACCESS_KEY").unwrap_or_else(|_| "minioadmin".to_string());
+  let secret_key =
+    env::var("DARX_S3_SECRET_KEY").unwrap_or_else(|_| "minioadmin".to_string());
+  let store = S3ArtifactStore::new(
+    bucket.as_str(),
+    "us-east-1",
+    Some(endpoint.as_str()),
+    Some(access_key.as_str()),
+    Some(secret_key.as_str()),
+  )?;
+
+  let key = format!("test/{}/SNAPSHOT.bin", new_nano_id());
+  assert_eq!(None, store.get(key.as_str()).await?);
+  store.put(key.as_str(), b"snapshot").await?;
+  assert_eq!(Some(b"snapshot".to_vec()), store.get(key.as_str()).await?);
+  store.delete(key.as_str()).await?;
+  assert_eq!(None, store.get(key.as_str()).await?);
+  Ok(())
+}
@@ -1,11 +1,23 @@
 mod common;
 use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use common::TenantProjectContext;
-use darx_core::code::control::{deploy_code, deploy_var};
+use darx_core::api::{ApiError, CodeRef};
+use darx_core::code::blob::{content_hash, missing_blobs, resolve_codes};
+use darx_core::code::control::{
+  deploy_code, deploy_var, get_deploy, list_api, list_deploy, promote_deploy,
+};
+use darx_core::code::gc::{gc_deploys, set_retention};
+use darx_core::env::{list_env, Env};
+use darx_core::node::{place_env, register_node};
 use darx_core::tenants::{
-  add_code_deploy, add_var_deploy, invoke_function, match_route,
+  add_code_deploy, add_var_deploy, authenticate, init_deploys, invoke_function,
+  match_route, reconcile_deploys,
 };
 use darx_core::{Code, Project};
+use darx_utils::new_nano_id;
+use ring::hmac;
 use serde_json::json;
 use std::collections::HashMap;
 use test_context::test_context;
@@ -51,7 +63,7 @@ async fn test_deploy_code(ctx: &mut TenantProjectContext) -> Result<()> {
   let mut vars = HashMap::new();
   vars.insert("key1".to_string(), "value1".to_string());
   let (var_deploy_seq, vars, txn) =
-    deploy_var(txn, env_id, &vars, &None).await?;
+    deploy_var(txn, env_id, &vars, &Default::default(), &None).await?;
 
   txn.commit().await.context("Failed to commit transaction")?;
 
@@ -73,10 +85,12 @@ async fn test_deploy_code(ctx: &mut TenantProjectContext) -> Result<()> {
 
   let ret = invoke_function(
     envs_dir,
+    db_pool,
     env_id,
     ret_env_id.as_str(),
     seq,
     json!({}),
+    None,
     &r.js_entry_point,
     &r.js_export,
     &r.func_sig.param_names,
@@ -91,10 +105,12 @@ async fn test_deploy_code(ctx: &mut TenantProjectContext) -> Result<()> {
 
   let ret = invoke_function(
     envs_dir,
+    db_pool,
     env_id,
     ret_env_id.as_str(),
     seq,
     json!({}),
+    None,
     &r.js_entry_point,
     &r.js_export,
     &r.func_sig.param_names,
@@ -108,10 +124,12 @@ async fn test_deploy_code(ctx: &mut TenantProjectContext) -> Result<()> {
   assert_eq!(ret_env_id.as_str(), env_id);
   let ret = invoke_function(
     envs_dir,
+    db_pool,
     env_id,
     ret_env_id.as_str(),
     seq,
     json!({}),
+    None,
     &r.js_entry_point,
     &r.js_export,
     &r.func_sig.param_names,
@@ -125,10 +143,12 @@ async fn test_deploy_code(ctx: &mut TenantProjectContext) -> Result<()> {
   assert_eq!(ret_env_id.as_str(), env_id);
   let ret = invoke_function(
     envs_dir,
+    db_pool,
     env_id,
     ret_env_id.as_str(),
     seq,
     json!({}),
+    None,
     &r.js_entry_point,
     &r.js_export,
     &r.func_sig.param_names,
@@ -137,3 +157,445 @@ async fn test_deploy_code(ctx: &mut TenantProjectContext) -> Result<()> {
   assert_eq!(ret, json!("value1"));
   Ok(())
 }
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_promote(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+
+  let txn = db_pool.begin().await?;
+  let staging = Env::new_tenant_env(ctx.proj().id(), "staging");
+  let txn = staging.save(txn).await?;
+
+  let codes = vec![Code {
+    fs_path: "functions/hello.js".to_string(),
+    content: r#"export default function hello() {return "hi";}"#.to_string(),
+  }];
+  let (_, final_codes, http_routes, txn) =
+    deploy_code(txn, env_id, &codes, &None, &None).await?;
+  let (var_deploy_seq, _, txn) =
+    deploy_var(txn, env_id, &Default::default(), &Default::default(), &None)
+      .await?;
+  txn.commit().await?;
+
+  let envs = list_env(db_pool, ctx.proj().id()).await?;
+  let names: Vec<_> = envs.iter().map(|e| e.name.as_str()).collect();
+  assert_eq!(vec!["dev", "staging"], names);
+
+  // the latest code deploy is promoted, skipping the var deploy.
+  let txn = db_pool.begin().await?;
+  let (_, promoted_codes, promoted_routes, txn) =
+    promote_deploy(txn, env_id, staging.id(), None, &None).await?;
+  txn.commit().await?;
+  assert_eq!(final_codes.len(), promoted_codes.len());
+  assert_eq!(http_routes.len(), promoted_routes.len());
+  assert_eq!("hello", promoted_routes[0].http_path);
+
+  // var deploys have no codes to promote.
+  let txn = db_pool.begin().await?;
+  let r =
+    promote_deploy(txn, env_id, staging.id(), Some(var_deploy_seq), &None)
+      .await;
+  assert!(matches!(r, Err(ApiError::DeployNotFound(_))));
+
+  // deploys are only promoted within a project.
+  let other = Project::new_tenant_proj("test_org", "other_proj");
+  let txn = db_pool.begin().await?;
+  let txn = other.save(txn).await?;
+  txn.commit().await?;
+  let txn = db_pool.begin().await?;
+  let r = promote_deploy(txn, env_id, other.env_id(), None, &None).await;
+  other.drop(db_pool).await?;
+  assert!(matches!(r, Err(ApiError::PermissionDenied(_))));
+
+  // env names are unique in a project.
+  let txn = db_pool.begin().await?;
+  let r = Env::new_tenant_env(ctx.proj().id(), "staging")
+    .save(txn)
+    .await;
+  assert!(matches!(r, Err(ApiError::EnvAlreadyExists(_))));
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_deploy_history(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+
+  let txn = db_pool.begin().await?;
+  let codes = vec![Code {
+    fs_path: "functions/hello.js".to_string(),
+    content: r#"export default function hello() {return "hi";}"#.to_string(),
+  }];
+  let (code_deploy_seq, _, _, txn) =
+    deploy_code(txn, env_id, &codes, &Some("v1".to_string()), &None).await?;
+  let mut vars = HashMap::new();
+  vars.insert("key1".to_string(), "value1".to_string());
+  let (var_deploy_seq, _, txn) =
+    deploy_var(txn, env_id, &vars, &Default::default(), &None).await?;
+  txn.commit().await?;
+
+  // the routes of the code deploy are still in effect after the var deploy.
+  let http_routes = list_api(db_pool, env_id).await?;
+  assert_eq!(1, http_routes.len());
+
+  let mut invalid_vars = HashMap::new();
+  invalid_vars.insert("my-key".to_string(), "value".to_string());
+  let txn = db_pool.begin().await?;
+  assert!(matches!(
+    deploy_var(txn, env_id, &invalid_vars, &Default::default(), &None).await,
+    Err(ApiError::InvalidVarKey(_))
+  ));
+
+  // the default var deploy of the project, the code and the var deploy.
+  let (deploys, next) = list_deploy(db_pool, env_id, None, Some(2)).await?;
+  assert_eq!(2, deploys.len());
+  assert_eq!(var_deploy_seq, deploys[0].deploy_seq);
+  assert_eq!(code_deploy_seq, deploys[1].deploy_seq);
+  assert_eq!(Some("v1".to_string()), deploys[1].tag);
+  assert_eq!(Some(code_deploy_seq), next);
+  let (deploys, next) = list_deploy(db_pool, env_id, next, Some(2)).await?;
+  assert_eq!(1, deploys.len());
+  assert_eq!(None, next);
+
+  let rsp = get_deploy(db_pool, env_id, var_deploy_seq).await?;
+  assert_eq!(1, rsp.codes.len());
+  assert_eq!("functions/hello.js", rsp.codes[0].fs_path);
+  assert_eq!(1, rsp.http_routes.len());
+  assert!(rsp
+    .vars
+    .iter()
+    .any(|v| v.key == "key1" && v.value == "value1"));
+
+  // vars of the code deploy come from the default var deploy.
+  let rsp = get_deploy(db_pool, env_id, code_deploy_seq).await?;
+  assert!(rsp.vars.iter().all(|v| v.key != "key1"));
+
+  let r = get_deploy(db_pool, env_id, var_deploy_seq + 1).await;
+  assert!(matches!(r, Err(ApiError::DeployNotFound(_))));
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_code_blobs(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+
+  let code = Code {
+    fs_path: "functions/hello.js".to_string(),
+    content: r#"export default function hello() {return "hi";}"#.to_string(),
+  };
+  let hash = content_hash(code.content.as_str());
+  assert_eq!(
+    vec![hash.clone()],
+    missing_blobs(db_pool, env_id, &[hash.clone()]).await?
+  );
+
+  let txn = db_pool.begin().await?;
+  let (_, _, _, txn) =
+    deploy_code(txn, env_id, &vec![code.clone()], &None, &None).await?;
+  txn.commit().await?;
+  assert!(missing_blobs(db_pool, env_id, &[hash.clone()])
+    .await?
+    .is_empty());
+
+  // the second deploy only references the stored content.
+  let code_refs = vec![CodeRef {
+    fs_path: "functions/hello2.js".to_string(),
+    hash: hash.clone(),
+  }];
+  let codes =
+    resolve_codes(db_pool, env_id, &[code.clone()], &code_refs).await?;
+  assert_eq!(2, codes.len());
+  assert_eq!(code.content, codes[1].content);
+  let txn = db_pool.begin().await?;
+  let (deploy_seq, _, _, txn) =
+    deploy_code(txn, env_id, &codes, &None, &None).await?;
+  txn.commit().await?;
+  let rsp = get_deploy(db_pool, env_id, deploy_seq).await?;
+  assert!(rsp
+    .codes
+    .iter()
+    .any(|c| c.fs_path == "functions/hello2.js" && c.content == code.content));
+
+  let code_refs = vec![CodeRef {
+    fs_path: "functions/hello3.js".to_string(),
+    hash: content_hash("unknown"),
+  }];
+  let r = resolve_codes(db_pool, env_id, &[], &code_refs).await;
+  assert!(matches!(r, Err(ApiError::BlobNotFound(_))));
+
+  // the blobs of an org are not found by the others.
+  let other = Project::new_tenant_proj("other_org", "other_proj");
+  let txn = db_pool.begin().await?;
+  let txn = other.save(txn).await?;
+  txn.commit().await?;
+  let missing = missing_blobs(db_pool, other.env_id(), &[hash.clone()]).await;
+  let code_refs = vec![CodeRef {
+    fs_path: "functions/hello.js".to_string(),
+    hash: hash.clone(),
+  }];
+  let r = resolve_codes(db_pool, other.env_id(), &[], &code_refs).await;
+  other.drop(db_pool).await?;
+  assert_eq!(vec![hash.clone()], missing?);
+  assert!(matches!(r, Err(ApiError::BlobNotFound(_))));
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_lazy_load_deploy(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+  let envs_dir = ctx.envs_dir();
+
+  let txn = db_pool.begin().await?;
+  let codes = vec![Code {
+    fs_path: "functions/hello.js".to_string(),
+    content: r#"export default function hello() {return "hi";}"#.to_string(),
+  }];
+  let (deploy_seq, _, _, txn) =
+    deploy_code(txn, env_id, &codes, &None, &None).await?;
+  txn.commit().await?;
+
+  // only routes are loaded on startup.
+  init_deploys(envs_dir, db_pool, None, 0).await?;
+  let deploy_dir = envs_dir.join(env_id).join(deploy_seq.to_string());
+  assert!(!deploy_dir.exists());
+
+  let (ret_env_id, seq, r) =
+    match_route(env_id, "hello", "POST").expect("should match url");
+  assert_eq!(deploy_seq, seq);
+
+  // concurrent first invocations load the deploy once.
+  let invoke = || {
+    invoke_function(
+      envs_dir,
+      db_pool,
+      env_id,
+      ret_env_id.as_str(),
+      seq,
+      json!({}),
+      None,
+      &r.js_entry_point,
+      &r.js_export,
+      &r.func_sig.param_names,
+    )
+  };
+  let (ret1, ret2) = tokio::join!(invoke(), invoke());
+  assert_eq!(ret1?, json!("hi"));
+  assert_eq!(ret2?, json!("hi"));
+  assert!(deploy_dir.join("SNAPSHOT.bin").exists());
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_gc_deploys(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+
+  let mut seqs = vec![];
+  for (i, tag) in [Some("v1".to_string()), None, None].iter().enumerate() {
+    let codes = vec![Code {
+      fs_path: "functions/hello.js".to_string(),
+      content: format!("export default function hello() {{return {};}}", i),
+    }];
+    let txn = db_pool.begin().await?;
+    let (seq, _, _, txn) = deploy_code(txn, env_id, &codes, tag, &None).await?;
+    txn.commit().await?;
+    seqs.push(seq);
+  }
+  set_retention(db_pool, env_id, 1).await?;
+  assert!(matches!(
+    set_retention(db_pool, env_id, 0).await,
+    Err(ApiError::InvalidRetention(_))
+  ));
+
+  // the tagged deploy, the latest deploy and the default var deploy it uses
+  // are kept.
+  let txn = db_pool.begin().await?;
+  let (deleted, txn) = gc_deploys(txn, env_id, true).await?;
+  txn.commit().await?;
+  assert_eq!(
+    vec![seqs[1]],
+    deleted.iter().map(|d| d.deploy_seq).collect::<Vec<_>>()
+  );
+  let (deploys, _) = list_deploy(db_pool, env_id, None, None).await?;
+  assert_eq!(4, deploys.len());
+
+  let txn = db_pool.begin().await?;
+  let (deleted, txn) = gc_deploys(txn, env_id, false).await?;
+  txn.commit().await?;
+  assert_eq!(1, deleted.len());
+  let (deploys, _) = list_deploy(db_pool, env_id, None, None).await?;
+  assert_eq!(3, deploys.len());
+  assert!(deploys.iter().all(|d| d.deploy_seq != seqs[1]));
+  let r = get_deploy(db_pool, env_id, seqs[1]).await;
+  assert!(matches!(r, Err(ApiError::DeployNotFound(_))));
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_redeliver_and_reconcile(
+  ctx: &mut TenantProjectContext,
+) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+  let envs_dir = ctx.envs_dir();
+
+  let txn = db_pool.begin().await?;
+  let codes = vec![Code {
+    fs_path: "functions/hello.js".to_string(),
+    content: r#"export default function hello() {return "hi";}"#.to_string(),
+  }];
+  let (code_deploy_seq, final_codes, http_routes, txn) =
+    deploy_code(txn, env_id, &codes, &None, &None).await?;
+  let mut vars = HashMap::new();
+  vars.insert("key1".to_string(), "value1".to_string());
+  let (var_deploy_seq, vars, txn) =
+    deploy_var(txn, env_id, &vars, &Default::default(), &None).await?;
+  txn.commit().await?;
+
+  // deploys delivered twice are applied once.
+  for _ in 0..2 {
+    add_code_deploy(
+      envs_dir,
+      env_id,
+      code_deploy_seq,
+      &final_codes,
+      &http_routes,
+    )
+    .await?;
+    add_var_deploy(env_id, var_deploy_seq, &vars).await?;
+  }
+  let (_, seq, _) =
+    match_route(env_id, "hello", "POST").expect("should match url");
+  assert_eq!(code_deploy_seq, seq);
+
+  // a deploy never delivered is loaded by reconciling.
+  let txn = db_pool.begin().await?;
+  let codes = vec![Code {
+    fs_path: "functions/hello2.js".to_string(),
+    content: r#"export default function hello2() {return "hi2";}"#.to_string(),
+  }];
+  let (missed_seq, _, _, txn) =
+    deploy_code(txn, env_id, &codes, &None, &None).await?;
+  txn.commit().await?;
+  assert!(match_route(env_id, "hello2", "POST").is_none());
+  reconcile_deploys(envs_dir, db_pool, None).await?;
+  let (_, seq, _) =
+    match_route(env_id, "hello2", "POST").expect("should match url");
+  assert_eq!(missed_seq, seq);
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_place_env(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+  let envs_dir = ctx.envs_dir();
+
+  let node1 = new_nano_id();
+  let node2 = new_nano_id();
+  register_node(db_pool, node1.as_str(), "http://127.0.0.1:3456", false)
+    .await?;
+  register_node(db_pool, node2.as_str(), "http://127.0.0.1:3458", false)
+    .await?;
+
+  let txn = db_pool.begin().await?;
+  let codes = vec![Code {
+    fs_path: "functions/hello.js".to_string(),
+    content: r#"export default function hello() {return "hi";}"#.to_string(),
+  }];
+  let (_, _, _, txn) = deploy_code(txn, env_id, &codes, &None, &None).await?;
+  let txn = place_env(txn, env_id, &[node1.clone()]).await?;
+  txn.commit().await?;
+
+  // the env is only loaded on the node it's placed on.
+  reconcile_deploys(envs_dir, db_pool, Some(node2.as_str())).await?;
+  assert!(match_route(env_id, "hello", "POST").is_none());
+  reconcile_deploys(envs_dir, db_pool, Some(node1.as_str())).await?;
+  assert!(match_route(env_id, "hello", "POST").is_some());
+
+  // moved to another node.
+  let txn = db_pool.begin().await?;
+  let txn = place_env(txn, env_id, &[node2.clone()]).await?;
+  txn.commit().await?;
+  reconcile_deploys(envs_dir, db_pool, Some(node1.as_str())).await?;
+  assert!(match_route(env_id, "hello", "POST").is_none());
+
+  let txn = db_pool.begin().await?;
+  let r = place_env(txn, env_id, &["not_exist".to_string()]).await;
+  assert!(matches!(r, Err(ApiError::NodeNotFound(_))));
+  Ok(())
+}
+
+#[test_context(TenantProjectContext)]
+#[tokio::test]
+async fn test_end_user_auth(ctx: &mut TenantProjectContext) -> Result<()> {
+  let env_id = ctx.proj().env_id();
+  let db_pool = ctx.db_pool();
+  let envs_dir = ctx.envs_dir();
+
+  let codes = vec![Code {
+    fs_path: "functions/me.js".to_string(),
+    content: r#"export const requireAuth = true;
+      export default function me() {return Darx.auth.sub;}"#
+      .to_string(),
+  }];
+  let mut secrets = HashMap::new();
+  secrets.insert("DARX_AUTH_SECRET".to_string(), "secret".to_string());
+  let txn = db_pool.begin().await?;
+  let (deploy_seq, final_codes, http_routes, txn) =
+    deploy_code(txn, env_id, &codes, &None, &None).await?;
+  let (var_deploy_seq, vars, txn) =
+    deploy_var(txn, env_id, &Default::default(), &secrets, &None).await?;
+  txn.commit().await?;
+  add_code_deploy(envs_dir, env_id, deploy_seq, &final_codes, &http_routes)
+    .await?;
+  add_var_deploy(env_id, var_deploy_seq, &vars).await?;
+
+  let (ret_env_id, seq, r) =
+    match_route(env_id, "me", "POST").expect("should match url");
+  assert!(r.func_sig.auth_required);
+  assert!(matches!(
+    authenticate(env_id, None, r.func_sig.auth_required),
+    Err(ApiError::AuthError)
+  ));
+  assert!(matches!(
+    authenticate(env_id, Some("invalid"), r.func_sig.auth_required),
+    Err(ApiError::AuthError)
+  ));
+
+  let message = format!(
+    "{}.{}",
+    URL_SAFE_NO_PAD.encode(json!({"alg": "HS256"}).to_string()),
+    URL_SAFE_NO_PAD
+      .encode(json!({"sub": "alice", "exp": i64::MAX}).to_string())
+  );
+  let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+  let sig = hmac::sign(&key, message.as_bytes());
+  let token = format!("{}.{}", message, URL_SAFE_NO_PAD.encode(sig.as_ref()));
+  let auth = authenticate(env_id, Some(token.as_str()), true)?;
+  let ret = invoke_function(
+    envs_dir,
+    db_pool,
+    env_id,
+    ret_env_id.as_str(),
+    seq,
+    json!({}),
+    auth,
+    &r.js_entry_point,
+    &r.js_export,
+    &r.func_sig.param_names,
+  )
+  .await?;
+  assert_eq!(ret, json!("alice"));
+  Ok(())
+}
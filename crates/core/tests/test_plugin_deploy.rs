@@ -23,7 +23,7 @@ async fn test_deploy_plugin_startup(
 
   let (plugin_name, plugin_env_id, _, _) = deploy_to_ctrl(ctx).await?;
 
-  init_deploys(envs_dir.as_path(), &db_pool).await?;
+  init_deploys(envs_dir.as_path(), &db_pool, None, 0).await?;
 
   check_url(
     plugin_name.as_str(),
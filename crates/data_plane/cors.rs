@@ -0,0 +1,132 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{
+  forward_ready, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::http::header::{
+  HeaderValue, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+  ORIGIN,
+};
+use actix_web::http::Method;
+use actix_web::{HttpResponse, ResponseError};
+use darx_core::api::{ApiError, CorsConfig};
+use darx_core::tenants;
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::try_extract_env_id;
+
+/// [`EnvCors`] applies the CORS policy of the env invoked,
+/// it answers preflight requests without invoking.
+pub struct EnvCors;
+
+impl<S, B> Transform<S, ServiceRequest> for EnvCors
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = actix_web::Error,
+    > + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<EitherBody<B>>;
+  type Error = actix_web::Error;
+  type Transform = EnvCorsMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(EnvCorsMiddleware { service }))
+  }
+}
+
+pub struct EnvCorsMiddleware<S> {
+  service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for EnvCorsMiddleware<S>
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = actix_web::Error,
+    > + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<EitherBody<B>>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let origin = req
+      .headers()
+      .get(ORIGIN)
+      .and_then(|origin| origin.to_str().ok())
+      .map(str::to_string);
+    let origin = match origin {
+      Some(origin) => origin,
+      // not a CORS request.
+      None => {
+        let fut = self.service.call(req);
+        return Box::pin(async move {
+          fut.await.map(ServiceResponse::map_into_left_body)
+        });
+      }
+    };
+    let cors = env_cors(&req);
+
+    let preflight_method = req
+      .headers()
+      .get(ACCESS_CONTROL_REQUEST_METHOD)
+      .and_then(|method| method.to_str().ok());
+    if let (&Method::OPTIONS, Some(method)) = (req.method(), preflight_method) {
+      let request_headers = req
+        .headers()
+        .get(ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|headers| headers.to_str().ok());
+      let rsp = match tenants::preflight_headers(
+        &cors,
+        origin.as_str(),
+        method,
+        request_headers,
+      ) {
+        Some(headers) => {
+          let mut rsp = HttpResponse::Ok();
+          for header in headers.into_iter() {
+            rsp.insert_header(header);
+          }
+          rsp.finish()
+        }
+        None => ApiError::PermissionDenied(format!(
+          "CORS request from {} is not allowed",
+          origin
+        ))
+        .error_response(),
+      };
+      return Box::pin(ready(Ok(req.into_response(rsp).map_into_right_body())));
+    }
+
+    let fut = self.service.call(req);
+    Box::pin(async move {
+      let mut rsp = fut.await?;
+      // the browser hides the response from a disallowed origin.
+      let headers = tenants::cors_headers(&cors, origin.as_str());
+      for (name, value) in headers.unwrap_or_default().into_iter() {
+        if let Ok(value) = HeaderValue::from_str(value.as_str()) {
+          rsp.headers_mut().insert(name, value);
+        }
+      }
+      Ok(rsp.map_into_left_body())
+    })
+  }
+}
+
+fn env_cors(req: &ServiceRequest) -> CorsConfig {
+  let func_url = req.match_info().get("func_url").unwrap_or_default();
+  let host = req.connection_info().host().to_string();
+  match try_extract_env_id(host.as_str(), req.request(), func_url) {
+    Ok((env_id, _)) => tenants::find_cors(env_id.as_str()),
+    // the invocation fails anyway, its error can be read by any origin.
+    Err(_) => CorsConfig::default(),
+  }
+}
@@ -1,24 +1,43 @@
-use actix_cors::Cors;
+mod cors;
+mod sync;
+
 use actix_web::dev::{ConnectionInfo, Server};
-use actix_web::web::{get, post, Data, Json, Path};
+use actix_web::web::{get, post, resource, Data, Json, Path};
 use actix_web::{
   App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer,
 };
 use anyhow::{Context, Result};
-use darx_core::api::{AddPluginDeployReq, AddVarDeployReq};
+use cors::EnvCors;
+use darx_core::api::{
+  AddPluginDeployReq, AddVarDeployReq, RemoveEnvReq, SetCorsReq, SetDomainsReq,
+  SetLimitsReq,
+};
+use darx_core::auth::{self, InternalAuth};
+use darx_core::code::gc;
+use darx_core::node;
 use darx_core::tenants;
+use darx_core::tenants::artifact::{
+  artifact_store_from_env, init_artifact_store,
+};
 use darx_core::{api::AddCodeDeployReq, api::AddTenantDBReq, api::ApiError};
 use darx_db;
+use darx_utils::new_nano_id;
 use serde_json;
 use sqlx::MySqlPool;
 use std::env;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 use tokio::fs;
-use tracing::info;
+use tokio::time::{interval_at, Instant};
+use tracing::{error, info};
 use tracing_actix_web::TracingLogger;
 
 const DARX_ENVS_DIR: &str = "./darx_envs";
+// used when no remote artifact store is configured.
+const DARX_ARTIFACTS_DIR: &str = "./darx_artifacts";
+// keeps the node id across restarts.
+const NODE_ID_FILE: &str = "NODE_ID";
 
 struct ServerState {
   envs_dir: PathBuf,
@@ -38,6 +57,9 @@ pub async fn run_server(
   let envs_dir = working_dir.join(DARX_ENVS_DIR);
   fs::create_dir_all(envs_dir.as_path()).await?;
 
+  // fails fast if the secret is not configured or too short.
+  auth::internal_secret();
+
   let db_pool = MySqlPool::connect(
     env::var("DATABASE_URL")
       .expect("DATABASE_URL should be configured")
@@ -46,9 +68,63 @@ pub async fn run_server(
   .await
   .context("Failed to connect database")?;
 
-  tenants::init_deploys(envs_dir.as_path(), &db_pool)
-    .await
-    .context("Failed to init deployments on startup")?;
+  let artifact_store =
+    artifact_store_from_env(working_dir.join(DARX_ARTIFACTS_DIR).as_path())
+      .context("Failed to create artifact store")?;
+  init_artifact_store(artifact_store)?;
+
+  // registered before loading deploys, so no deploy is missed in between.
+  let node_id = load_node_id(working_dir.as_path()).await?;
+  let node_url = env::var("DARX_NODE_URL")
+    .or_else(|_| env::var("DATA_PLANE_URL"))
+    .unwrap_or_else(|_| format!("http://{}", socket_addr));
+  let pull = pull_mode();
+  let first_event_id =
+    node::register_node(&db_pool, node_id.as_str(), node_url.as_str(), pull)
+      .await
+      .context("Failed to register data plane node")?;
+  info!(
+    "registered node {} at {}, pull: {}",
+    node_id, node_url, pull
+  );
+  actix_web::rt::spawn(run_heartbeat(db_pool.clone(), node_id.clone()));
+
+  let prewarm_envs = env::var("DARX_PREWARM_ENVS")
+    .map(|n| n.parse::<u32>().expect("Failed to parse DARX_PREWARM_ENVS"))
+    .unwrap_or(0);
+  tenants::init_deploys(
+    envs_dir.as_path(),
+    &db_pool,
+    Some(node_id.as_str()),
+    prewarm_envs,
+  )
+  .await
+  .context("Failed to init deployments on startup")?;
+
+  if pull {
+    let control_url = env::var("CONTROL_PLANE_URL")
+      .expect("CONTROL_PLANE_URL should be configured to pull changes");
+    actix_web::rt::spawn(sync::run_pull(
+      envs_dir.clone(),
+      db_pool.clone(),
+      control_url,
+      node_id.clone(),
+      first_event_id,
+    ));
+  }
+
+  actix_web::rt::spawn(run_save_usage(db_pool.clone()));
+  if let Some(period) = gc::gc_interval() {
+    actix_web::rt::spawn(run_gc(envs_dir.clone(), db_pool.clone(), period));
+  }
+  if let Some(period) = reconcile_interval() {
+    actix_web::rt::spawn(run_reconcile(
+      envs_dir.clone(),
+      db_pool.clone(),
+      node_id,
+      period,
+    ));
+  }
 
   info!("listen on {}", socket_addr);
 
@@ -58,26 +134,165 @@ pub async fn run_server(
   });
   Ok(
     HttpServer::new(move || {
-      let cors = Cors::default()
-        .allow_any_method()
-        .allow_any_header()
-        .allow_any_origin();
       App::new()
         .wrap(TracingLogger::default())
-        .wrap(cors)
         .app_data(server_state.clone())
         .route("/", get().to(|| async { "data plane healthy." }))
-        .route("/invoke/{func_url:.*}", post().to(invoke_function))
-        .route("/add_tenant_db", post().to(add_tenant_db))
-        .route("/add_plugin_deploy", post().to(add_plugin_deploy))
-        .route("/add_code_deploy", post().to(add_code_deploy))
-        .route("/add_var_deploy", post().to(add_var_deploy))
+        .service(
+          resource("/invoke/{func_url:.*}")
+            .wrap(EnvCors)
+            .route(post().to(invoke_function)),
+        )
+        // called by the control plane only.
+        .service(
+          resource("/add_tenant_db")
+            .wrap(InternalAuth)
+            .route(post().to(add_tenant_db)),
+        )
+        .service(
+          resource("/add_plugin_deploy")
+            .wrap(InternalAuth)
+            .route(post().to(add_plugin_deploy)),
+        )
+        .service(
+          resource("/add_code_deploy")
+            .wrap(InternalAuth)
+            .route(post().to(add_code_deploy)),
+        )
+        .service(
+          resource("/add_var_deploy")
+            .wrap(InternalAuth)
+            .route(post().to(add_var_deploy)),
+        )
+        .service(
+          resource("/remove_env")
+            .wrap(InternalAuth)
+            .route(post().to(remove_env)),
+        )
+        .service(
+          resource("/set_domains")
+            .wrap(InternalAuth)
+            .route(post().to(set_domains)),
+        )
+        .service(
+          resource("/set_limits")
+            .wrap(InternalAuth)
+            .route(post().to(set_limits)),
+        )
+        .service(
+          resource("/set_cors")
+            .wrap(InternalAuth)
+            .route(post().to(set_cors)),
+        )
     })
     .bind(&socket_addr)?
     .run(),
   )
 }
 
+async fn run_gc(envs_dir: PathBuf, db_pool: MySqlPool, period: Duration) {
+  let mut interval = interval_at(Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    if let Err(e) =
+      tenants::remove_stale_deploys(envs_dir.as_path(), &db_pool).await
+    {
+      error!("Failed to remove stale deploys: {:?}", e);
+    }
+  }
+}
+
+async fn run_reconcile(
+  envs_dir: PathBuf,
+  db_pool: MySqlPool,
+  node_id: String,
+  period: Duration,
+) {
+  let mut interval = interval_at(Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    if let Err(e) = tenants::reconcile_deploys(
+      envs_dir.as_path(),
+      &db_pool,
+      Some(node_id.as_str()),
+    )
+    .await
+    {
+      error!("Failed to reconcile deploys: {:?}", e);
+    }
+  }
+}
+
+async fn run_save_usage(db_pool: MySqlPool) {
+  let period = tenants::USAGE_SAVE_INTERVAL;
+  let mut interval = interval_at(Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    if let Err(e) = tenants::save_usage(&db_pool).await {
+      error!("Failed to save usage: {:?}", e);
+    }
+  }
+}
+
+async fn run_heartbeat(db_pool: MySqlPool, node_id: String) {
+  let period = node::HEARTBEAT_INTERVAL;
+  let mut interval = interval_at(Instant::now() + period, period);
+  loop {
+    interval.tick().await;
+    if let Err(e) = node::heartbeat(&db_pool, node_id.as_str()).await {
+      error!("Failed to send heartbeat: {:?}", e);
+    }
+  }
+}
+
+/// [`pull_mode`] is true if `DARX_SYNC_MODE=pull`, the node pulls changes
+/// from the control plane instead of being pushed to, so the control plane
+/// doesn't need to reach it. Defaults to `push`.
+fn pull_mode() -> bool {
+  match env::var("DARX_SYNC_MODE").as_deref() {
+    Ok("pull") => true,
+    Ok("push") | Err(_) => false,
+    Ok(other) => panic!("Unknown DARX_SYNC_MODE: {}", other),
+  }
+}
+
+/// [`load_node_id`] reads the node id from `DARX_NODE_ID`, otherwise from
+/// the working dir, a new one is generated for the first run.
+async fn load_node_id(working_dir: &std::path::Path) -> Result<String> {
+  if let Ok(node_id) = env::var("DARX_NODE_ID") {
+    return Ok(node_id);
+  }
+  let path = working_dir.join(NODE_ID_FILE);
+  if path.exists() {
+    let node_id = fs::read_to_string(path.as_path())
+      .await
+      .context("Failed to read node id")?;
+    return Ok(node_id.trim().to_string());
+  }
+  let node_id = new_nano_id();
+  fs::write(path.as_path(), node_id.as_str())
+    .await
+    .context("Failed to write node id")?;
+  Ok(node_id)
+}
+
+/// [`reconcile_interval`] reads how often the deploys are reconciled with the
+/// control db from `DARX_RECONCILE_INTERVAL_SECS`, defaults to a minute,
+/// `0` disables it.
+fn reconcile_interval() -> Option<Duration> {
+  let secs = env::var("DARX_RECONCILE_INTERVAL_SECS")
+    .map(|s| {
+      s.parse::<u64>()
+        .expect("Failed to parse DARX_RECONCILE_INTERVAL_SECS")
+    })
+    .unwrap_or(60);
+  if secs == 0 {
+    None
+  } else {
+    Some(Duration::from_secs(secs))
+  }
+}
+
 async fn invoke_function(
   server_state: Data<ServerState>,
   conn: ConnectionInfo,
@@ -86,8 +301,8 @@ async fn invoke_function(
   Json(req): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
   let host = conn.host();
-  let env_id = try_extract_env_id(host, &http_req)?;
-  let func_url = func_url.into_inner();
+  let (env_id, func_url) =
+    try_extract_env_id(host, &http_req, func_url.as_str())?;
 
   info!("invoke_function: {}, env_id: {}", func_url, env_id);
 
@@ -105,12 +320,23 @@ async fn invoke_function(
     env_id, target_env_id, deploy_seq, route
   );
 
+  // rejects the call before creating an isolate.
+  let auth = tenants::authenticate(
+    env_id.as_str(),
+    auth::bearer_token(&http_req),
+    route.func_sig.auth_required,
+  )?;
+  // held until the invocation ends.
+  let _permit = tenants::acquire(env_id.as_str(), route.http_path.as_str())?;
+
   let ret = tenants::invoke_function(
     &server_state.envs_dir,
+    &server_state.control_db,
     env_id.as_str(),
     &target_env_id,
     deploy_seq,
     req,
+    auth,
     &route.js_entry_point,
     &route.js_export,
     &route.func_sig.param_names,
@@ -168,6 +394,36 @@ async fn add_var_deploy(
   Ok(HttpResponse::Ok())
 }
 
+async fn remove_env(
+  server_state: Data<ServerState>,
+  Json(req): Json<RemoveEnvReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  tenants::remove_env(server_state.envs_dir.as_path(), req.env_id.as_str())
+    .await?;
+  Ok(HttpResponse::Ok())
+}
+
+async fn set_domains(
+  Json(req): Json<SetDomainsReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  tenants::set_domains(req.env_id.as_str(), &req.domains);
+  Ok(HttpResponse::Ok())
+}
+
+async fn set_limits(
+  Json(req): Json<SetLimitsReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  tenants::set_limits(req.env_id.as_str(), &req.limits);
+  Ok(HttpResponse::Ok())
+}
+
+async fn set_cors(
+  Json(req): Json<SetCorsReq>,
+) -> Result<HttpResponseBuilder, ApiError> {
+  tenants::set_cors(req.env_id.as_str(), req.cors.as_ref());
+  Ok(HttpResponse::Ok())
+}
+
 // async fn create_table(
 //   conn: ConnectionInfo,
 //   http_req: HttpRequest,
@@ -229,7 +485,15 @@ async fn add_var_deploy(
 //   Ok(pool.inner().clone())
 // }
 
-fn try_extract_env_id(host: &str, http_req: &HttpRequest) -> Result<String> {
+/// [`try_extract_env_id`] returns the env id and the function url to match
+/// the routes of the env with.
+/// A custom domain maps the host and the leading segments of `func_url` to
+/// an env, other hosts are `{env_id}.{DARX_DOMAIN}`.
+fn try_extract_env_id(
+  host: &str,
+  http_req: &HttpRequest,
+  func_url: &str,
+) -> Result<(String, String), ApiError> {
   let darx_env = env::var("DARX_ENV").expect("DARX_ENV should be configured");
   let host = if darx_env != "production" {
     // dev environment use a special Darx-Dev-Host header to specify the host.
@@ -245,14 +509,29 @@ fn try_extract_env_id(host: &str, http_req: &HttpRequest) -> Result<String> {
   } else {
     host.to_string()
   };
+  if let Some(matched) = tenants::match_domain(host.as_str(), func_url) {
+    return Ok(matched);
+  }
   let env_id = env_id_from_domain(host.as_str())?;
-  Ok(env_id)
+  Ok((env_id, func_url.to_string()))
 }
 
-fn env_id_from_domain(domain: &str) -> Result<String> {
-  let mut parts = domain.split('.');
-  let env_id = parts.next().ok_or_else(|| {
-    ApiError::DomainNotFound(format!("invalid domain: {}", domain))
-  })?;
-  Ok(env_id.to_string())
+fn env_id_from_domain(domain: &str) -> Result<String, ApiError> {
+  let hostname = domain
+    .rsplit_once(':')
+    .map_or(domain, |(hostname, _)| hostname)
+    .to_lowercase();
+  // without `DARX_DOMAIN`, any host is taken as `{env_id}.*`.
+  let subdomain = match env::var("DARX_DOMAIN") {
+    Ok(darx_domain) => hostname
+      .strip_suffix(darx_domain.to_lowercase().as_str())
+      .and_then(|subdomain| subdomain.strip_suffix('.')),
+    Err(_) => hostname.split('.').next(),
+  };
+  match subdomain {
+    Some(env_id) if !env_id.is_empty() && !env_id.contains('.') => {
+      Ok(env_id.to_string())
+    }
+    _ => Err(ApiError::DomainNotFound(domain.to_string())),
+  }
 }
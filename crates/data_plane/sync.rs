@@ -0,0 +1,161 @@
+use anyhow::{bail, Context, Result};
+use darx_core::api::{
+  AddCodeDeployReq, AddPluginDeployReq, AddTenantDBReq, AddVarDeployReq,
+  ChangesReq, ChangesRsp, EventKind, RemoveEnvReq, SetCorsReq, SetDomainsReq,
+  SetLimitsReq,
+};
+use darx_core::{auth, tenants};
+use serde::de::DeserializeOwned;
+use sqlx::MySqlPool;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+const WAIT_SECS: u64 = 30;
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// [`run_pull`] keeps pulling the changes after `cursor` from the control
+/// plane and applies them, a failed pull is retried with backoff.
+pub(crate) async fn run_pull(
+  envs_dir: PathBuf,
+  db_pool: MySqlPool,
+  control_url: String,
+  node_id: String,
+  mut cursor: i64,
+) {
+  let client = reqwest::Client::new();
+  let mut backoff = Duration::from_secs(1);
+  loop {
+    let r = pull_changes(
+      &client,
+      envs_dir.as_path(),
+      &db_pool,
+      control_url.as_str(),
+      node_id.as_str(),
+      &mut cursor,
+    )
+    .await;
+    match r {
+      Ok(_) => backoff = Duration::from_secs(1),
+      Err(e) => {
+        error!("Failed to pull changes after {}: {:?}", cursor, e);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+      }
+    }
+  }
+}
+
+/// [`pull_changes`] moves `cursor` forward with each change applied,
+/// so a failed change is pulled again by the next call.
+async fn pull_changes(
+  client: &reqwest::Client,
+  envs_dir: &Path,
+  db_pool: &MySqlPool,
+  control_url: &str,
+  node_id: &str,
+  cursor: &mut i64,
+) -> Result<()> {
+  let req = ChangesReq {
+    since: *cursor,
+    node_id: Some(node_id.to_string()),
+    wait_secs: Some(WAIT_SECS),
+  };
+  let rsp = client
+    .get(format!("{}/changes", control_url))
+    .query(&req)
+    .bearer_auth(auth::internal_secret())
+    .timeout(Duration::from_secs(WAIT_SECS + 10))
+    .send()
+    .await
+    .context("Failed to send changes request")?;
+  if !rsp.status().is_success() {
+    bail!(
+      "Failed to pull changes: {}",
+      rsp.text().await.unwrap_or_default()
+    );
+  }
+  let rsp: ChangesRsp = rsp
+    .json()
+    .await
+    .context("Failed to parse changes response")?;
+
+  if rsp.reset {
+    info!("changes after {} are removed, reload all deploys", cursor);
+    tenants::reconcile_deploys(envs_dir, db_pool, Some(node_id)).await?;
+  }
+  for event in rsp.events.iter() {
+    apply_change(envs_dir, event.kind.as_str(), &event.payload)
+      .await
+      .with_context(|| format!("Failed to apply change {}", event.id))?;
+    *cursor = event.id;
+  }
+  *cursor = rsp.cursor.max(*cursor);
+  Ok(())
+}
+
+/// [`apply_change`] does what the data plane api of `kind` does.
+async fn apply_change(
+  envs_dir: &Path,
+  kind: &str,
+  payload: &serde_json::Value,
+) -> Result<()> {
+  match EventKind::parse(kind)? {
+    EventKind::AddCodeDeploy => {
+      let req: AddCodeDeployReq = parse_payload(payload)?;
+      tenants::add_code_deploy(
+        envs_dir,
+        req.env_id.as_str(),
+        req.deploy_seq,
+        &req.codes,
+        &req.http_routes,
+      )
+      .await?;
+    }
+    EventKind::AddPluginDeploy => {
+      let req: AddPluginDeployReq = parse_payload(payload)?;
+      tenants::add_plugin_deploy(
+        &req.name,
+        envs_dir,
+        req.env_id.as_str(),
+        req.deploy_seq,
+        &req.codes,
+        &req.http_routes,
+      )
+      .await?;
+    }
+    EventKind::AddVarDeploy => {
+      let req: AddVarDeployReq = parse_payload(payload)?;
+      tenants::add_var_deploy(req.env_id.as_str(), req.deploy_seq, &req.vars)
+        .await?;
+    }
+    EventKind::AddTenantDB => {
+      let req: AddTenantDBReq = parse_payload(payload)?;
+      darx_db::add_tenant_db_info(req.env_id.as_str(), req.db_info);
+    }
+    EventKind::RemoveEnv => {
+      let req: RemoveEnvReq = parse_payload(payload)?;
+      tenants::remove_env(envs_dir, req.env_id.as_str()).await?;
+    }
+    EventKind::SetDomains => {
+      let req: SetDomainsReq = parse_payload(payload)?;
+      tenants::set_domains(req.env_id.as_str(), &req.domains);
+    }
+    EventKind::SetLimits => {
+      let req: SetLimitsReq = parse_payload(payload)?;
+      tenants::set_limits(req.env_id.as_str(), &req.limits);
+    }
+    EventKind::SetCors => {
+      let req: SetCorsReq = parse_payload(payload)?;
+      tenants::set_cors(req.env_id.as_str(), req.cors.as_ref());
+    }
+  }
+  Ok(())
+}
+
+fn parse_payload<T: DeserializeOwned>(
+  payload: &serde_json::Value,
+) -> Result<T> {
+  serde_json::from_value(payload.clone()).context("Failed to parse payload")
+}
@@ -4,6 +4,7 @@ mod tenants;
 pub use control::{drop_tenant_db, save_tenant_db};
 pub use tenants::{
   add_column_sql, add_tenant_db_info, create_table_sql, drop_column_sql,
-  drop_table_sql, get_tenant_pool, rename_column_sql, rename_table_sql,
-  AddColumnReq, CreateTableReq, DDLReq, TenantConnPool, TenantDBInfo,
+  drop_table_sql, get_tenant_pool, remove_tenant_db_info, rename_column_sql,
+  rename_table_sql, AddColumnReq, CreateTableReq, DDLReq, TenantConnPool,
+  TenantDBInfo,
 };
@@ -13,7 +13,8 @@ pub use ddl::{
   rename_column_sql, rename_table_sql,
 };
 pub use pool::{
-  add_tenant_db_info, get_tenant_pool, MySqlTenantPool, TenantDBInfo,
+  add_tenant_db_info, get_tenant_pool, remove_tenant_db_info, MySqlTenantPool,
+  TenantDBInfo,
 };
 
 #[async_trait]
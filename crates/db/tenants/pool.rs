@@ -67,3 +67,8 @@ pub async fn get_tenant_pool(
 pub fn add_tenant_db_info(env_id: &str, db_info: TenantDBInfo) {
   GLOBAL_DB_INFO.insert(env_id.to_string(), db_info);
 }
+
+pub fn remove_tenant_db_info(env_id: &str) {
+  GLOBAL_DB_INFO.remove(env_id);
+  GLOBAL_POOL.remove(env_id);
+}
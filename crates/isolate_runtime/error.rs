@@ -0,0 +1,144 @@
+use deno_core::error::JsError;
+use deno_core::{op, OpState};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+deno_core::extension!(darx_error, ops = [op_set_thrown]);
+
+/// [`Thrown`] is what the function of an invocation throws besides
+/// the js error, see `invoke` of `00_bootstrap.js`.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Thrown {
+  code: Option<String>,
+  status: Option<u16>,
+}
+
+#[op]
+fn op_set_thrown(state: &mut OpState, thrown: Thrown) {
+  state.put::<Thrown>(thrown);
+}
+
+/// [`JsErrorDetails`] is the error thrown by the function of an invocation.
+/// `code` and `status` are the properties of the error if it has them,
+/// the status is always a client or server error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsErrorDetails {
+  pub name: String,
+  pub message: String,
+  /// the frames of the user's code, the files are relative to the project.
+  pub stack: Vec<StackFrame>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub code: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub status: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackFrame {
+  pub function: Option<String>,
+  pub file: String,
+  pub line: Option<i64>,
+  pub column: Option<i64>,
+}
+
+impl fmt::Display for JsErrorDetails {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.name, self.message)?;
+    for frame in self.stack.iter() {
+      write!(f, "\n    at ")?;
+      if let Some(function) = frame.function.as_ref() {
+        write!(f, "{} ", function)?;
+      }
+      write!(f, "({}", frame.file)?;
+      if let (Some(line), Some(column)) = (frame.line, frame.column) {
+        write!(f, ":{}:{}", line, column)?;
+      }
+      write!(f, ")")?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for JsErrorDetails {}
+
+impl JsErrorDetails {
+  pub(crate) fn new(js_error: JsError, thrown: Option<Thrown>) -> Self {
+    let thrown = thrown.unwrap_or_default();
+    let message = js_error.message.unwrap_or_else(|| {
+      // a value thrown is not an error.
+      let message = js_error.exception_message;
+      message
+        .strip_prefix("Uncaught ")
+        .map(str::to_string)
+        .unwrap_or(message)
+    });
+    let stack = js_error
+      .frames
+      .into_iter()
+      .filter_map(|frame| {
+        let file = frame.file_name?;
+        // the frames of the runtime and the invoking script are not
+        // the user's, the script has no extension.
+        if file.starts_with("ext:") || Path::new(&file).extension().is_none() {
+          return None;
+        }
+        Some(StackFrame {
+          function: frame.function_name.filter(|name| !name.is_empty()),
+          file,
+          line: frame.line_number,
+          column: frame.column_number,
+        })
+      })
+      .collect();
+    Self {
+      name: js_error.name.unwrap_or_else(|| "Error".to_string()),
+      message,
+      stack,
+      code: thrown.code,
+      status: thrown.status.filter(|status| (400..600).contains(status)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use deno_core::error::JsStackFrame;
+
+  #[test]
+  fn test_js_error_details() {
+    let frame = |file: &str| {
+      let mut frame =
+        JsStackFrame::from_location(Some(file.to_string()), Some(3), Some(9));
+      frame.function_name = Some("validate".to_string());
+      frame
+    };
+    let js_error = JsError {
+      name: Some("TypeError".to_string()),
+      message: Some("invalid email".to_string()),
+      stack: None,
+      cause: None,
+      exception_message: "Uncaught TypeError: invalid email".to_string(),
+      frames: vec![
+        frame("functions/user.ts"),
+        frame("ext:darx_bootstrap/00_bootstrap.js"),
+        frame("invoke_function"),
+      ],
+      source_line: None,
+      source_line_frame_index: None,
+      aggregated: None,
+    };
+    let thrown = Thrown {
+      code: Some("INVALID_EMAIL".to_string()),
+      status: Some(422),
+    };
+    let details = JsErrorDetails::new(js_error, Some(thrown));
+    assert_eq!(Some(422), details.status);
+    assert_eq!(1, details.stack.len());
+    assert_eq!(
+      "TypeError: invalid email\n    at validate (functions/user.ts:3:9)",
+      details.to_string()
+    );
+  }
+}
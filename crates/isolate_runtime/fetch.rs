@@ -0,0 +1,399 @@
+use crate::log::{ERROR_LEVEL, INFO_LEVEL};
+use crate::permissions::{create_http_client, Egress, Permissions};
+use crate::{DeploySeq, EnvId};
+use anyhow::bail;
+use deno_core::error::{type_error, AnyError};
+use deno_core::url::Url;
+use deno_core::{
+  op, AsyncResult, BufView, ByteString, OpDecl, OpState, Resource, ResourceId,
+  ZeroCopyBuf,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// [`FetchLimits`] bound the outbound fetches of an invocation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchLimits {
+  /// the fetches running at the same time, until their response bodies
+  /// are closed, the others wait for a slot.
+  #[serde(default = "default_max_concurrency")]
+  pub max_concurrency: u32,
+  /// the time of a fetch, reading its response body included.
+  #[serde(default = "default_timeout_ms")]
+  pub timeout_ms: u64,
+  #[serde(default = "default_max_response_bytes")]
+  pub max_response_bytes: u64,
+  /// the response bytes of all fetches of an invocation.
+  #[serde(default = "default_max_total_bytes")]
+  pub max_total_bytes: u64,
+}
+
+fn default_max_concurrency() -> u32 {
+  6
+}
+
+fn default_timeout_ms() -> u64 {
+  10_000
+}
+
+fn default_max_response_bytes() -> u64 {
+  10 * 1024 * 1024
+}
+
+fn default_max_total_bytes() -> u64 {
+  50 * 1024 * 1024
+}
+
+impl Default for FetchLimits {
+  fn default() -> Self {
+    Self {
+      max_concurrency: default_max_concurrency(),
+      timeout_ms: default_timeout_ms(),
+      max_response_bytes: default_max_response_bytes(),
+      max_total_bytes: default_max_total_bytes(),
+    }
+  }
+}
+
+impl FetchLimits {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    if self.max_concurrency == 0 {
+      bail!("max_concurrency should be positive");
+    }
+    if self.timeout_ms == 0 {
+      bail!("timeout_ms should be positive");
+    }
+    if self.max_response_bytes == 0 || self.max_total_bytes == 0 {
+      bail!("max_response_bytes and max_total_bytes should be positive");
+    }
+    Ok(())
+  }
+}
+
+struct FetchState {
+  limits: FetchLimits,
+  slots: Arc<Semaphore>,
+  total_bytes: Rc<Cell<u64>>,
+  // the egress policy the client installed is built with.
+  client_egress: Option<Arc<Egress>>,
+  // the targets of the requests not sent yet.
+  targets: HashMap<ResourceId, Target>,
+}
+
+impl FetchState {
+  fn new(limits: FetchLimits) -> Self {
+    Self {
+      slots: Arc::new(Semaphore::new(limits.max_concurrency as usize)),
+      limits,
+      total_bytes: Default::default(),
+      client_egress: None,
+      targets: Default::default(),
+    }
+  }
+}
+
+struct Target {
+  method: String,
+  host: String,
+}
+
+deno_core::extension!(
+  darx_fetch,
+  // the fetch ops of deno_fetch are wrapped with the limits,
+  // a custom client would bypass both the limits and the egress policy.
+  middleware = |op| match op.name {
+    "op_fetch" => OpDecl {
+      name: "op_fetch",
+      ..op_darx_fetch::decl()
+    },
+    "op_fetch_send" => OpDecl {
+      name: "op_fetch_send",
+      ..op_darx_fetch_send::decl()
+    },
+    "op_fetch_custom_client" => op.disable(),
+    _ => op,
+  },
+  state = |state| {
+    state.put::<FetchState>(FetchState::new(FetchLimits::default()));
+  }
+);
+
+/// [`set_fetch_limits`] applies the limits to the fetches of the isolate.
+pub fn set_fetch_limits(state: &mut OpState, limits: &FetchLimits) {
+  let total_bytes = state.borrow::<FetchState>().total_bytes.clone();
+  let mut fetch = FetchState::new(limits.clone());
+  fetch.total_bytes = total_bytes;
+  state.put::<FetchState>(fetch);
+}
+
+// ensure_client rebuilds the client of deno_fetch if the egress policy or
+// the limits changed since it's built.
+fn ensure_client(state: &mut OpState) -> Result<(), AnyError> {
+  let egress = state.borrow::<Permissions>().egress();
+  let fetch = state.borrow::<FetchState>();
+  if let Some(client_egress) = fetch.client_egress.as_ref() {
+    if Arc::ptr_eq(client_egress, &egress) {
+      return Ok(());
+    }
+  }
+  let timeout = Duration::from_millis(fetch.limits.timeout_ms);
+  let user_agent = state.borrow::<deno_fetch::Options>().user_agent.clone();
+  let client =
+    create_http_client(egress.clone(), user_agent.as_str(), timeout)?;
+  state.put::<reqwest::Client>(client);
+  state.borrow_mut::<FetchState>().client_egress = Some(egress);
+  Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[op]
+fn op_darx_fetch(
+  state: &mut OpState,
+  method: ByteString,
+  url: String,
+  headers: Vec<(ByteString, ByteString)>,
+  client_rid: Option<u32>,
+  has_body: bool,
+  body_length: Option<u64>,
+  data: Option<ZeroCopyBuf>,
+) -> Result<serde_json::Value, AnyError> {
+  ensure_client(state)?;
+  let target = Target {
+    method: String::from_utf8_lossy(&method).to_string(),
+    host: Url::parse(url.as_str())
+      .ok()
+      .and_then(|url| url.host_str().map(str::to_string))
+      .unwrap_or_default(),
+  };
+  let ret = deno_fetch::op_fetch::call::<Permissions>(
+    state,
+    method,
+    url,
+    headers,
+    client_rid,
+    has_body,
+    body_length,
+    data,
+  )?;
+  // the fields of the return are private, it's read as json.
+  let ret = serde_json::to_value(ret)?;
+  if let Some(rid) = ret["requestRid"].as_u64() {
+    let fetch = state.borrow_mut::<FetchState>();
+    fetch.targets.insert(rid as ResourceId, target);
+  }
+  Ok(ret)
+}
+
+#[op]
+async fn op_darx_fetch_send(
+  state: Rc<RefCell<OpState>>,
+  rid: ResourceId,
+) -> Result<serde_json::Value, AnyError> {
+  let (slots, limits, total_bytes, mut call) = {
+    let mut state = state.borrow_mut();
+    let env = state.try_borrow::<EnvId>().map(|env| env.0.clone());
+    let seq = state.try_borrow::<DeploySeq>().map(|seq| seq.0);
+    let fetch = state.borrow_mut::<FetchState>();
+    let target = fetch.targets.remove(&rid).unwrap_or(Target {
+      method: "GET".to_string(),
+      host: String::new(),
+    });
+    let call = Call {
+      env: env.unwrap_or_default(),
+      seq: seq.unwrap_or_default(),
+      target,
+      start: Instant::now(),
+      status: None,
+      bytes: Cell::new(0),
+      error: RefCell::new(None),
+    };
+    (
+      fetch.slots.clone(),
+      fetch.limits.clone(),
+      fetch.total_bytes.clone(),
+      call,
+    )
+  };
+
+  let slot = slots.acquire_owned().await?;
+  call.start = Instant::now();
+  let ret = deno_fetch::op_fetch_send::call(state.clone(), rid).await;
+  let mut ret = match ret.and_then(|ret| Ok(serde_json::to_value(ret)?)) {
+    Ok(ret) => ret,
+    Err(e) => {
+      call.fail(e.to_string());
+      return Err(e);
+    }
+  };
+  call.status = ret["status"].as_u64().map(|status| status as u16);
+
+  let response_rid = ret["responseRid"].as_u64().unwrap_or_default();
+  let body = state
+    .borrow_mut()
+    .resource_table
+    .take_any(response_rid as ResourceId)?;
+  let content_length = ret["contentLength"].as_u64().unwrap_or_default();
+  if content_length > limits.max_response_bytes {
+    body.close();
+    let msg = format!(
+      "The response body of {} bytes exceeds the limit of {} bytes",
+      content_length, limits.max_response_bytes
+    );
+    call.fail(msg.clone());
+    return Err(type_error(msg));
+  }
+
+  let body = LimitedBody {
+    inner: body,
+    call,
+    max_bytes: limits.max_response_bytes,
+    total_bytes,
+    max_total_bytes: limits.max_total_bytes,
+    _slot: slot,
+  };
+  let rid = state.borrow_mut().resource_table.add(body);
+  ret["responseRid"] = rid.into();
+  Ok(ret)
+}
+
+/// [`Call`] is an outbound fetch, it's recorded in the log of the invocation
+/// once its response body is dropped.
+struct Call {
+  env: String,
+  seq: i64,
+  target: Target,
+  start: Instant,
+  status: Option<u16>,
+  bytes: Cell<u64>,
+  error: RefCell<Option<String>>,
+}
+
+impl Call {
+  fn fail(&self, error: String) {
+    self.error.borrow_mut().get_or_insert(error);
+  }
+}
+
+impl Drop for Call {
+  fn drop(&mut self) {
+    let latency = self.start.elapsed().as_millis();
+    let Target { method, host } = &self.target;
+    let bytes = self.bytes.get();
+    let (level, message) = match (self.status, self.error.borrow().as_ref()) {
+      (Some(status), None) => (
+        INFO_LEVEL,
+        format!(
+          "fetch {} {} {} in {}ms, {} bytes",
+          method, host, status, latency, bytes
+        ),
+      ),
+      (status, error) => (
+        ERROR_LEVEL,
+        format!(
+          "fetch {} {} {} failed in {}ms, {} bytes: {}",
+          method,
+          host,
+          status.map(|s| s.to_string()).unwrap_or_default(),
+          latency,
+          bytes,
+          error.map(String::as_str).unwrap_or_default(),
+        ),
+      ),
+    };
+    tracing::info!(env = %self.env, seq = self.seq, "{}", message);
+    if !self.env.is_empty() {
+      crate::log::record(
+        self.env.as_str(),
+        self.seq,
+        level.0,
+        "fetch".to_string(),
+        message,
+      );
+    }
+  }
+}
+
+/// [`LimitedBody`] counts the bytes read from a response body,
+/// a read fails once the limits are exceeded.
+struct LimitedBody {
+  inner: Rc<dyn Resource>,
+  call: Call,
+  max_bytes: u64,
+  total_bytes: Rc<Cell<u64>>,
+  max_total_bytes: u64,
+  // the fetch is running until its body is dropped.
+  _slot: OwnedSemaphorePermit,
+}
+
+impl Resource for LimitedBody {
+  fn name(&self) -> Cow<str> {
+    "fetchResponseBody".into()
+  }
+
+  fn read(self: Rc<Self>, limit: usize) -> AsyncResult<BufView> {
+    Box::pin(async move {
+      let buf = match self.inner.clone().read(limit).await {
+        Ok(buf) => buf,
+        Err(e) => {
+          self.call.fail(e.to_string());
+          return Err(e);
+        }
+      };
+      let len = buf.len() as u64;
+      let bytes = self.call.bytes.get() + len;
+      self.call.bytes.set(bytes);
+      let total_bytes = self.total_bytes.get() + len;
+      self.total_bytes.set(total_bytes);
+
+      let msg = if bytes > self.max_bytes {
+        format!(
+          "The response body exceeds the limit of {} bytes",
+          self.max_bytes
+        )
+      } else if total_bytes > self.max_total_bytes {
+        format!(
+          "The responses of the invocation exceed the limit of {} bytes",
+          self.max_total_bytes
+        )
+      } else {
+        return Ok(buf);
+      };
+      self.call.fail(msg.clone());
+      self.inner.clone().close();
+      Err(type_error(msg))
+    })
+  }
+
+  fn size_hint(&self) -> (u64, Option<u64>) {
+    self.inner.size_hint()
+  }
+
+  fn close(self: Rc<Self>) {
+    self.inner.clone().close()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fetch_limits() {
+    assert!(FetchLimits::default().validate().is_ok());
+    let limits: FetchLimits =
+      serde_json::from_str(r#"{"timeout_ms": 3000}"#).unwrap();
+    assert_eq!(3000, limits.timeout_ms);
+    assert_eq!(default_max_concurrency(), limits.max_concurrency);
+    let invalid = FetchLimits {
+      max_concurrency: 0,
+      ..Default::default()
+    };
+    assert!(invalid.validate().is_err());
+  }
+}
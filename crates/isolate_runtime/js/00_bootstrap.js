@@ -15,12 +15,18 @@ const {
   ArrayPrototypeSplice,
   Error,
   ErrorPrototype,
+  NumberIsInteger,
   ObjectDefineProperty,
   ObjectDefineProperties,
   ObjectPrototypeIsPrototypeOf,
   ObjectSetPrototypeOf,
   ObjectFreeze,
+  ReflectApply,
   SafeWeakMap,
+  String,
+  StringPrototypeReplaceAll,
+  StringPrototypeStartsWith,
+  StringPrototypeSlice,
   StringPrototypeSplit,
   WeakMapPrototypeGet,
   WeakMapPrototypeSet,
@@ -57,4 +63,56 @@ const globalScope = {
 };
 
 ObjectDefineProperties(globalThis, globalScope);
-globalThis.Darx = {};
\ No newline at end of file
+
+class PermissionDenied extends Error {
+  constructor(msg) {
+    super(msg);
+    this.name = "PermissionDenied";
+  }
+}
+core.registerErrorClass("PermissionDenied", PermissionDenied);
+
+// The stacks of errors refer to the files of the project.
+function prepareStackTrace(error, sites) {
+  const stack = core.prepareStackTrace(error, sites);
+  const sourceRoot = ops.op_source_root();
+  if (!sourceRoot) {
+    return stack;
+  }
+  const callSites = error.__callSiteEvals;
+  for (let i = 0; i < callSites.length; ++i) {
+    const callSite = callSites[i];
+    const fileName = callSite.fileName;
+    if (fileName && StringPrototypeStartsWith(fileName, sourceRoot)) {
+      callSite.fileName = StringPrototypeSlice(fileName, sourceRoot.length);
+    }
+  }
+  return StringPrototypeReplaceAll(stack, sourceRoot, "");
+}
+ObjectDefineProperty(
+  Error,
+  "prepareStackTrace",
+  nonEnumerable(prepareStackTrace),
+);
+globalThis.Darx = {};
+
+// invoke calls the function of an invocation, the code and status of the
+// error it throws are kept for the error response.
+async function invoke(func, args) {
+  try {
+    return await ReflectApply(func, undefined, args);
+  } catch (error) {
+    const code = error?.code;
+    const status = error?.status;
+    ops.op_set_thrown({
+      code: typeof code === "string" || typeof code === "number"
+        ? String(code)
+        : null,
+      status: NumberIsInteger(status) && status >= 400 && status < 600
+        ? status
+        : null,
+    });
+    throw error;
+  }
+}
+ObjectDefineProperty(globalThis.Darx, "invoke", { value: invoke });
\ No newline at end of file
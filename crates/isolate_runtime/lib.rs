@@ -1,5 +1,6 @@
 use anyhow::{bail, Context, Result};
 use db_ops::darx_db_ops;
+use deno_core::error::JsError;
 use deno_core::{v8, Extension, Snapshot};
 use module_loader::TenantModuleLoader;
 use std::collections::HashMap;
@@ -7,9 +8,22 @@ use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 mod db_ops;
+mod error;
+mod fetch;
 pub mod log;
 mod module_loader;
 mod permissions;
+mod source_root;
+
+pub use error::{JsErrorDetails, StackFrame};
+pub use fetch::FetchLimits;
+pub use module_loader::{
+  is_remote, ImportMap, VendoredModules, IMPORT_MAP_FILE, VENDOR_DIR,
+  VENDOR_MODULES_FILE,
+};
+pub use permissions::{EgressClient, EgressPolicy};
+
+const USER_AGENT: &str = "darx-runtime";
 
 deno_core::extension!(darx_bootstrap, esm = ["js/00_bootstrap.js"]);
 
@@ -124,6 +138,38 @@ impl DarxIsolate {
     Ok(js_runtime)
   }
 
+  /// [`set_egress`] restricts the hosts the isolate can fetch from,
+  /// the default policy blocks private networks only.
+  pub fn set_egress(&mut self, egress: &EgressPolicy) {
+    permissions::set_egress(
+      &mut self.js_runtime.op_state().borrow_mut(),
+      egress,
+    );
+  }
+
+  /// [`set_fetch_limits`] bounds the fetches of the isolate,
+  /// the default limits apply if it's not called.
+  pub fn set_fetch_limits(&mut self, limits: &FetchLimits) {
+    fetch::set_fetch_limits(
+      &mut self.js_runtime.op_state().borrow_mut(),
+      limits,
+    );
+  }
+
+  /// [`js_error`] replaces the js exception thrown by an invocation with
+  /// its [`JsErrorDetails`], other errors are returned as they are.
+  pub fn js_error(&mut self, err: anyhow::Error) -> anyhow::Error {
+    let thrown = self
+      .js_runtime
+      .op_state()
+      .borrow_mut()
+      .try_take::<error::Thrown>();
+    match err.downcast::<JsError>() {
+      Ok(js_error) => JsErrorDetails::new(js_error, thrown).into(),
+      Err(err) => err,
+    }
+  }
+
   /// Loads and evaluates a module from a file.
   /// The `file_path` is the path to the file relative to the project directory.
   pub async fn load_and_eval_module_file(
@@ -170,7 +216,7 @@ impl DarxIsolate {
   }
 
   fn extensions(deploy_dir: impl AsRef<Path>) -> Vec<Extension> {
-    let user_agent = "darx-runtime".to_string();
+    let user_agent = USER_AGENT.to_string();
     let root_cert_store = deno_tls::create_default_root_cert_store();
 
     vec![
@@ -191,13 +237,18 @@ impl DarxIsolate {
           ..Default::default()
         },
       ),
+      fetch::darx_fetch::init_ops_and_esm(),
+      source_root::darx_source_root::init_ops_and_esm(PathBuf::from(
+        deploy_dir.as_ref(),
+      )),
+      error::darx_error::init_ops_and_esm(),
       darx_bootstrap::init_ops_and_esm(),
       darx_db_ops::init_ops_and_esm(),
     ]
   }
 
   fn snapshot_extensions(deploy_dir: impl AsRef<Path>) -> Vec<Extension> {
-    let user_agent = "darx-runtime".to_string();
+    let user_agent = USER_AGENT.to_string();
     let root_cert_store = deno_tls::create_default_root_cert_store();
 
     vec![
@@ -218,6 +269,11 @@ impl DarxIsolate {
           ..Default::default()
         },
       ),
+      fetch::darx_fetch::init_ops(),
+      source_root::darx_source_root::init_ops(PathBuf::from(
+        deploy_dir.as_ref(),
+      )),
+      error::darx_error::init_ops(),
       darx_bootstrap::init_ops(),
       darx_db_ops::init_ops(),
     ]
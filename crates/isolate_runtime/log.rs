@@ -26,8 +26,9 @@ thread_local! {
     static LOG_BUF : RefCell<Vec<Entry>> = RefCell::new(Vec::with_capacity(BUF_LEN));
 }
 
+// the files of stacks are relative to the deploy dir, see `00_bootstrap.js`.
 static RE: Lazy<Regex> =
-  Lazy::new(|| Regex::new(r"at\s+(\S+)\s+\(.*/([^/]+):(\d+):\d+\)").unwrap());
+  Lazy::new(|| Regex::new(r"at\s+(\S+)\s+\((\S+):(\d+):\d+\)").unwrap());
 
 pub(crate) fn record(
   env: &str,
@@ -60,3 +61,24 @@ pub(crate) fn record(
 pub fn collect() -> Vec<Entry> {
   LOG_BUF.with(|v| v.borrow_mut().drain(..).collect())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_record_func() {
+    let stack = "    at add (functions/foo.ts:3:7)".to_string();
+    record("env", 1, INFO_LEVEL.0, stack, "message".to_string());
+    record(
+      "env",
+      1,
+      INFO_LEVEL.0,
+      "fetch".to_string(),
+      "message".to_string(),
+    );
+    let entries = collect();
+    assert_eq!("functions/foo.ts:3:add", entries[0].func);
+    assert_eq!("fetch", entries[1].func);
+  }
+}
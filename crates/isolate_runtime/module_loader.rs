@@ -1,15 +1,93 @@
-use deno_core::anyhow::{anyhow, Error};
+use deno_core::anyhow::{anyhow, bail, Context, Error};
+use deno_core::url::Url;
 use deno_core::{
   resolve_import, ModuleLoader, ModuleSourceFuture, ModuleSpecifier,
   ResolutionKind,
 };
-use std::path::PathBuf;
+use once_cell::unsync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 
+/// The dir of the third-party modules resolved at deploy time.
+pub const VENDOR_DIR: &str = "vendor";
+
+/// The [`VendoredModules`] of a deploy.
+pub const VENDOR_MODULES_FILE: &str = "vendor/modules.json";
+
+/// [`VendoredModules`] locks the third-party modules of a deploy,
+/// the `https:` and `npm:` imports are loaded from the vendor dir.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VendoredModules {
+  /// the path of the module of an import, e.g.
+  /// `npm:nanoid@4` -> `vendor/1f2e3d4c5b6a7980.js`.
+  pub imports: BTreeMap<String, String>,
+  /// the url a module is fetched from, the imports in it are relative to
+  /// the url, e.g. `vendor/1f2e3d4c5b6a7980.js` -> `https://esm.sh/nanoid@4`.
+  pub urls: BTreeMap<String, String>,
+}
+
+/// The [`ImportMap`] of a deploy.
+pub const IMPORT_MAP_FILE: &str = "import_map.json";
+
+/// [`ImportMap`] maps the bare imports of the project's modules, e.g.
+/// `{"imports": {"@lib/": "./lib/", "nanoid": "npm:nanoid@4"}}`.
+/// The targets are remote or relative to the project dir with `./`,
+/// scopes are not supported.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportMap {
+  #[serde(default)]
+  pub imports: BTreeMap<String, String>,
+}
+
+impl ImportMap {
+  /// [`ImportMap::parse`] parses and validates an import map.
+  pub fn parse(json: &str) -> Result<Self, Error> {
+    let import_map: ImportMap = serde_json::from_str(json)
+      .with_context(|| format!("Failed to parse {}", IMPORT_MAP_FILE))?;
+    for (key, target) in import_map.imports.iter() {
+      if key.ends_with('/') && !target.ends_with('/') {
+        bail!("The target of {} should end with '/', got {}", key, target);
+      }
+      if !target.starts_with("./") && !is_remote(target) {
+        bail!("The target of {} should start with './' or be remote", key);
+      }
+    }
+    Ok(import_map)
+  }
+
+  /// [`ImportMap::resolve`] returns the target of an import, mapped by
+  /// the key equal to it, or the longest key ending with `/` prefixing it.
+  pub fn resolve(&self, specifier: &str) -> Option<String> {
+    if let Some(target) = self.imports.get(specifier) {
+      return Some(target.clone());
+    }
+    self
+      .imports
+      .iter()
+      .filter(|(key, _)| key.ends_with('/') && specifier.starts_with(*key))
+      .max_by_key(|(key, _)| key.len())
+      .map(|(key, target)| format!("{}{}", target, &specifier[key.len()..]))
+  }
+}
+
+/// [`is_remote`] returns whether the import is resolved at deploy time.
+pub fn is_remote(specifier: &str) -> bool {
+  specifier.starts_with("https:")
+    || specifier.starts_with("http:")
+    || specifier.starts_with("npm:")
+}
+
 pub struct TenantModuleLoader {
-  // TODO not used right now, maybe we can delete it.
   tenant_dir: PathBuf,
   fs_module_loader: deno_core::FsModuleLoader,
+  // read on the first remote import.
+  vendored: OnceCell<VendoredModules>,
+  // read on the first import of a project's module.
+  import_map: OnceCell<ImportMap>,
 }
 
 impl TenantModuleLoader {
@@ -17,8 +95,44 @@ impl TenantModuleLoader {
     Self {
       tenant_dir,
       fs_module_loader: deno_core::FsModuleLoader,
+      vendored: OnceCell::new(),
+      import_map: OnceCell::new(),
     }
   }
+
+  fn vendored(&self) -> Result<&VendoredModules, Error> {
+    self
+      .vendored
+      .get_or_try_init(|| self.read_json(VENDOR_MODULES_FILE))
+  }
+
+  fn import_map(&self) -> Result<&ImportMap, Error> {
+    self
+      .import_map
+      .get_or_try_init(|| self.read_json(IMPORT_MAP_FILE))
+  }
+
+  // read_json reads a file of the tenant dir, the default if it's absent.
+  fn read_json<T: DeserializeOwned + Default>(
+    &self,
+    file: &str,
+  ) -> Result<T, Error> {
+    let path = self.tenant_dir.join(file);
+    if !path.exists() {
+      return Ok(Default::default());
+    }
+    let content = std::fs::read(path.as_path())
+      .with_context(|| format!("Failed to read {}", file))?;
+    serde_json::from_slice(&content)
+      .with_context(|| format!("Failed to parse {}", file))
+  }
+
+  // relative_path returns the path of a file of the tenant dir by its url.
+  fn relative_path(&self, specifier: &ModuleSpecifier) -> Option<String> {
+    let path = specifier.to_file_path().ok()?;
+    let path = path.strip_prefix(self.tenant_dir.as_path()).ok()?;
+    path.to_str().map(str::to_string)
+  }
 }
 
 impl ModuleLoader for TenantModuleLoader {
@@ -28,7 +142,43 @@ impl ModuleLoader for TenantModuleLoader {
     referrer: &str,
     _kind: ResolutionKind,
   ) -> Result<ModuleSpecifier, Error> {
-    let module_specifier = resolve_import(specifier, referrer)?;
+    // the imports of a vendored module are relative to its url.
+    let vendored_referrer = Url::parse(referrer)
+      .ok()
+      .and_then(|referrer| self.relative_path(&referrer))
+      .filter(|path| Path::new(path).starts_with(VENDOR_DIR));
+    let module_specifier = match vendored_referrer {
+      Some(path) => {
+        let base = self.vendored()?.urls.get(&path).cloned();
+        resolve_import(specifier, base.as_deref().unwrap_or(referrer))?
+      }
+      // the imports of the project's modules are mapped by the import map,
+      // the targets are relative to the tenant dir.
+      None => match self.import_map()?.resolve(specifier) {
+        Some(target) => {
+          let root = Url::from_directory_path(self.tenant_dir.as_path())
+            .map_err(|_| anyhow!("Invalid tenant dir"))?;
+          resolve_import(target.as_str(), root.as_str())?
+        }
+        None => resolve_import(specifier, referrer)?,
+      },
+    };
+
+    if is_remote(module_specifier.as_str()) {
+      let path = self
+        .vendored()?
+        .imports
+        .get(module_specifier.as_str())
+        .ok_or_else(|| {
+          anyhow!(
+            "Module {} is not resolved at deploy time",
+            module_specifier.as_str()
+          )
+        })?;
+      return Url::from_file_path(self.tenant_dir.join(path))
+        .map_err(|_| anyhow!("Invalid vendored module {}", path));
+    }
+
     let path = module_specifier
       .to_file_path()
       .map_err(|_| anyhow!("Only file:// URLs are supported."))?;
@@ -53,3 +203,34 @@ impl ModuleLoader for TenantModuleLoader {
     )
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_import_map() {
+    let import_map = ImportMap::parse(
+      r#"{"imports": {"@lib/": "./lib/", "@lib/db/": "./lib/db/v2/",
+        "nanoid": "npm:nanoid@4"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+      Some("./lib/greet.ts".to_string()),
+      import_map.resolve("@lib/greet.ts")
+    );
+    assert_eq!(
+      Some("./lib/db/v2/user.js".to_string()),
+      import_map.resolve("@lib/db/user.js")
+    );
+    assert_eq!(
+      Some("npm:nanoid@4".to_string()),
+      import_map.resolve("nanoid")
+    );
+    assert_eq!(None, import_map.resolve("./lib/greet.ts"));
+
+    assert!(ImportMap::parse(r#"{"imports": {"@lib/": "./lib"}}"#).is_err());
+    assert!(ImportMap::parse(r#"{"imports": {"a": "/a.js"}}"#).is_err());
+    assert!(ImportMap::parse(r#"{"scopes": {}}"#).is_err());
+  }
+}
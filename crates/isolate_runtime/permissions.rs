@@ -1,17 +1,305 @@
-use deno_core::error::AnyError;
-use deno_core::url::Url;
-use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use deno_core::error::{custom_error, AnyError};
+use deno_core::url::{Host, Url};
+use deno_core::OpState;
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{HeaderMap, USER_AGENT};
+use reqwest::redirect::Policy;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// [`EgressPolicy`] restricts the hosts and ports an isolate can fetch from,
+/// private networks are blocked unless listed in `allowed_networks`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EgressPolicy {
+  /// `api.example.com`, `*.example.com` for its subdomains, `*` for any host.
+  #[serde(default = "default_allowed_hosts")]
+  pub allowed_hosts: Vec<String>,
+  /// Any port is allowed if empty.
+  #[serde(default = "default_allowed_ports")]
+  pub allowed_ports: Vec<u16>,
+  /// The private networks allowed in CIDR notation, e.g. `10.1.0.0/16`.
+  #[serde(default)]
+  pub allowed_networks: Vec<String>,
+}
+
+fn default_allowed_hosts() -> Vec<String> {
+  vec!["*".to_string()]
+}
+
+fn default_allowed_ports() -> Vec<u16> {
+  vec![80, 443]
+}
+
+impl Default for EgressPolicy {
+  fn default() -> Self {
+    Self {
+      allowed_hosts: default_allowed_hosts(),
+      allowed_ports: default_allowed_ports(),
+      allowed_networks: vec![],
+    }
+  }
+}
+
+impl EgressPolicy {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    for host in self.allowed_hosts.iter() {
+      let name = host.strip_prefix("*.").unwrap_or(host);
+      if host != "*"
+        && (name.is_empty() || name.contains(|c: char| "*/:@ ".contains(c)))
+      {
+        bail!("invalid host pattern {}", host);
+      }
+    }
+    for network in self.allowed_networks.iter() {
+      network
+        .parse::<IpNet>()
+        .with_context(|| format!("invalid network {}", network))?;
+    }
+    Ok(())
+  }
+}
+
+// The special purpose networks of IANA, a fetch to them could reach
+// the internal services of darx.
+static BLOCKED_NETWORKS: Lazy<Vec<IpNet>> = Lazy::new(|| {
+  [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+  ]
+  .iter()
+  .map(|network| network.parse().unwrap())
+  .collect()
+});
+
+/// [`Egress`] is an [`EgressPolicy`] with its networks parsed.
+pub(crate) struct Egress {
+  policy: EgressPolicy,
+  networks: Vec<IpNet>,
+}
+
+impl Egress {
+  fn new(policy: &EgressPolicy) -> Self {
+    let networks = policy
+      .allowed_networks
+      .iter()
+      .filter_map(|network| match network.parse() {
+        Ok(network) => Some(network),
+        Err(e) => {
+          tracing::warn!("ignore invalid network {}: {}", network, e);
+          None
+        }
+      })
+      .collect();
+    Self {
+      policy: policy.clone(),
+      networks,
+    }
+  }
+
+  fn check_url(&self, url: &Url) -> Result<(), AnyError> {
+    let (host, port) = match (url.host(), url.port_or_known_default()) {
+      (Some(host), Some(port)) => (host, port),
+      _ => {
+        return Err(permission_denied(format!(
+          "Requires net access to \"{}\", the url has no host or port",
+          url
+        )))
+      }
+    };
+    let host_str = match host {
+      Host::Domain(domain) => domain.trim_end_matches('.').to_lowercase(),
+      Host::Ipv4(ip) => ip.to_string(),
+      Host::Ipv6(ip) => format!("[{}]", ip),
+    };
+    let target = format!("{}:{}", host_str, port);
+    if !self.allows_host(host_str.as_str()) {
+      return Err(permission_denied(format!(
+        "Requires net access to \"{}\", the host is not allowed",
+        target
+      )));
+    }
+    if !self.policy.allowed_ports.is_empty()
+      && !self.policy.allowed_ports.contains(&port)
+    {
+      return Err(permission_denied(format!(
+        "Requires net access to \"{}\", the port is not allowed",
+        target
+      )));
+    }
+    // domains are checked once resolved, by the resolver of the client.
+    match host {
+      Host::Ipv4(ip) => self.check_ip(target.as_str(), IpAddr::V4(ip)),
+      Host::Ipv6(ip) => self.check_ip(target.as_str(), IpAddr::V6(ip)),
+      Host::Domain(_) => Ok(()),
+    }
+  }
+
+  fn allows_host(&self, host: &str) -> bool {
+    self.policy.allowed_hosts.iter().any(|pattern| {
+      if pattern == "*" {
+        return true;
+      }
+      match pattern.strip_prefix("*.") {
+        Some(domain) => host
+          .strip_suffix(domain.to_lowercase().as_str())
+          .map(|sub| sub.len() > 1 && sub.ends_with('.'))
+          .unwrap_or(false),
+        None => pattern.eq_ignore_ascii_case(host),
+      }
+    })
+  }
+
+  fn check_ip(&self, target: &str, ip: IpAddr) -> Result<(), AnyError> {
+    // an IPv4-mapped address reaches the IPv4 one.
+    let ip = match ip {
+      IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+      ip => ip,
+    };
+    let blocked = BLOCKED_NETWORKS.iter().any(|network| network.contains(&ip));
+    if blocked && !self.networks.iter().any(|network| network.contains(&ip)) {
+      return Err(permission_denied(format!(
+        "Requires net access to \"{}\", {} is a private address",
+        target, ip
+      )));
+    }
+    Ok(())
+  }
+}
+
+fn permission_denied(msg: String) -> AnyError {
+  custom_error("PermissionDenied", msg)
+}
+
+/// [`EgressResolver`] checks the addresses a domain resolves to,
+/// the client connects to the checked addresses only, so a domain can't be
+/// rebound to a private address after its check.
+struct EgressResolver(Arc<Egress>);
+
+impl Resolve for EgressResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let egress = self.0.clone();
+    Box::pin(async move {
+      let host = name.as_str();
+      let addrs = tokio::net::lookup_host((host, 0))
+        .await?
+        .collect::<Vec<_>>();
+      for addr in addrs.iter() {
+        egress.check_ip(host, addr.ip())?;
+      }
+      let addrs: Addrs = Box::new(addrs.into_iter());
+      Ok(addrs)
+    })
+  }
+}
+
+/// [`create_http_client`] is the client of deno_fetch with the resolver
+/// of the egress policy, and without proxies which bypass the resolver.
+pub(crate) fn create_http_client(
+  egress: Arc<Egress>,
+  user_agent: &str,
+  timeout: Duration,
+) -> Result<reqwest::Client, AnyError> {
+  let mut tls_config = deno_tls::create_client_config(
+    Some(deno_tls::create_default_root_cert_store()),
+    vec![],
+    None,
+    None,
+  )?;
+  tls_config.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
+
+  let mut headers = HeaderMap::new();
+  headers.insert(USER_AGENT, user_agent.parse()?);
+  let client = reqwest::Client::builder()
+    .redirect(Policy::none())
+    .default_headers(headers)
+    .use_preconfigured_tls(tls_config)
+    .no_proxy()
+    .timeout(timeout)
+    .dns_resolver(Arc::new(EgressResolver(egress)))
+    .build()?;
+  Ok(client)
+}
+
+/// [`EgressClient`] fetches by an egress policy outside of isolates,
+/// e.g. the modules vendored at deploy time. The client doesn't follow
+/// redirects, each location should be checked by [`EgressClient::check_url`].
+pub struct EgressClient {
+  egress: Arc<Egress>,
+  client: reqwest::Client,
+}
+
+impl EgressClient {
+  pub fn new(
+    policy: &EgressPolicy,
+    user_agent: &str,
+    timeout: Duration,
+  ) -> anyhow::Result<Self> {
+    policy.validate()?;
+    let egress = Arc::new(Egress::new(policy));
+    let client = create_http_client(egress.clone(), user_agent, timeout)?;
+    Ok(Self { egress, client })
+  }
+
+  /// [`EgressClient::check_url`] checks the host and port of the url,
+  /// the addresses of a domain are checked once resolved by the client.
+  pub fn check_url(&self, url: &Url) -> anyhow::Result<()> {
+    self.egress.check_url(url)
+  }
+
+  pub fn client(&self) -> &reqwest::Client {
+    &self.client
+  }
+}
+
+/// [`set_egress`] applies the policy to the fetches of the isolate,
+/// the client of the fetches is rebuilt with it by the next fetch.
+pub fn set_egress(state: &mut OpState, egress: &EgressPolicy) {
+  state.borrow_mut::<Permissions>().egress = Arc::new(Egress::new(egress));
+}
 
 pub struct Permissions {
-  _deploy_dir: PathBuf,
+  deploy_dir: PathBuf,
+  egress: Arc<Egress>,
 }
 
 impl Permissions {
   pub fn new(options: Options) -> Self {
     Self {
-      _deploy_dir: options.deploy_dir,
+      deploy_dir: options.deploy_dir,
+      egress: Arc::new(Egress::new(&EgressPolicy::default())),
     }
   }
+
+  pub(crate) fn egress(&self) -> Arc<Egress> {
+    self.egress.clone()
+  }
 }
 
 pub struct Options {
@@ -27,17 +315,26 @@ deno_core::extension!(
         state.put::<Permissions>(Permissions::new(options.options));
     }
 );
-
 impl deno_fetch::FetchPermissions for Permissions {
   fn check_net_url(
     &mut self,
-    _url: &Url,
+    url: &Url,
     _api_name: &str,
   ) -> Result<(), AnyError> {
-    Ok(())
+    self.egress.check_url(url)
   }
 
-  fn check_read(&mut self, _p: &Path, _api_name: &str) -> Result<(), AnyError> {
+  fn check_read(&mut self, p: &Path, _api_name: &str) -> Result<(), AnyError> {
+    let deploy_dir = std::env::current_dir()
+      .map(|dir| dir.join(&self.deploy_dir))
+      .unwrap_or_else(|_| self.deploy_dir.clone());
+    let escaped = p.components().any(|c| c == Component::ParentDir);
+    if escaped || !p.starts_with(deploy_dir) {
+      return Err(permission_denied(format!(
+        "Requires read access to \"{}\"",
+        p.display()
+      )));
+    }
     Ok(())
   }
 }
@@ -54,3 +351,84 @@ impl deno_web::TimersPermission for Permissions {
   ) {
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check(egress: &Egress, url: &str) -> bool {
+    egress.check_url(&Url::parse(url).unwrap()).is_ok()
+  }
+
+  #[test]
+  fn test_default_egress() {
+    let egress = Egress::new(&EgressPolicy::default());
+    assert!(check(&egress, "https://example.com/a"));
+    assert!(check(&egress, "http://1.1.1.1"));
+    assert!(!check(&egress, "https://example.com:8443"));
+    assert!(!check(&egress, "http://127.0.0.1"));
+    assert!(!check(&egress, "http://169.254.169.254/latest/meta-data"));
+    assert!(!check(&egress, "http://[::1]"));
+    assert!(!check(&egress, "http://[::ffff:10.0.0.1]"));
+    assert!(egress
+      .check_ip("example.com", "10.1.2.3".parse().unwrap())
+      .is_err());
+    assert!(egress
+      .check_ip("example.com", "8.8.8.8".parse().unwrap())
+      .is_ok());
+  }
+
+  #[test]
+  fn test_egress_policy() {
+    let policy = EgressPolicy {
+      allowed_hosts: vec![
+        "api.example.com".to_string(),
+        "*.test.io".to_string(),
+      ],
+      allowed_ports: vec![],
+      allowed_networks: vec!["10.1.0.0/16".to_string()],
+    };
+    assert!(policy.validate().is_ok());
+    let egress = Egress::new(&policy);
+    assert!(check(&egress, "https://API.example.com:8443"));
+    assert!(check(&egress, "https://a.b.test.io"));
+    assert!(!check(&egress, "https://test.io"));
+    assert!(!check(&egress, "https://atest.io"));
+    assert!(!check(&egress, "https://example.com"));
+    assert!(egress
+      .check_ip("a.test.io", "10.1.2.3".parse().unwrap())
+      .is_ok());
+    assert!(egress
+      .check_ip("a.test.io", "10.2.2.3".parse().unwrap())
+      .is_err());
+
+    let invalid = EgressPolicy {
+      allowed_networks: vec!["10.1.0.0/33".to_string()],
+      ..Default::default()
+    };
+    assert!(invalid.validate().is_err());
+    let invalid = EgressPolicy {
+      allowed_hosts: vec!["*.*.com".to_string()],
+      ..Default::default()
+    };
+    assert!(invalid.validate().is_err());
+  }
+
+  #[test]
+  fn test_check_read() {
+    use deno_fetch::FetchPermissions;
+
+    let mut permissions = Permissions::new(Options {
+      deploy_dir: PathBuf::from("/deploys/1"),
+    });
+    assert!(permissions
+      .check_read(Path::new("/deploys/1/data.json"), "fetch")
+      .is_ok());
+    assert!(permissions
+      .check_read(Path::new("/deploys/1/../2/data.json"), "fetch")
+      .is_err());
+    assert!(permissions
+      .check_read(Path::new("/etc/passwd"), "fetch")
+      .is_err());
+  }
+}
@@ -0,0 +1,48 @@
+use deno_core::url::Url;
+use deno_core::{op, OpState};
+use std::path::{Path, PathBuf};
+
+/// [`SourceRoot`] is the url of the deploy dir, the stacks of errors refer
+/// to the files relative to it.
+struct SourceRoot(String);
+
+deno_core::extension!(
+  darx_source_root,
+  ops = [op_source_root],
+  options = {
+    deploy_dir: PathBuf,
+  },
+  state = |state, options| {
+    state.put::<SourceRoot>(SourceRoot(source_root(&options.deploy_dir)));
+  }
+);
+
+fn absolute(deploy_dir: &Path) -> PathBuf {
+  std::env::current_dir()
+    .map(|dir| dir.join(deploy_dir))
+    .unwrap_or_else(|_| deploy_dir.to_path_buf())
+}
+
+fn source_root(deploy_dir: &Path) -> String {
+  Url::from_directory_path(absolute(deploy_dir))
+    .map(String::from)
+    .unwrap_or_default()
+}
+
+#[op]
+fn op_source_root(state: &mut OpState) -> String {
+  state.borrow::<SourceRoot>().0.clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_source_root() {
+    assert_eq!(
+      "file:///deploys/1/",
+      source_root(Path::new("/deploys/1")).as_str()
+    );
+  }
+}
@@ -6,4 +6,11 @@ export function Hi(msg) {
 
 export function ThrowExp() {
   throw Error('Something went wrong');
-}
\ No newline at end of file
+}
+
+export function ThrowStatus() {
+  const error = new Error('name is required');
+  error.status = 422;
+  error.code = 'INVALID_NAME';
+  throw error;
+}
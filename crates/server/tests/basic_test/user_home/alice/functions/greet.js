@@ -0,0 +1,5 @@
+import { greet } from "@lib/greet.js";
+
+export function Hello(name) {
+    return greet(name);
+}
@@ -1,12 +1,20 @@
 use ::time::{Duration, OffsetDateTime};
 use anyhow::{anyhow, Result};
 use darx_core::api::{
-  ApiError, DeployVarReq, ErrorResponse, NewPluginProjectReq, NewProjectRsp,
-  NewTenantProjectReq,
+  AddMemberReq, ApiError, ChangesReq, ChangesRsp, CorsConfig, DeployVarReq,
+  DomainInfo, EgressPolicy, EnvLimits, ErrorResponse, EventKind,
+  JsErrorDetails, ListDomainRsp, NewApiKeyReq, NewApiKeyRsp, NewOrgReq,
+  NewPluginProjectReq, NewProjectRsp, NewTenantProjectReq, RateLimit, Role,
+  SetMemberRoleReq,
 };
 use darx_utils::new_nano_id;
 use dotenv::dotenv;
-use reqwest::Client;
+use reqwest::header::{
+  HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+  ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS,
+  ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION, ORIGIN, RETRY_AFTER,
+};
+use reqwest::{Client, StatusCode};
 use serde_json::json;
 use sqlx::MySqlPool;
 use std::collections::HashMap;
@@ -23,6 +31,7 @@ use tracing_subscriber::Layer;
 
 const DATA: &str = "127.0.0.1:3456";
 const CONTROL: &str = "127.0.0.1:3457";
+const INTERNAL_SECRET: &str = "test_internal_secret";
 
 #[actix_web::test]
 async fn test_main_process() {
@@ -52,7 +61,11 @@ async fn test_main_process() {
   // deploy_var
   let mut vars = HashMap::new();
   vars.insert("key1".to_string(), "value1".to_string());
-  let req = DeployVarReq { desc: None, vars };
+  let req = DeployVarReq {
+    desc: None,
+    vars,
+    secrets: Default::default(),
+  };
   client
     .post(format!("http://{}/deploy_var/{}", CONTROL, env_id.as_str()))
     .json(&req)
@@ -96,6 +109,220 @@ async fn test_main_process() {
     .unwrap();
   assert_eq!(resp, "\"Hi 123 from foo, env key1 = value1\"");
 
+  // custom domains map a hostname and path prefix to the env.
+  let hostname = format!("{}.example.com", new_nano_id());
+  client
+    .post(format!("http://{}/add_domain/{}", CONTROL, env_id.as_str()))
+    .json(&DomainInfo {
+      hostname: hostname.clone(),
+      path_prefix: "/api/".to_string(),
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let resp = client
+    .post(format!("http://{}/invoke/api/foo.Hi", DATA))
+    .header("Darx-Dev-Host", hostname.as_str())
+    .json(&req)
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
+  assert_eq!(resp, "\"Hi 123 from foo, env key1 = value1\"");
+  let rsp = client
+    .get(format!(
+      "http://{}/list_domains/{}",
+      CONTROL,
+      env_id.as_str()
+    ))
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json::<ListDomainRsp>()
+    .await
+    .unwrap();
+  assert_eq!(
+    rsp.domains,
+    vec![DomainInfo {
+      hostname: hostname.clone(),
+      path_prefix: "api".to_string(),
+    }]
+  );
+  let status = client
+    .post(format!("http://{}/add_domain/{}", CONTROL, env_id.as_str()))
+    .json(&rsp.domains[0])
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::CONFLICT, status);
+
+  // the deploys are in the changes feed for pull nodes.
+  let changes = client
+    .get(format!("http://{}/changes", CONTROL))
+    .query(&ChangesReq {
+      since: 0,
+      node_id: None,
+      wait_secs: None,
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json::<ChangesRsp>()
+    .await
+    .unwrap();
+  let kinds = changes
+    .events
+    .iter()
+    .filter(|e| e.env_id == env_id)
+    .map(|e| e.kind.as_str())
+    .collect::<Vec<_>>();
+  assert!(kinds.contains(&EventKind::AddVarDeploy.as_str()));
+  assert!(kinds.contains(&EventKind::AddCodeDeploy.as_str()));
+
+  // members access an org with their roles.
+  let org_id = format!("org_{}", new_nano_id());
+  client
+    .post(format!("http://{}/new_org", CONTROL))
+    .json(&NewOrgReq {
+      org_id: org_id.clone(),
+      owner_id: "alice".to_string(),
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let alice_key = new_api_key(&client, org_id.as_str(), None).await;
+  let alice = new_auth_client(alice_key.as_str());
+  alice
+    .post(format!("http://{}/add_member/{}", CONTROL, org_id))
+    .json(&AddMemberReq {
+      user_id: "bob".to_string(),
+      role: Role::Viewer,
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let bob_key = new_api_key(&alice, org_id.as_str(), Some("bob")).await;
+  let bob = new_auth_client(bob_key.as_str());
+
+  let rsp = alice
+    .post(format!("http://{}/new_tenant_project", CONTROL))
+    .json(&NewTenantProjectReq {
+      org_id: org_id.clone(),
+      project_name: "test_proj".to_string(),
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json::<NewProjectRsp>()
+    .await
+    .unwrap();
+  let org_env_id = rsp.env.id;
+  let list_var_url = format!("http://{}/list_var/{}", CONTROL, org_env_id);
+  let deploy_var_url = format!("http://{}/deploy_var/{}", CONTROL, org_env_id);
+  let status = Client::new()
+    .get(list_var_url.as_str())
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::UNAUTHORIZED, status);
+  let status = bob
+    .get(list_var_url.as_str())
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::OK, status);
+  let req = DeployVarReq {
+    desc: None,
+    vars: HashMap::new(),
+    secrets: Default::default(),
+  };
+  let status = bob
+    .post(deploy_var_url.as_str())
+    .json(&req)
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::FORBIDDEN, status);
+  // the env of another org.
+  let status = bob
+    .get(format!("http://{}/list_var/{}", CONTROL, env_id))
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::FORBIDDEN, status);
+  // only the control plane calls the data plane.
+  let status = bob
+    .post(format!("http://{}/remove_env", DATA))
+    .json(&json!({ "env_id": org_env_id }))
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+  alice
+    .post(format!("http://{}/set_member_role/{}/bob", CONTROL, org_id))
+    .json(&SetMemberRoleReq {
+      role: Role::Developer,
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let status = bob
+    .post(deploy_var_url.as_str())
+    .json(&req)
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::OK, status);
+  let status = bob
+    .post(format!("http://{}/remove_member/{}/alice", CONTROL, org_id))
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::FORBIDDEN, status);
+
+  // the keys of a removed member stop working.
+  alice
+    .post(format!("http://{}/remove_member/{}/bob", CONTROL, org_id))
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let status = bob
+    .get(list_var_url.as_str())
+    .send()
+    .await
+    .unwrap()
+    .status();
+  assert_eq!(StatusCode::UNAUTHORIZED, status);
+
   if env::var("DATABASE_URL").is_ok() {
     let db = MySqlPool::connect(
       env::var("DATABASE_URL")
@@ -116,10 +343,10 @@ async fn test_main_process() {
     info!("logs: {:?}", logs);
     assert_eq!(2, logs.len());
     assert_eq!(0, logs[0].level);
-    assert_eq!("foo.js:2:Hi", logs[0].func);
+    assert_eq!("functions/foo.js:2:Hi", logs[0].func);
     assert_eq!("this is a debug log", logs[0].message);
     assert_eq!(5, logs[1].level);
-    assert_eq!("foo.js:3:Hi", logs[1].func);
+    assert_eq!("functions/foo.js:3:Hi", logs[1].func);
     assert_eq!("this is another log", logs[1].message);
   }
 
@@ -139,6 +366,21 @@ async fn test_main_process() {
     .unwrap();
   assert_eq!(resp, "\"Hi 1 obj 1 null from bar\"");
 
+  // the modules of lib are imported by the import map.
+  let resp = client
+    .post(format!("http://{}/invoke/greet.Hello", DATA))
+    .header("Darx-Dev-Host", format!("{}.darx.sh", env_id.as_str()))
+    .json(&json!({"name": "alice"}))
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
+  assert_eq!(resp, "\"Hello alice from lib\"");
+
   // deploy plugin
   let code_path =
     project_dir.join("tests/basic_test/user_home/plugins/test_plugin");
@@ -183,9 +425,130 @@ async fn test_main_process() {
 
   assert_eq!(resp.status(), expected.0);
 
-  let resp = resp.json::<ErrorResponse<()>>().await.unwrap();
+  let resp = resp.json::<ErrorResponse<JsErrorDetails>>().await.unwrap();
   assert_eq!(resp.error.code, expected.1);
   info!("runtime exception response: {:?}", &resp);
+  let details = resp.error.details.unwrap();
+  assert_eq!("Error", details.name);
+  assert_eq!("Something went wrong", details.message);
+  assert_eq!("functions/foo.js", details.stack[0].file);
+  assert_eq!(Some(8), details.stack[0].line);
+
+  // the status and code of the error thrown are returned.
+  let resp = client
+    .post(format!("http://{}/invoke/foo.ThrowStatus", DATA))
+    .header("Darx-Dev-Host", format!("{}.darx.sh", env_id))
+    .json(&json!({}))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+  let resp = resp.json::<ErrorResponse<JsErrorDetails>>().await.unwrap();
+  assert_eq!(42290, resp.error.code);
+  let details = resp.error.details.unwrap();
+  assert_eq!(Some("INVALID_NAME".to_string()), details.code);
+  assert_eq!("name is required", details.message);
+
+  // the CORS policy of the env answers preflight requests.
+  let cors = CorsConfig {
+    allowed_origins: vec!["https://app.example.com".to_string()],
+    allowed_methods: vec!["POST".to_string()],
+    allowed_headers: vec!["Content-Type".to_string()],
+    allow_credentials: true,
+    max_age: Some(600),
+  };
+  client
+    .post(format!("http://{}/set_cors/{}", CONTROL, env_id))
+    .json(&cors)
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let preflight = |origin: &'static str| {
+    client
+      .request(
+        reqwest::Method::OPTIONS,
+        format!("http://{}/invoke/foo.Hi", DATA),
+      )
+      .header("Darx-Dev-Host", format!("{}.darx.sh", env_id))
+      .header(ORIGIN, origin)
+      .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+      .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+      .send()
+  };
+  let resp = preflight("https://app.example.com").await.unwrap();
+  assert_eq!(StatusCode::OK, resp.status());
+  assert_eq!(
+    "https://app.example.com",
+    resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN]
+  );
+  assert_eq!("true", resp.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS]);
+  let resp = preflight("https://other.com").await.unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, resp.status());
+
+  // developers set the egress hosts, private networks are for operators.
+  let egress = EgressPolicy {
+    allowed_hosts: vec!["api.example.com".to_string()],
+    ..Default::default()
+  };
+  client
+    .post(format!("http://{}/set_egress/{}", CONTROL, env_id))
+    .json(&egress)
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let resp = client
+    .post(format!("http://{}/set_egress/{}", CONTROL, env_id))
+    .json(&EgressPolicy {
+      allowed_networks: vec!["10.0.0.0/8".to_string()],
+      ..Default::default()
+    })
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+
+  let mut limits = EnvLimits::default();
+  limits.route_rate_limits.insert(
+    "foo.Hi".to_string(),
+    RateLimit {
+      per_sec: 0.01,
+      burst: Some(1),
+    },
+  );
+  client
+    .post(format!("http://{}/set_limits/{}", CONTROL, env_id))
+    .json(&limits)
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+  let invoke = || {
+    client
+      .post(format!("http://{}/invoke/foo.Hi", DATA))
+      .header("Darx-Dev-Host", format!("{}.darx.sh", env_id))
+      .json(&json!({"msg": "123"}))
+      .send()
+  };
+  assert_eq!(StatusCode::OK, invoke().await.unwrap().status());
+  let resp = invoke().await.unwrap();
+  assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status());
+  assert_eq!("100", resp.headers()[RETRY_AFTER]);
+
+  // the limits set by operators keep the egress hosts.
+  let limits = client
+    .get(format!("http://{}/get_limits/{}", CONTROL, env_id))
+    .send()
+    .await
+    .unwrap()
+    .json::<EnvLimits>()
+    .await
+    .unwrap();
+  assert_eq!(Some(egress), limits.egress);
 
   handle.abort();
   let _ = handle.await;
@@ -223,12 +586,45 @@ async fn test_deploy_bad_code() {
   let _ = handle.await;
 }
 
+/// [`new_api_key`] creates a key of `user_id`, the org owner by default.
+async fn new_api_key(
+  client: &Client,
+  org_id: &str,
+  user_id: Option<&str>,
+) -> String {
+  client
+    .post(format!("http://{}/new_api_key/{}", CONTROL, org_id))
+    .json(&NewApiKeyReq {
+      name: "test".to_string(),
+      user_id: user_id.map(|s| s.to_string()),
+    })
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json::<NewApiKeyRsp>()
+    .await
+    .unwrap()
+    .secret
+}
+
+fn new_auth_client(token: &str) -> Client {
+  let mut headers = HeaderMap::new();
+  headers.insert(
+    AUTHORIZATION,
+    HeaderValue::from_str(format!("Bearer {}", token).as_str()).unwrap(),
+  );
+  Client::builder().default_headers(headers).build().unwrap()
+}
+
 async fn prepare_server(
   server_path: PathBuf,
 ) -> (JoinHandle<Result<()>>, String, Client) {
   env::set_var("DATA_PLANE_URL", format!("http://{}", DATA));
   env::set_var("DATA_PLANE_DB_HOST", "127.0.0.1");
   env::set_var("DATA_PLANE_DB_PORT", "3306");
+  env::set_var("DARX_INTERNAL_SECRET", INTERNAL_SECRET);
   dotenv().ok();
 
   let registry = tracing_subscriber::registry();
@@ -250,12 +646,14 @@ async fn prepare_server(
 
   let handle = run_server(server_path).await;
 
+  // the internal secret is allowed to access all orgs.
+  let client = new_auth_client(INTERNAL_SECRET);
+
   // create tenant project
   let req = NewTenantProjectReq {
     org_id: "test_org".to_string(),
     project_name: "test_proj".to_string(),
   };
-  let client = Client::new();
   let rsp = client
     .post(format!("http://{}/new_tenant_project", CONTROL))
     .json(&req)